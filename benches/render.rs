@@ -0,0 +1,42 @@
+use std::path::{Path, PathBuf};
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use flower_service::{RenderConfig, Renderer, modify_image};
+use image::{Rgb, RgbImage};
+
+// A gradient photo of a typical Commons download, written once next to the outputs
+fn sample_photo(dir: &Path) -> PathBuf {
+    let path = dir.join("Bench_flower.png");
+    RgbImage::from_fn(2560, 1707, |x, y| {
+        Rgb([(x / 10) as u8, (y / 7) as u8, ((x + y) / 17) as u8])
+    })
+    .save(&path)
+    .expect("cannot write the sample photo");
+    path
+}
+
+fn render(c: &mut Criterion) {
+    let dir = std::env::temp_dir().join("flower-service-bench");
+    std::fs::create_dir_all(&dir).expect("cannot create the bench directory");
+    let input = sample_photo(&dir);
+    let output = dir.join("wallpaper.png");
+    let config = RenderConfig::default();
+
+    let mut group = c.benchmark_group("render");
+    group.sample_size(10);
+    group.bench_function("modify_image", |b| {
+        b.iter(|| modify_image("Bench_flower.png", &input, &output, &config).unwrap())
+    });
+    let mut renderer = Renderer::default();
+    group.bench_function("renderer", |b| {
+        b.iter(|| {
+            renderer
+                .render("Bench_flower.png", &input, &output, &config)
+                .unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, render);
+criterion_main!(benches);
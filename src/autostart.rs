@@ -0,0 +1,49 @@
+use std::ffi::OsStr;
+use std::io;
+use std::os::windows::ffi::OsStrExt as _;
+
+use winapi::shared::winerror::{ERROR_FILE_NOT_FOUND, ERROR_SUCCESS};
+use winapi::um::winnt::REG_SZ;
+use winapi::um::winreg::{HKEY_CURRENT_USER, RegDeleteKeyValueW, RegSetKeyValueW};
+
+pub const AUTOSTART_KEY: &str = r"HKEY_CURRENT_USER\Software\Microsoft\Windows\CurrentVersion\Run";
+const RUN_SUB_KEY: &str = r"Software\Microsoft\Windows\CurrentVersion\Run";
+const VALUE_NAME: &str = "flower-service";
+
+fn to_wide(value: impl AsRef<OsStr>) -> Vec<u16> {
+    value.as_ref().encode_wide().chain(Some(0)).collect()
+}
+
+// Starts the running executable at every login of the current user. Returns the command that
+// was written to the Run key.
+pub fn install_autostart() -> io::Result<String> {
+    let exe_path = std::env::current_exe()?;
+    // Quoted, the path may contain spaces
+    let command = format!("\"{}\"", exe_path.display());
+    let (sub_key, name, value) = (to_wide(RUN_SUB_KEY), to_wide(VALUE_NAME), to_wide(&command));
+    let result = unsafe {
+        RegSetKeyValueW(
+            HKEY_CURRENT_USER,
+            sub_key.as_ptr(),
+            name.as_ptr(),
+            REG_SZ,
+            value.as_ptr() as *const _,
+            (value.len() * 2) as u32,
+        )
+    };
+    if result != ERROR_SUCCESS as i32 {
+        return Err(io::Error::from_raw_os_error(result));
+    }
+    Ok(command)
+}
+
+// Returns false if there was no registration to remove
+pub fn uninstall_autostart() -> io::Result<bool> {
+    let (sub_key, name) = (to_wide(RUN_SUB_KEY), to_wide(VALUE_NAME));
+    let result = unsafe { RegDeleteKeyValueW(HKEY_CURRENT_USER, sub_key.as_ptr(), name.as_ptr()) };
+    match result as u32 {
+        ERROR_SUCCESS => Ok(true),
+        ERROR_FILE_NOT_FOUND => Ok(false),
+        _ => Err(io::Error::from_raw_os_error(result)),
+    }
+}
@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::str::FromStr;
+
+use image::imageops::{self, FilterType};
+use image::{Rgba, RgbaImage};
+use rayon::prelude::*;
+use serde::Deserialize;
+
+use crate::{apply_mask, parse_hex_color, system_uses_dark_theme};
+
+pub const DEFAULT_BACKGROUND: Rgba<u8> = Rgba([233, 223, 199, 255]);
+
+// Top and bottom color of a vertical gradient
+pub type Gradient = (Rgba<u8>, Rgba<u8>);
+
+// Side length the photo is shrunk to before counting colors
+const SAMPLE_SIZE: u32 = 64;
+// Low bits dropped per channel, so similar shades fall into the same bucket
+const QUANTIZE_SHIFT: u8 = 4;
+// Longer side of the copy that gets blurred, so the cost does not grow with the canvas
+const BLUR_WORK_SIZE: u32 = 320;
+const DEFAULT_BLUR_SIGMA: f32 = 40.;
+// Brightness kept in the strip behind the caption of a blurred background
+const CAPTION_STRIP_BRIGHTNESS: f32 = 0.55;
+const DEFAULT_DARK_BACKGROUND: Rgba<u8> = Rgba([32, 32, 32, 255]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Background {
+    Solid(Rgba<u8>),
+    // The most frequent color of the photo
+    DominantColor,
+    // The photo zoomed to cover the canvas and blurred, `sigma` in canvas pixels
+    BlurredFill { sigma: f32 },
+    // One of two colors depending on whether the system uses a light or a dark theme
+    FollowSystemTheme { light: Rgba<u8>, dark: Rgba<u8> },
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Background::Solid(DEFAULT_BACKGROUND)
+    }
+}
+
+impl Background {
+    // The canvas the photo is placed on; corners and caption blend into it
+    pub fn backdrop(&self, image: &RgbaImage, width: u32, height: u32) -> RgbaImage {
+        let mut canvas = RgbaImage::new(width, height);
+        self.draw_backdrop(image, &mut canvas);
+        canvas
+    }
+
+    // Like `backdrop`, but plain colors are filled into the existing canvas
+    pub(crate) fn draw_backdrop(&self, image: &RgbaImage, canvas: &mut RgbaImage) {
+        match self {
+            Background::Solid(color) => fill(canvas, *color),
+            Background::DominantColor => {
+                fill(canvas, dominant_color(image).unwrap_or(DEFAULT_BACKGROUND))
+            }
+            Background::BlurredFill { sigma } => {
+                *canvas = blurred_fill(image, canvas.width(), canvas.height(), *sigma)
+            }
+            Background::FollowSystemTheme { .. } => {
+                self.resolve_system_theme().0.draw_backdrop(image, canvas)
+            }
+        }
+    }
+
+    // The background to render with and the caption color that suits it. Called for every
+    // render, so switching the theme of the system shows from the next wallpaper on.
+    pub fn resolve_system_theme(&self) -> (Self, Rgba<u8>) {
+        match *self {
+            Background::FollowSystemTheme { dark, .. } if system_uses_dark_theme() => {
+                (Background::Solid(dark), Rgba([255, 255, 255, 255]))
+            }
+            Background::FollowSystemTheme { light, .. } => {
+                (Background::Solid(light), Rgba([0, 0, 0, 255]))
+            }
+            background => (background, background.caption_color()),
+        }
+    }
+
+    pub fn caption_color(&self) -> Rgba<u8> {
+        match self {
+            Background::BlurredFill { .. } => Rgba([255, 255, 255, 255]),
+            _ => Rgba([0, 0, 0, 255]),
+        }
+    }
+
+    // A busy backdrop gets a darkened strip behind the caption, plain colors are left alone
+    pub(crate) fn prepare_caption_strip(
+        &self,
+        canvas: &mut RgbaImage,
+        columns: Range<u32>,
+        rows: Range<u32>,
+    ) {
+        if let Background::BlurredFill { .. } = self {
+            for y in rows.start..rows.end.min(canvas.height()) {
+                for x in columns.start..columns.end.min(canvas.width()) {
+                    let pixel = canvas.get_pixel_mut(x, y);
+                    for channel in &mut pixel.0[..3] {
+                        *channel = (*channel as f32 * CAPTION_STRIP_BRIGHTNESS) as u8;
+                    }
+                }
+            }
+        }
+    }
+
+    // Composites a vertical gradient from `top` to `bottom` over the given area
+    pub(crate) fn draw_gradient(
+        canvas: &mut RgbaImage,
+        columns: Range<u32>,
+        rows: Range<u32>,
+        (top, bottom): Gradient,
+    ) {
+        let rows = rows.start..rows.end.min(canvas.height());
+        let height = rows.len().max(2) as f32 - 1.;
+        for y in rows.clone() {
+            let t = (y - rows.start) as f32 / height;
+            let color = Rgba(std::array::from_fn(|channel| {
+                (top[channel] as f32 * (1. - t) + bottom[channel] as f32 * t).round() as u8
+            }));
+            for x in columns.start..columns.end.min(canvas.width()) {
+                let pixel = canvas.get_pixel_mut(x, y);
+                *pixel = apply_mask(*pixel, color);
+            }
+        }
+    }
+
+    pub(crate) fn scaled(&self, factor: f32) -> Self {
+        match self {
+            Background::BlurredFill { sigma } => Background::BlurredFill {
+                sigma: sigma * factor,
+            },
+            _ => *self,
+        }
+    }
+}
+
+// Same as `RgbaImage::from_pixel`, filling the rows in parallel
+fn fill(canvas: &mut RgbaImage, color: Rgba<u8>) {
+    let width = canvas.width();
+    if width > 0 {
+        canvas.par_chunks_mut(width as usize * 4).for_each(|row| {
+            for pixel in row.chunks_exact_mut(4) {
+                pixel.copy_from_slice(&color.0);
+            }
+        });
+    }
+}
+
+// Crops the photo to the canvas aspect ratio, then blurs a small copy and scales it back up
+fn blurred_fill(image: &RgbaImage, width: u32, height: u32, sigma: f32) -> RgbaImage {
+    let (image_width, image_height) = image.dimensions();
+    if image_width == 0 || image_height == 0 {
+        return RgbaImage::from_pixel(width, height, DEFAULT_BACKGROUND);
+    }
+
+    let aspect = width as f32 / height as f32;
+    let (crop_width, crop_height) = if image_width as f32 / image_height as f32 > aspect {
+        ((image_height as f32 * aspect) as u32, image_height)
+    } else {
+        (image_width, (image_width as f32 / aspect) as u32)
+    };
+    let (crop_width, crop_height) = (
+        crop_width.clamp(1, image_width),
+        crop_height.clamp(1, image_height),
+    );
+    let cropped = imageops::crop_imm(
+        image,
+        (image_width - crop_width) / 2,
+        (image_height - crop_height) / 2,
+        crop_width,
+        crop_height,
+    )
+    .to_image();
+
+    let work_scale = (BLUR_WORK_SIZE as f32 / width.max(height) as f32).min(1.);
+    let work_width = ((width as f32 * work_scale) as u32).max(1);
+    let work_height = ((height as f32 * work_scale) as u32).max(1);
+    let small = imageops::thumbnail(&cropped, work_width, work_height);
+    let blurred = imageops::blur(&small, sigma * work_scale);
+    imageops::resize(&blurred, width, height, FilterType::Triangle)
+}
+
+// Averages the pixels of the most populated quantized bucket, which keeps the exact shade
+// instead of snapping to the bucket corner
+fn dominant_color(image: &RgbaImage) -> Option<Rgba<u8>> {
+    if image.width() == 0 || image.height() == 0 {
+        return None;
+    }
+    let sample = image::imageops::thumbnail(
+        image,
+        image.width().min(SAMPLE_SIZE),
+        image.height().min(SAMPLE_SIZE),
+    );
+
+    let mut buckets: HashMap<[u8; 3], (u32, [u32; 3])> = HashMap::new();
+    for pixel in sample.pixels() {
+        let [r, g, b, a] = pixel.0;
+        if a == 0 {
+            continue;
+        }
+        let key = [
+            r >> QUANTIZE_SHIFT,
+            g >> QUANTIZE_SHIFT,
+            b >> QUANTIZE_SHIFT,
+        ];
+        let (count, sums) = buckets.entry(key).or_default();
+        *count += 1;
+        sums[0] += r as u32;
+        sums[1] += g as u32;
+        sums[2] += b as u32;
+    }
+
+    let (count, sums) = buckets
+        .into_values()
+        .max_by_key(|&(count, sums)| (count, sums))?;
+    let [r, g, b] = sums.map(|sum| (sum / count) as u8);
+    Some(Rgba([r, g, b, 255]))
+}
+
+impl FromStr for Background {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        if value.eq_ignore_ascii_case("dominant") {
+            return Ok(Background::DominantColor);
+        }
+        if value.eq_ignore_ascii_case("blurred") {
+            return Ok(Background::BlurredFill {
+                sigma: DEFAULT_BLUR_SIGMA,
+            });
+        }
+        if let Some(sigma) = value.strip_prefix("blurred ") {
+            return match sigma.trim().parse::<f32>() {
+                Ok(sigma) if sigma.is_finite() && sigma > 0. => {
+                    Ok(Background::BlurredFill { sigma })
+                }
+                _ => Err(format!(
+                    "Invalid blur sigma {sigma:?}, expected a positive number"
+                )),
+            };
+        }
+        if value.eq_ignore_ascii_case("system") {
+            return Ok(Background::FollowSystemTheme {
+                light: DEFAULT_BACKGROUND,
+                dark: DEFAULT_DARK_BACKGROUND,
+            });
+        }
+        if let Some(colors) = value.strip_prefix("system ") {
+            let colors: Vec<&str> = colors.split_whitespace().collect();
+            let [light, dark] = colors.as_slice() else {
+                return Err(format!(
+                    "Invalid system background {value:?}, expected \"system #light #dark\""
+                ));
+            };
+            return Ok(Background::FollowSystemTheme {
+                light: opaque(parse_hex_color(light)?),
+                dark: opaque(parse_hex_color(dark)?),
+            });
+        }
+        if value.starts_with('#') {
+            return Ok(Background::Solid(opaque(parse_hex_color(value)?)));
+        }
+        Err(format!(
+            "Unknown background {value:?}, expected \"dominant\", \"blurred [sigma]\", \"system [#light #dark]\" or \"#rrggbb\""
+        ))
+    }
+}
+
+// The canvas has no transparency, the alpha of a background color is ignored
+fn opaque(Rgba([r, g, b, _]): Rgba<u8>) -> Rgba<u8> {
+    Rgba([r, g, b, 255])
+}
+
+impl TryFrom<String> for Background {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The canvas fill before it became a buffer fill
+    fn filled_per_pixel(width: u32, height: u32, color: Rgba<u8>) -> RgbaImage {
+        let mut canvas = RgbaImage::new(width, height);
+        for x in 0..width {
+            for y in 0..height {
+                canvas.put_pixel(x, y, color);
+            }
+        }
+        canvas
+    }
+
+    #[test]
+    fn fill_matches_the_per_pixel_loop() {
+        for (width, height) in [(1, 1), (7, 3), (64, 37)] {
+            let mut canvas = RgbaImage::new(width, height);
+            fill(&mut canvas, DEFAULT_BACKGROUND);
+            assert_eq!(
+                canvas.as_raw(),
+                filled_per_pixel(width, height, DEFAULT_BACKGROUND).as_raw()
+            );
+        }
+    }
+
+    #[test]
+    fn solid_backdrop_matches_the_per_pixel_loop() {
+        let photo = RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 255]));
+        let backdrop = Background::default().backdrop(&photo, 31, 17);
+        assert_eq!(
+            backdrop.as_raw(),
+            filled_per_pixel(31, 17, DEFAULT_BACKGROUND).as_raw()
+        );
+    }
+
+    #[test]
+    fn fill_overwrites_a_reused_canvas() {
+        let mut canvas = RgbaImage::from_pixel(9, 5, Rgba([1, 2, 3, 4]));
+        fill(&mut canvas, DEFAULT_BACKGROUND);
+        assert_eq!(
+            canvas.as_raw(),
+            filled_per_pixel(9, 5, DEFAULT_BACKGROUND).as_raw()
+        );
+    }
+}
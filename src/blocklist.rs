@@ -0,0 +1,68 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Images that are never shown, one file name per line; lines starting with '#' are comments.
+// A missing file blocks nothing.
+pub fn load_blocklist(path: &Path) -> HashSet<String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return HashSet::new(),
+        Err(err) => {
+            log::warn!("Cannot read {}, ignoring it: {err}", path.display());
+            return HashSet::new();
+        }
+    };
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect()
+}
+
+// Listed either with its path below wiki_flowers or with the bare file name
+pub fn is_blocked(file_name: &str, blocklist: &HashSet<String>) -> bool {
+    blocklist.contains(file_name)
+        || Path::new(file_name)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| blocklist.contains(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        let path = std::env::temp_dir().join(format!(
+            "flower-service-blocklist-{}.txt",
+            std::process::id()
+        ));
+        fs::write(
+            &path,
+            "# never again\nRosa_canina.jpg\n\n  spring/Tulip.png  \n#Lily.png\n",
+        )
+        .unwrap();
+        let blocklist = load_blocklist(&path);
+        let _ = fs::remove_file(&path);
+        assert_eq!(
+            blocklist,
+            HashSet::from(["Rosa_canina.jpg".to_owned(), "spring/Tulip.png".to_owned()])
+        );
+    }
+
+    #[test]
+    fn blocked_by_path_or_file_name() {
+        let blocklist =
+            HashSet::from(["Rosa_canina.jpg".to_owned(), "spring/Tulip.png".to_owned()]);
+        assert!(is_blocked("Rosa_canina.jpg", &blocklist));
+        assert!(is_blocked("summer/Rosa_canina.jpg", &blocklist));
+        assert!(is_blocked("spring/Tulip.png", &blocklist));
+        assert!(!is_blocked("Tulip.png", &blocklist));
+        assert!(!is_blocked("autumn/Tulip.png", &blocklist));
+        assert!(!is_blocked("Lily.png", &blocklist));
+    }
+}
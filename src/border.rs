@@ -0,0 +1,86 @@
+use image::{Rgba, RgbaImage};
+use serde::Deserialize;
+
+use crate::color::deserialize_color;
+use crate::corners::rounded_rect_coverage;
+use crate::{CornerRadii, apply_mask};
+
+// A frame along the inside of the photo edge, following its rounded corners
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct BorderConfig {
+    // In canvas pixels
+    pub width: u32,
+    #[serde(deserialize_with = "deserialize_color")]
+    pub color: Rgba<u8>,
+}
+
+impl Default for BorderConfig {
+    fn default() -> Self {
+        Self {
+            width: 2,
+            color: Rgba([255, 255, 255, 255]),
+        }
+    }
+}
+
+impl BorderConfig {
+    pub(crate) fn scaled(&self, factor: f32) -> Self {
+        Self {
+            width: ((self.width as f32 * factor).round() as u32).max(1),
+            color: self.color,
+        }
+    }
+}
+
+// Strokes the border over a photo of the given size at `offset`. The radii must already be
+// clamped to the photo size, like the ones the corners were rounded with.
+pub(crate) fn draw_border(
+    canvas: &mut RgbaImage,
+    border: &BorderConfig,
+    offset: (u32, u32),
+    (width, height): (u32, u32),
+    radii: CornerRadii,
+) {
+    let border_width = border.width;
+    if border_width == 0 {
+        return;
+    }
+    // The inner edge runs at the border width from the outer one, with correspondingly smaller corners
+    let inner_size = (
+        width.saturating_sub(2 * border_width),
+        height.saturating_sub(2 * border_width),
+    );
+    let inner_radii = radii.map(|radius| {
+        radius
+            .saturating_sub(border_width)
+            .min(inner_size.0 / 2)
+            .min(inner_size.1 / 2)
+    });
+    let [r, g, b, a] = border.color.0;
+    for y in 0..height {
+        for x in 0..width {
+            let inner = if (border_width..border_width + inner_size.0).contains(&x)
+                && (border_width..border_width + inner_size.1).contains(&y)
+            {
+                rounded_rect_coverage(
+                    (x - border_width, y - border_width),
+                    inner_size,
+                    inner_radii,
+                )
+            } else {
+                0.
+            };
+            if inner >= 1. {
+                continue;
+            }
+            let coverage = (rounded_rect_coverage((x, y), (width, height), radii) - inner).max(0.);
+            let (canvas_x, canvas_y) = (offset.0 + x, offset.1 + y);
+            if coverage <= 0. || canvas_x >= canvas.width() || canvas_y >= canvas.height() {
+                continue;
+            }
+            let pixel = canvas.get_pixel_mut(canvas_x, canvas_y);
+            *pixel = apply_mask(*pixel, Rgba([r, g, b, (coverage * a as f32) as u8]));
+        }
+    }
+}
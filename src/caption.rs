@@ -0,0 +1,474 @@
+use std::ops::Range;
+
+use image::{GenericImage, Rgba, RgbaImage};
+use rusttype::{Font, GlyphId, PositionedGlyph, Scale, point};
+use serde::Deserialize;
+
+use crate::apply_mask;
+use crate::color::{deserialize_color, parse_hex_color};
+use crate::render::SRGB_TO_LINEAR;
+
+pub(crate) const MAX_CAPTION_LINES: usize = 3;
+
+// Drawn around every glyph so the caption stays readable on busy backgrounds
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct CaptionOutline {
+    // In canvas pixels, 1 or 2 look best
+    pub width: u32,
+    #[serde(deserialize_with = "deserialize_color")]
+    pub color: Rgba<u8>,
+}
+
+impl Default for CaptionOutline {
+    fn default() -> Self {
+        Self {
+            width: 2,
+            color: Rgba([255, 255, 255, 255]),
+        }
+    }
+}
+
+impl CaptionOutline {
+    pub(crate) fn scaled(&self, factor: f32) -> Self {
+        Self {
+            width: ((self.width as f32 * factor).round() as u32).max(1),
+            color: self.color,
+        }
+    }
+}
+
+// "auto" picks black or white text, whichever stands out more from what is behind the caption
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum TextColor {
+    Fixed(Rgba<u8>),
+    Auto,
+}
+
+impl TryFrom<String> for TextColor {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if value.trim().eq_ignore_ascii_case("auto") {
+            return Ok(TextColor::Auto);
+        }
+        parse_hex_color(&value).map(TextColor::Fixed).map_err(|_| {
+            format!(
+                "Invalid text color {:?}, expected \"#rrggbb\", \"#rrggbbaa\" or \"auto\"",
+                value.trim()
+            )
+        })
+    }
+}
+
+// Black text has the better contrast above this relative luminance, white text below it
+const BLACK_TEXT_LUMINANCE: f32 = 0.179;
+
+// Compares the average luminance of the region in linear light, like the WCAG contrast ratio
+pub(crate) fn auto_text_color(
+    canvas: &RgbaImage,
+    columns: Range<u32>,
+    rows: Range<u32>,
+) -> Rgba<u8> {
+    let columns = columns.start..columns.end.min(canvas.width());
+    let rows = rows.start..rows.end.min(canvas.height());
+    let mut total = 0.;
+    let mut count = 0;
+    for y in rows {
+        for x in columns.clone() {
+            let [r, g, b, _] = canvas
+                .get_pixel(x, y)
+                .0
+                .map(|channel| SRGB_TO_LINEAR[channel as usize]);
+            total += 0.2126 * r + 0.7152 * g + 0.0722 * b;
+            count += 1;
+        }
+    }
+    if count > 0 && total / (count as f32) < BLACK_TEXT_LUMINANCE {
+        Rgba([255, 255, 255, 255])
+    } else {
+        Rgba([0, 0, 0, 255])
+    }
+}
+
+// The caption font and the one that fills in the characters it has no glyph for
+pub(crate) struct Fonts<'a> {
+    pub primary: Font<'a>,
+    pub fallback: Option<Font<'a>>,
+}
+
+impl<'a> Fonts<'a> {
+    // The primary font draws characters neither font has, as its notdef box
+    fn font_for(&self, c: char) -> (&Font<'a>, bool) {
+        match &self.fallback {
+            Some(fallback) if !has_glyph(&self.primary, c) && has_glyph(fallback, c) => {
+                (fallback, true)
+            }
+            _ => (&self.primary, false),
+        }
+    }
+
+    // Characters of `text` that neither font can draw
+    pub(crate) fn missing_chars(&self, text: &str) -> Vec<char> {
+        let mut missing: Vec<char> = text
+            .chars()
+            .filter(|&c| !c.is_whitespace() && !c.is_control())
+            .filter(|&c| {
+                !has_glyph(&self.primary, c)
+                    && self
+                        .fallback
+                        .as_ref()
+                        .is_none_or(|fallback| !has_glyph(fallback, c))
+            })
+            .collect();
+        missing.sort_unstable();
+        missing.dedup();
+        missing
+    }
+}
+
+fn has_glyph(font: &Font, c: char) -> bool {
+    font.glyph(c).id() != GlyphId(0)
+}
+
+// Lays out a line like `Font::layout`, with `spacing` times the font size added between the
+// letters and the fallback font for characters the primary one lacks. Glyphs without an advance,
+// like combining marks, stay on the letter before them. There is no shaping, right-to-left text
+// comes out in the order it is stored.
+pub(crate) fn layout<'a>(
+    fonts: &Fonts<'a>,
+    scale: Scale,
+    text: &str,
+    spacing: f32,
+    baseline: f32,
+) -> Vec<PositionedGlyph<'a>> {
+    let spacing = spacing * scale.x;
+    let mut caret = 0.;
+    // Kerning only applies between glyphs of the same font
+    let mut last: Option<(bool, GlyphId)> = None;
+    let mut glyphs = Vec::new();
+    for c in text.chars() {
+        let (font, is_fallback) = fonts.font_for(c);
+        let glyph = font.glyph(c).scaled(scale);
+        if let Some((last_is_fallback, last_id)) = last
+            && last_is_fallback == is_fallback
+        {
+            caret += font.pair_kerning(scale, last_id, glyph.id());
+        }
+        let advance = glyph.h_metrics().advance_width;
+        if !glyphs.is_empty() && advance > 0. {
+            caret += spacing;
+        }
+        last = Some((is_fallback, glyph.id()));
+        glyphs.push(glyph.positioned(point(caret, baseline)));
+        caret += advance;
+    }
+    glyphs
+}
+
+pub(crate) fn line_width(fonts: &Fonts, scale: Scale, text: &str, spacing: f32) -> f32 {
+    layout(fonts, scale, text, spacing, 0.)
+        .last()
+        .map(|glyph| glyph.position().x + glyph.unpositioned().h_metrics().advance_width)
+        .unwrap_or(0.)
+        .max(0.)
+}
+
+// Greedy word wrap; whatever does not fit into the last line stays there and gets scaled down
+pub(crate) fn wrap_lines(
+    fonts: &Fonts,
+    scale: Scale,
+    text: &str,
+    spacing: f32,
+    max_width: f32,
+    max_lines: usize,
+) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for word in text.split_whitespace() {
+        let is_last_line = lines.len() == max_lines;
+        match lines.last_mut() {
+            Some(line)
+                if is_last_line
+                    || line_width(fonts, scale, &format!("{line} {word}"), spacing)
+                        <= max_width =>
+            {
+                line.push(' ');
+                line.push_str(word);
+            }
+            _ => lines.push(word.to_owned()),
+        }
+    }
+    lines
+}
+
+// Shrinks the scale of a line that is wider than the available space
+pub(crate) fn fitting_scale(
+    fonts: &Fonts,
+    scale: Scale,
+    text: &str,
+    spacing: f32,
+    max_width: f32,
+) -> Scale {
+    let width = line_width(fonts, scale, text, spacing);
+    if width <= max_width {
+        return scale;
+    }
+    let factor = max_width / width;
+    Scale {
+        x: scale.x * factor,
+        y: scale.y * factor,
+    }
+}
+
+// Width the line takes on the canvas, measured from the glyph advances since light
+// anti-aliased edges would under-measure it
+pub(crate) fn line_extent(
+    fonts: &Fonts,
+    scale: Scale,
+    text: &str,
+    spacing: f32,
+    max_width: u32,
+) -> u32 {
+    (line_width(fonts, scale, text, spacing).ceil() as u32 + 2).min(max_width)
+}
+
+// Baseline that centers the capitals in a band of `band_height`, kept high enough for the
+// descenders to stay inside of it
+pub(crate) fn centered_baseline(font: &Font, scale: Scale, band_height: u32) -> f32 {
+    let v_metrics = font.v_metrics(scale);
+    let cap_height = font
+        .glyph('H')
+        .scaled(scale)
+        .exact_bounding_box()
+        .map(|bb| -bb.min.y)
+        .unwrap_or(v_metrics.ascent);
+    let band_height = band_height as f32;
+    ((band_height + cap_height) / 2.)
+        .min(band_height + v_metrics.descent)
+        .max(0.)
+}
+
+// Blends the glyphs of one line into `target` by coverage; glyphs reaching outside of it are
+// clipped. The outline is the coverage shifted in every direction, drawn below the fill.
+pub(crate) fn draw_line<I: GenericImage<Pixel = Rgba<u8>>>(
+    glyphs: &[PositionedGlyph],
+    target: &mut I,
+    color: Rgba<u8>,
+    outline: Option<&CaptionOutline>,
+) {
+    if let Some(outline) = outline {
+        let width = outline.width as i32;
+        for dy in -width..=width {
+            for dx in -width..=width {
+                if (dx, dy) != (0, 0) {
+                    draw_coverage(glyphs, target, outline.color, (dx, dy));
+                }
+            }
+        }
+    }
+    draw_coverage(glyphs, target, color, (0, 0));
+}
+
+fn draw_coverage<I: GenericImage<Pixel = Rgba<u8>>>(
+    glyphs: &[PositionedGlyph],
+    target: &mut I,
+    color: Rgba<u8>,
+    (shift_x, shift_y): (i32, i32),
+) {
+    let (width, height) = target.dimensions();
+    let [r, g, b, a] = color.0;
+    for glyph in glyphs {
+        if let Some(bb) = glyph.pixel_bounding_box() {
+            glyph.draw(|x, y, v| {
+                let x = x as i32 + bb.min.x + 2 + shift_x;
+                let y = y as i32 + bb.min.y + shift_y;
+                if (0..width as i32).contains(&x) && (0..height as i32).contains(&y) {
+                    let (x, y) = (x as u32, y as u32);
+                    let alpha = (v * a as f32) as u8;
+                    let pixel = apply_mask(target.get_pixel(x, y), Rgba([r, g, b, alpha]));
+                    target.put_pixel(x, y, pixel);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLACK: Rgba<u8> = Rgba([0, 0, 0, 255]);
+    const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
+    const BAND_HEIGHT: u32 = 50;
+
+    fn fonts() -> Fonts<'static> {
+        Fonts {
+            primary: Font::try_from_bytes(include_bytes!("../PlayfairDisplay-Regular.ttf"))
+                .unwrap(),
+            fallback: None,
+        }
+    }
+
+    // One line of text at 40 pixels, centered in a band like the caption below the photo and
+    // `extra_width` wider than the extent measured for it
+    fn draw_text(
+        text: &str,
+        extra_width: u32,
+        background: Rgba<u8>,
+        color: Rgba<u8>,
+        outline: Option<&CaptionOutline>,
+    ) -> RgbaImage {
+        let fonts = fonts();
+        let scale = Scale::uniform(40.);
+        let width = line_extent(&fonts, scale, text, 0., 1000) + extra_width;
+        let mut target = RgbaImage::from_pixel(width, BAND_HEIGHT, background);
+        let baseline = centered_baseline(&fonts.primary, scale, BAND_HEIGHT);
+        draw_line(
+            &layout(&fonts, scale, text, 0., baseline),
+            &mut target,
+            color,
+            outline,
+        );
+        target
+    }
+
+    #[test]
+    fn text_edges_are_anti_aliased() {
+        let text = draw_text("Rosa", 0, WHITE, BLACK, None);
+        let gray_levels: Vec<u8> = text
+            .pixels()
+            .map(|pixel| pixel[0])
+            .filter(|&level| level != 255)
+            .collect();
+        assert!(gray_levels.iter().any(|&level| level < 32));
+        let edge_pixels = gray_levels.iter().filter(|&&level| level >= 32).count();
+        assert!(
+            edge_pixels * 10 > gray_levels.len(),
+            "only {edge_pixels} of {} inked pixels are partly covered",
+            gray_levels.len()
+        );
+    }
+
+    #[test]
+    fn extent_holds_the_whole_word() {
+        let extra_width = 10;
+        let text = draw_text("Rosa canina", extra_width, WHITE, BLACK, None);
+        let inked_columns: Vec<u32> = (0..text.width())
+            .filter(|&x| (0..BAND_HEIGHT).any(|y| text.get_pixel(x, y)[0] < 255))
+            .collect();
+        let extent = text.width() - extra_width;
+        assert!(inked_columns.first().is_some_and(|&x| x > 0));
+        assert!(
+            inked_columns.last().is_some_and(|&x| x < extent),
+            "ink reaches column {:?} of {extent}",
+            inked_columns.last()
+        );
+    }
+
+    #[test]
+    fn capitals_are_centered_in_the_band() {
+        let text = draw_text("HIEH", 0, WHITE, BLACK, None);
+        let inked_rows: Vec<u32> = (0..BAND_HEIGHT)
+            .filter(|&y| (0..text.width()).any(|x| text.get_pixel(x, y)[0] < 128))
+            .collect();
+        let (Some(&top), Some(&bottom)) = (inked_rows.first(), inked_rows.last()) else {
+            panic!("nothing was drawn");
+        };
+        let space_above = top;
+        let space_below = BAND_HEIGHT - 1 - bottom;
+        assert!(
+            space_above.abs_diff(space_below) <= 1,
+            "{space_above} rows above the capitals, {space_below} below"
+        );
+    }
+
+    #[test]
+    fn outline_surrounds_the_fill() {
+        let gray = Rgba([128, 128, 128, 255]);
+        let outline = CaptionOutline {
+            width: 1,
+            color: Rgba([255, 0, 0, 255]),
+        };
+        let text = draw_text("Rosa", 4, gray, BLACK, Some(&outline));
+        let is_fill = |pixel: &Rgba<u8>| pixel.0[..3].iter().all(|&channel| channel < 64);
+        let mut fill_pixels = 0;
+        for (x, y, pixel) in text.enumerate_pixels() {
+            if !is_fill(pixel) {
+                continue;
+            }
+            fill_pixels += 1;
+            for (dx, dy) in [(-1, 0), (1, 0), (0, -1), (0, 1)] {
+                let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+                if let Some(neighbor) = text.get_pixel_checked(nx as u32, ny as u32) {
+                    assert_ne!(*neighbor, gray, "({x}, {y}) touches the background");
+                }
+            }
+        }
+        assert!(fill_pixels > 0);
+        assert!(text.pixels().any(|pixel| pixel[0] > 200 && pixel[1] < 60));
+
+        let plain = draw_text("Rosa", 4, gray, BLACK, None);
+        assert!(plain.pixels().all(|pixel| pixel[0] == pixel[1]));
+    }
+
+    #[test]
+    fn letter_spacing_changes_the_width() {
+        let fonts = fonts();
+        let scale = Scale::uniform(40.);
+        let tight = line_width(&fonts, scale, "Rosa canina", -0.02);
+        let plain = line_width(&fonts, scale, "Rosa canina", 0.);
+        let wide = line_width(&fonts, scale, "Rosa canina", 0.1);
+        assert!(tight < plain && plain < wide, "{tight} {plain} {wide}");
+        // Ten gaps between eleven characters
+        assert!((wide - plain - 10. * 0.1 * 40.).abs() < 0.01);
+    }
+
+    #[test]
+    fn right_to_left_and_combining_text_is_laid_out() {
+        let fonts = fonts();
+        let scale = Scale::uniform(40.);
+        for text in [
+            "\u{5d5}\u{5e8}\u{5d3}",
+            "O\u{308}nothera",
+            "e\u{301}\u{301}",
+        ] {
+            let glyphs = layout(&fonts, scale, text, 0.1, 30.);
+            assert_eq!(glyphs.len(), text.chars().count(), "{text}");
+            let mut target = RgbaImage::from_pixel(400, BAND_HEIGHT, WHITE);
+            draw_line(&glyphs, &mut target, BLACK, None);
+        }
+    }
+
+    #[test]
+    fn characters_outside_the_font_are_reported() {
+        let fonts = fonts();
+        assert!(fonts.missing_chars("Œnothera biennis").is_empty());
+        assert_eq!(fonts.missing_chars("櫻 花 (Prunus) 花"), ['櫻', '花']);
+        // A fallback without them does not help either
+        let with_fallback = Fonts {
+            fallback: Some(fonts.primary.clone()),
+            ..fonts
+        };
+        assert_eq!(with_fallback.missing_chars("花"), ['花']);
+    }
+
+    #[test]
+    fn auto_color_contrasts_with_the_region() {
+        // Dark on the left half, light on the right
+        let canvas = RgbaImage::from_fn(100, 40, |x, _| {
+            if x < 50 {
+                Rgba([30, 40, 35, 255])
+            } else {
+                Rgba([230, 225, 210, 255])
+            }
+        });
+        assert_eq!(auto_text_color(&canvas, 0..50, 0..40), WHITE);
+        assert_eq!(auto_text_color(&canvas, 50..100, 0..40), BLACK);
+        // Mostly light, so dark text still reads best
+        assert_eq!(auto_text_color(&canvas, 40..100, 0..40), BLACK);
+        // Clipped to the canvas, nothing left defaults to black
+        assert_eq!(auto_text_color(&canvas, 80..200, 30..90), BLACK);
+        assert_eq!(auto_text_color(&canvas, 150..200, 0..40), BLACK);
+    }
+}
@@ -0,0 +1,40 @@
+use image::Rgba;
+use serde::{Deserialize as _, Deserializer, de};
+
+use crate::Gradient;
+
+// Accepts "#rrggbb" and "#rrggbbaa"
+pub fn parse_hex_color(value: &str) -> Result<Rgba<u8>, String> {
+    let value = value.trim();
+    let hex = value
+        .strip_prefix('#')
+        .filter(|hex| {
+            (hex.len() == 6 || hex.len() == 8) && hex.chars().all(|c| c.is_ascii_hexdigit())
+        })
+        .ok_or_else(|| format!("Invalid color {value:?}, expected \"#rrggbb\" or \"#rrggbbaa\""))?;
+    let channel = |offset: usize| {
+        hex.get(offset..offset + 2)
+            .map_or(Ok(255), |channel| u8::from_str_radix(channel, 16))
+    };
+    match (channel(0), channel(2), channel(4), channel(6)) {
+        (Ok(r), Ok(g), Ok(b), Ok(a)) => Ok(Rgba([r, g, b, a])),
+        _ => Err(format!("Invalid color {value:?}")),
+    }
+}
+
+pub(crate) fn deserialize_color<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Rgba<u8>, D::Error> {
+    parse_hex_color(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+}
+
+// A ["#top", "#bottom"] pair of colors
+pub(crate) fn deserialize_optional_gradient<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Gradient>, D::Error> {
+    let [top, bottom] = <[String; 2]>::deserialize(deserializer)?;
+    Ok(Some((
+        parse_hex_color(&top).map_err(de::Error::custom)?,
+        parse_hex_color(&bottom).map_err(de::Error::custom)?,
+    )))
+}
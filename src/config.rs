@@ -0,0 +1,154 @@
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::{fmt, fs, io};
+
+use serde::Deserialize;
+
+use crate::color::deserialize_optional_gradient;
+use crate::schedule::deserialize_optional_jitter;
+use crate::{
+    Background, BlendMode, BorderConfig, CaptionLayout, CaptionOutline, ChangeInterval,
+    CornerRadii, FetchConfig, FitMode, Gradient, Insets, MontageConfig, OutputFormat, PhotoFilter,
+    ResizeFilter, ScheduleZone, SelectionStrategy, ShadowConfig, StampConfig, StatusConfig,
+    TextColor, WallpaperStyle, to_path,
+};
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "Cannot read config file: {err}"),
+            ConfigError::Parse(err) => write!(f, "Invalid config file: {err}"),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Io(err) => Some(err),
+            ConfigError::Parse(err) => Some(err),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub interval: ChangeInterval,
+    // "Europe/Berlin" changes at midnight in Berlin wherever the machine is
+    pub timezone: ScheduleZone,
+    // Shifts the changes of this machine by up to this much either way, e.g. "30m"
+    #[serde(deserialize_with = "deserialize_optional_jitter")]
+    pub jitter: Option<Duration>,
+    // Multiplies the sizes of the caption, margins and corners
+    pub scale: Option<f32>,
+    // Skip the flowers of changes missed while the machine was off
+    pub catch_up: bool,
+    pub selection: SelectionStrategy,
+    pub background: Background,
+    pub font: Option<PathBuf>,
+    pub fallback_font: Option<PathBuf>,
+    pub shadow: Option<ShadowConfig>,
+    pub border: Option<BorderConfig>,
+    pub text_color: Option<TextColor>,
+    pub text_outline: Option<CaptionOutline>,
+    // Extra space between the letters of the caption in pixels, negative moves them closer
+    pub letter_spacing: f32,
+    pub line_spacing: Option<f32>,
+    pub caption_layout: CaptionLayout,
+    // Edges of the screen the photo and the caption stay clear of, e.g. where the desktop icons are
+    pub safe_area: Option<Insets>,
+    // Minimum height below the photo for the caption, grows with the caption lines
+    pub caption_band: Option<u32>,
+    // "— {name} —" surrounds every caption with dashes
+    pub caption_template: Option<String>,
+    // Tiles the next flowers in a grid instead of showing one, leave the section out for one
+    pub montage: Option<MontageConfig>,
+    pub fit_mode: FitMode,
+    pub resize_filter: ResizeFilter,
+    pub min_photo_size: u32,
+    pub max_photo_megapixels: Option<u32>,
+    pub animation_frame: usize,
+    #[serde(rename = "filter")]
+    pub photo_filter: PhotoFilter,
+    pub brightness: i32,
+    pub contrast: f32,
+    pub corner_radius: Option<CornerRadii>,
+    pub corner_blending: BlendMode,
+    pub corner_aa: Option<u8>,
+    #[serde(deserialize_with = "deserialize_optional_gradient")]
+    pub caption_gradient: Option<Gradient>,
+    // Date or other details in a corner, leave the section out for none
+    pub info_stamp: Option<StampConfig>,
+    pub output_format: OutputFormat,
+    pub jpeg_quality: Option<u8>,
+    pub wallpaper_style: WallpaperStyle,
+    // Keeps a dated copy of every rendered wallpaper, relative paths are in the base path
+    pub archive_dir: Option<PathBuf>,
+    // Render every change to a new file name for desktops that cache the wallpaper by its path
+    pub unique_output_names: bool,
+    // Put the wallpaper from before the first run back when the service stops
+    pub restore_on_exit: bool,
+    // Also show the flower on the Windows lock screen
+    pub lock_screen: bool,
+    // Show a desktop notification with the caption of every new flower
+    pub notify: bool,
+    // Accept commands such as "next" from other programs, see Readme.md
+    pub control_pipe: bool,
+    pub fetch: FetchConfig,
+    pub status: StatusConfig,
+}
+
+impl Config {
+    // A missing config file means all defaults
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::parse(&read_config_file()?)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, ConfigError> {
+        toml::from_str(contents).map_err(ConfigError::Parse)
+    }
+}
+
+// The contents of config.toml, empty when there is none
+pub fn read_config_file() -> Result<String, ConfigError> {
+    match fs::read_to_string(to_path("config.toml")) {
+        Ok(contents) => Ok(contents),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        Err(err) => Err(ConfigError::Io(err)),
+    }
+}
+
+// Settings that differ between two versions of the config file, e.g. "background" or
+// "shadow.blur". Both have to be valid TOML.
+pub fn changed_settings(old: &str, new: &str) -> Vec<String> {
+    let (Ok(old), Ok(new)) = (old.parse::<toml::Table>(), new.parse::<toml::Table>()) else {
+        return Vec::new();
+    };
+    let mut changed = Vec::new();
+    collect_changes("", &old, &new, &mut changed);
+    changed
+}
+
+fn collect_changes(prefix: &str, old: &toml::Table, new: &toml::Table, changed: &mut Vec<String>) {
+    let mut keys: Vec<&String> = old.keys().chain(new.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    for key in keys {
+        let name = format!("{prefix}{key}");
+        match (old.get(key), new.get(key)) {
+            (Some(toml::Value::Table(old)), Some(toml::Value::Table(new))) => {
+                collect_changes(&format!("{name}."), old, new, changed)
+            }
+            (old, new) if old != new => changed.push(name),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use std::io::{self, BufRead as _, BufReader, Read, Write};
+use std::sync::mpsc::Sender;
+
+use serde_json::{Value, json};
+
+use crate::{SharedStatus, list_images, to_path};
+
+// Requests to the running service, e.g. from the tray menu
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Next,
+    Previous,
+    Pause(bool),
+    // Shows the flower from the next change on, `None` continues the rotation
+    Pin(Option<String>),
+    Quit,
+    // Images were added to or removed from the image directory
+    LibraryChanged,
+}
+
+// Answers one line such as "next" or "pin Rosa_canina.jpg" with one line of JSON. Returns false
+// once the scheduler is gone.
+fn serve_connection(
+    stream: impl Read + Write,
+    status: &SharedStatus,
+    commands: &Sender<Command>,
+) -> io::Result<bool> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let (command, argument) = match line.trim().split_once(' ') {
+        Some((command, argument)) => (command, argument.trim()),
+        None => (line.trim(), ""),
+    };
+    let (response, command) = match (command, argument) {
+        ("status", "") => {
+            let status = status
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .clone();
+            (json!({ "ok": true, "status": status }), None)
+        }
+        ("next", "") => (json!({ "ok": true }), Some(Command::Next)),
+        ("prev", "") => (json!({ "ok": true }), Some(Command::Previous)),
+        ("unpin", "") => (json!({ "ok": true }), Some(Command::Pin(None))),
+        ("pin", file_name) if !file_name.is_empty() => {
+            match list_images(&to_path("wiki_flowers")) {
+                Ok(image_file_names) if image_file_names.iter().any(|name| name == file_name) => (
+                    json!({ "ok": true }),
+                    Some(Command::Pin(Some(file_name.to_owned()))),
+                ),
+                Ok(_) => (error(format!("{file_name} is not in wiki_flowers")), None),
+                Err(err) => (error(format!("Cannot list wiki_flowers: {err}")), None),
+            }
+        }
+        _ => (
+            error(format!(
+                "Unknown command {:?}, expected next, prev, status, pin <file name> or unpin",
+                line.trim()
+            )),
+            None,
+        ),
+    };
+    let running = command.is_none_or(|command| commands.send(command).is_ok());
+    let response = if running {
+        response
+    } else {
+        error("The service is stopping".to_owned())
+    };
+    let stream = reader.get_mut();
+    writeln!(stream, "{response}")?;
+    stream.flush()?;
+    Ok(running)
+}
+
+fn error(message: String) -> Value {
+    json!({ "ok": false, "error": message })
+}
+
+#[cfg(windows)]
+pub const CONTROL_PIPE: &str = r"\\.\pipe\flower-service";
+
+// Serves commands on the named pipe until the scheduler is gone. Only the current user and
+// administrators can write to it, remote clients are rejected.
+#[cfg(windows)]
+pub fn run_control_server(status: SharedStatus, commands: Sender<Command>) -> io::Result<()> {
+    use std::ffi::OsStr;
+    use std::os::windows::ffi::OsStrExt as _;
+
+    use winapi::shared::winerror::ERROR_PIPE_CONNECTED;
+    use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+    use winapi::um::namedpipeapi::{ConnectNamedPipe, CreateNamedPipeW, DisconnectNamedPipe};
+    use winapi::um::winbase::{
+        PIPE_ACCESS_DUPLEX, PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE,
+        PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+    };
+
+    const BUFFER_SIZE: u32 = 4096;
+
+    let name: Vec<u16> = OsStr::new(CONTROL_PIPE)
+        .encode_wide()
+        .chain(Some(0))
+        .collect();
+    log::info!("Accepting commands on {CONTROL_PIPE}");
+    loop {
+        let handle = unsafe {
+            CreateNamedPipeW(
+                name.as_ptr(),
+                PIPE_ACCESS_DUPLEX,
+                PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                PIPE_UNLIMITED_INSTANCES,
+                BUFFER_SIZE,
+                BUFFER_SIZE,
+                0,
+                std::ptr::null_mut(),
+            )
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+        let pipe = windows_pipe::Pipe(handle);
+        // A client that connected before the call is not an error
+        if unsafe { ConnectNamedPipe(handle, std::ptr::null_mut()) } == 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(ERROR_PIPE_CONNECTED as i32) {
+                log::warn!("Cannot accept a client on {CONTROL_PIPE}: {err}");
+                continue;
+            }
+        }
+        let result = serve_connection(&pipe, &status, &commands);
+        unsafe { DisconnectNamedPipe(handle) };
+        match result {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(err) => log::warn!("Cannot answer a command on {CONTROL_PIPE}: {err}"),
+        }
+    }
+}
+
+#[cfg(windows)]
+mod windows_pipe {
+    use std::io::{self, Read, Write};
+
+    use winapi::um::fileapi::{FlushFileBuffers, ReadFile, WriteFile};
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::winnt::HANDLE;
+
+    // One connected instance of the pipe, closed when dropped
+    pub struct Pipe(pub HANDLE);
+
+    impl Read for &Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut read = 0;
+            let len = buf.len().min(u32::MAX as usize) as u32;
+            let ok = unsafe {
+                ReadFile(
+                    self.0,
+                    buf.as_mut_ptr() as *mut _,
+                    len,
+                    &mut read,
+                    std::ptr::null_mut(),
+                )
+            };
+            if ok == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(read as usize)
+        }
+    }
+
+    impl Write for &Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut written = 0;
+            let len = buf.len().min(u32::MAX as usize) as u32;
+            let ok = unsafe {
+                WriteFile(
+                    self.0,
+                    buf.as_ptr() as *const _,
+                    len,
+                    &mut written,
+                    std::ptr::null_mut(),
+                )
+            };
+            if ok == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(written as usize)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            if unsafe { FlushFileBuffers(self.0) } == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for Pipe {
+        fn drop(&mut self) {
+            unsafe { CloseHandle(self.0) };
+        }
+    }
+}
+
+// Elsewhere the same commands go to a Unix socket in the base path
+#[cfg(unix)]
+pub fn run_control_server(status: SharedStatus, commands: Sender<Command>) -> io::Result<()> {
+    use std::os::unix::net::UnixListener;
+
+    let path = to_path("flower-service.sock");
+    // Left behind by a service that was killed; the instance lock keeps a second one from getting here
+    match std::fs::remove_file(&path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    let listener = UnixListener::bind(&path)?;
+    log::info!("Accepting commands on {}", path.display());
+    for stream in listener.incoming() {
+        match stream.and_then(|stream| serve_connection(stream, &status, &commands)) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) => log::warn!("Cannot answer a command on {}: {err}", path.display()),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,343 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use image::RgbaImage;
+use serde::Deserialize;
+
+use crate::BlendMode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomRight,
+    BottomLeft,
+}
+
+impl Corner {
+    pub const ALL: [Corner; 4] = [
+        Corner::TopLeft,
+        Corner::TopRight,
+        Corner::BottomRight,
+        Corner::BottomLeft,
+    ];
+}
+
+// Radius of every corner of the photo, 0 leaves a corner sharp.
+// The config takes a single radius or four in the order of `Corner::ALL`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(from = "RadiusSetting")]
+pub struct CornerRadii {
+    pub top_left: u32,
+    pub top_right: u32,
+    pub bottom_right: u32,
+    pub bottom_left: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RadiusSetting {
+    Uniform(u32),
+    PerCorner([u32; 4]),
+}
+
+impl From<RadiusSetting> for CornerRadii {
+    fn from(setting: RadiusSetting) -> Self {
+        match setting {
+            RadiusSetting::Uniform(radius) => CornerRadii::uniform(radius),
+            RadiusSetting::PerCorner([top_left, top_right, bottom_right, bottom_left]) => {
+                CornerRadii {
+                    top_left,
+                    top_right,
+                    bottom_right,
+                    bottom_left,
+                }
+            }
+        }
+    }
+}
+
+impl CornerRadii {
+    pub const fn uniform(radius: u32) -> Self {
+        Self {
+            top_left: radius,
+            top_right: radius,
+            bottom_right: radius,
+            bottom_left: radius,
+        }
+    }
+
+    pub fn get(&self, corner: Corner) -> u32 {
+        match corner {
+            Corner::TopLeft => self.top_left,
+            Corner::TopRight => self.top_right,
+            Corner::BottomRight => self.bottom_right,
+            Corner::BottomLeft => self.bottom_left,
+        }
+    }
+
+    pub(crate) fn map(&self, f: impl Fn(u32) -> u32) -> Self {
+        Self {
+            top_left: f(self.top_left),
+            top_right: f(self.top_right),
+            bottom_right: f(self.bottom_right),
+            bottom_left: f(self.bottom_left),
+        }
+    }
+}
+
+// Coverage of a pixel by a rounded rectangle, with the same corner curve as the corner masks.
+// The radii must not exceed half of the width or height.
+pub(crate) fn rounded_rect_coverage(
+    (x, y): (u32, u32),
+    (width, height): (u32, u32),
+    radii: CornerRadii,
+) -> f32 {
+    let radius = match (x < width / 2, y < height / 2) {
+        (true, true) => radii.top_left,
+        (false, true) => radii.top_right,
+        (false, false) => radii.bottom_right,
+        (true, false) => radii.bottom_left,
+    };
+    let corner_distance = |position: u32, size: u32| {
+        if position < radius {
+            Some(radius - (position + 1))
+        } else if position >= size - radius {
+            Some(position - (size - radius))
+        } else {
+            None
+        }
+    };
+    match (corner_distance(x, width), corner_distance(y, height)) {
+        (Some(dx), Some(dy)) => {
+            let distance = ((dx * dx + dy * dy) as f32).sqrt();
+            1. - (distance - radius as f32 + 0.5).clamp(0., 1.)
+        }
+        _ => 1.,
+    }
+}
+
+// The mask holds the quarter circle around its origin, opaque outside the radius. Every pixel
+// averages `samples` x `samples` points, each with an edge that is 1 / `samples` pixels soft;
+// a single sample is the plain distance test.
+pub fn build_corner_mask(radius: u32, color: image::Rgba<u8>, samples: u8) -> RgbaImage {
+    let [r, g, b, _] = color.0;
+    let samples = samples.max(1) as u32;
+    let step = 1. / samples as f32;
+    RgbaImage::from_fn(radius, radius, |x, y| {
+        let mut coverage = 0.;
+        for sample_y in 0..samples {
+            for sample_x in 0..samples {
+                // Centered on the pixel position, a single sample sits right on it
+                let px = x as f32 + (sample_x as f32 + 0.5) * step - 0.5;
+                let py = y as f32 + (sample_y as f32 + 0.5) * step - 0.5;
+                let distance = (px * px + py * py).sqrt();
+                coverage += ((distance - radius as f32) * samples as f32 + 0.5).clamp(0., 1.);
+            }
+        }
+        let alpha = coverage / (samples * samples) as f32 * 255.;
+        image::Rgba([r, g, b, alpha as u8])
+    })
+}
+
+type MaskCache = HashMap<(u32, u8), Arc<RgbaImage>>;
+
+// Only the alpha of the cached masks is used, the color comes from the backdrop
+pub(crate) fn cached_corner_mask(radius: u32, samples: u8) -> Arc<RgbaImage> {
+    static CORNER_MASKS: OnceLock<Mutex<MaskCache>> = OnceLock::new();
+    let mut masks = CORNER_MASKS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    masks
+        .entry((radius, samples))
+        .or_insert_with(|| {
+            Arc::new(build_corner_mask(
+                radius,
+                image::Rgba([0, 0, 0, 255]),
+                samples,
+            ))
+        })
+        .clone()
+}
+
+// Blends one corner of the placed image, located at `offset` on the canvas, with the mask
+pub fn round_corner(
+    canvas: &mut RgbaImage,
+    resized: &RgbaImage,
+    offset: (u32, u32),
+    mask: &RgbaImage,
+    corner: Corner,
+) {
+    blend_corner(
+        canvas,
+        resized,
+        offset,
+        mask,
+        corner,
+        BlendMode::Srgb,
+        |_, _, mask_pixel| mask_pixel,
+    );
+}
+
+// Like `round_corner`, but blends towards the pixel of `backdrop` instead of the mask color,
+// for backgrounds that are not a single color
+pub fn round_corner_over(
+    canvas: &mut RgbaImage,
+    backdrop: &RgbaImage,
+    resized: &RgbaImage,
+    offset: (u32, u32),
+    mask: &RgbaImage,
+    corner: Corner,
+    blending: BlendMode,
+) {
+    blend_corner(
+        canvas,
+        resized,
+        offset,
+        mask,
+        corner,
+        blending,
+        |x, y, mask_pixel| {
+            let [r, g, b, _] = backdrop.get_pixel(x, y).0;
+            image::Rgba([r, g, b, mask_pixel[3]])
+        },
+    );
+}
+
+fn blend_corner(
+    canvas: &mut RgbaImage,
+    resized: &RgbaImage,
+    offset: (u32, u32),
+    mask: &RgbaImage,
+    corner: Corner,
+    blending: BlendMode,
+    blend_color: impl Fn(u32, u32, image::Rgba<u8>) -> image::Rgba<u8>,
+) {
+    let (width, height) = resized.dimensions();
+    let radius = mask.width().min(mask.height()).min(width).min(height);
+    let (left, top) = match corner {
+        Corner::TopLeft => (true, true),
+        Corner::TopRight => (false, true),
+        Corner::BottomRight => (false, false),
+        Corner::BottomLeft => (true, false),
+    };
+    let x_range = if left {
+        0..radius
+    } else {
+        width - radius..width
+    };
+    let y_range = if top {
+        0..radius
+    } else {
+        height - radius..height
+    };
+
+    for y in y_range {
+        let mask_y = if top {
+            radius - (y + 1)
+        } else {
+            y - (height - radius)
+        };
+        for x in x_range.clone() {
+            let mask_x = if left {
+                radius - (x + 1)
+            } else {
+                x - (width - radius)
+            };
+            let (canvas_x, canvas_y) = (offset.0.saturating_add(x), offset.1.saturating_add(y));
+            if canvas_x >= canvas.width() || canvas_y >= canvas.height() {
+                continue;
+            }
+            let mask_pixel = blend_color(canvas_x, canvas_y, *mask.get_pixel(mask_x, mask_y));
+            let pixel = blending.blend(*resized.get_pixel(x, y), mask_pixel);
+            canvas.put_pixel(canvas_x, canvas_y, pixel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+    const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
+    // Space around the square, so the corners are blended at an offset like on a real canvas
+    const INSET: u32 = 3;
+
+    // A red square placed on a white canvas with the corners in `radii` rounded
+    fn rounded_square(size: u32, radii: CornerRadii) -> RgbaImage {
+        let square = RgbaImage::from_pixel(size, size, RED);
+        let mut canvas = RgbaImage::from_pixel(size + 2 * INSET, size + 2 * INSET, WHITE);
+        image::imageops::replace(&mut canvas, &square, INSET.into(), INSET.into());
+        for corner in Corner::ALL {
+            let radius = radii.get(corner);
+            if radius > 0 {
+                let mask = build_corner_mask(radius, WHITE, 1);
+                round_corner(&mut canvas, &square, (INSET, INSET), &mask, corner);
+            }
+        }
+        canvas
+    }
+
+    #[test]
+    fn all_four_corners_are_rounded_alike() {
+        let size = 40;
+        let canvas = rounded_square(size, CornerRadii::uniform(10));
+        let last = canvas.width() - 1;
+        for (x, y, pixel) in canvas.enumerate_pixels() {
+            assert_eq!(
+                pixel,
+                canvas.get_pixel(last - x, y),
+                "({x}, {y}) left to right"
+            );
+            assert_eq!(
+                pixel,
+                canvas.get_pixel(x, last - y),
+                "({x}, {y}) top to bottom"
+            );
+        }
+        assert_eq!(*canvas.get_pixel(INSET, INSET), WHITE);
+        assert_eq!(*canvas.get_pixel(INSET + size / 2, INSET), RED);
+        assert_eq!(*canvas.get_pixel(INSET, INSET + size / 2), RED);
+    }
+
+    #[test]
+    fn only_the_top_corners_are_rounded() {
+        let size = 40;
+        let radii: CornerRadii = serde_json::from_str("[10, 10, 0, 0]").unwrap();
+        let canvas = rounded_square(size, radii);
+        let (near, far) = (INSET, INSET + size - 1);
+        assert_eq!(*canvas.get_pixel(near, near), WHITE);
+        assert_eq!(*canvas.get_pixel(far, near), WHITE);
+        assert_eq!(*canvas.get_pixel(near, far), RED);
+        assert_eq!(*canvas.get_pixel(far, far), RED);
+        // Below the top corners the sides are straight
+        for y in INSET + 10..=far {
+            assert_eq!(*canvas.get_pixel(near, y), RED, "left side at {y}");
+            assert_eq!(*canvas.get_pixel(far, y), RED, "right side at {y}");
+        }
+    }
+
+    #[test]
+    fn supersampling_smooths_the_mask_edge() {
+        let radius = 10;
+        let single = build_corner_mask(radius, WHITE, 1);
+        let supersampled = build_corner_mask(radius, WHITE, 4);
+        let is_partial = |alpha: u8| alpha > 0 && alpha < 255;
+        let smoothed = single
+            .pixels()
+            .zip(supersampled.pixels())
+            .filter(|(single, supersampled)| !is_partial(single[3]) && is_partial(supersampled[3]))
+            .count();
+        assert!(smoothed > 0);
+        // Far inside and outside the curve both agree
+        assert_eq!(single.get_pixel(0, 0)[3], 0);
+        assert_eq!(supersampled.get_pixel(0, 0)[3], 0);
+        assert_eq!(single.get_pixel(radius - 1, radius - 1)[3], 255);
+        assert_eq!(supersampled.get_pixel(radius - 1, radius - 1)[3], 255);
+    }
+}
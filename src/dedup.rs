@@ -0,0 +1,89 @@
+use std::path::Path;
+
+use image::imageops::{self, FilterType};
+use rayon::prelude::*;
+
+// Hashes that differ in at most this many of their 64 bits count as the same photo
+pub const DUPLICATE_DISTANCE: u32 = 6;
+
+// Near-identical photos, with the one that has the most pixels to keep
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateGroup {
+    pub keep: String,
+    pub remove: Vec<String>,
+}
+
+struct Hashed {
+    file_name: String,
+    hash: u64,
+    pixels: u64,
+}
+
+// Difference hash: a bit for every pair of horizontal neighbours of a 9x8 grayscale thumbnail,
+// set where the left one is brighter. Scaling, recompression and small color changes keep it.
+pub fn difference_hash(image: &image::DynamicImage) -> u64 {
+    let thumbnail = imageops::resize(&image.to_luma8(), 9, 8, FilterType::Triangle);
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if thumbnail.get_pixel(x, y)[0] > thumbnail.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+// Groups the images of `dir` whose hashes are within `max_distance`. Files that cannot be read
+// are left out with a warning.
+pub fn find_duplicates(
+    dir: &Path,
+    file_names: &[String],
+    max_distance: u32,
+) -> Vec<DuplicateGroup> {
+    let hashed: Vec<Hashed> = file_names
+        .par_iter()
+        .filter_map(|file_name| match image::open(dir.join(file_name)) {
+            Ok(image) => Some(Hashed {
+                file_name: file_name.clone(),
+                hash: difference_hash(&image),
+                pixels: image.width() as u64 * image.height() as u64,
+            }),
+            Err(err) => {
+                log::warn!("Cannot read {file_name}, leaving it out: {err}");
+                None
+            }
+        })
+        .collect();
+
+    // Every image joins the first group with a member close enough, in the order of `file_names`
+    let mut groups: Vec<Vec<&Hashed>> = Vec::new();
+    for image in &hashed {
+        let close = groups.iter_mut().find(|group| {
+            group
+                .iter()
+                .any(|member| (member.hash ^ image.hash).count_ones() <= max_distance)
+        });
+        match close {
+            Some(group) => group.push(image),
+            None => groups.push(vec![image]),
+        }
+    }
+
+    groups
+        .into_iter()
+        .filter(|group| group.len() > 1)
+        .map(|mut group| {
+            // Stable, so the first listed wins between equal resolutions
+            group.sort_by_key(|image| std::cmp::Reverse(image.pixels));
+            DuplicateGroup {
+                keep: group[0].file_name.clone(),
+                remove: group[1..]
+                    .iter()
+                    .map(|image| image.file_name.clone())
+                    .collect(),
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,111 @@
+use std::error::Error;
+use std::path::PathBuf;
+use std::{fmt, io};
+
+use crate::{ConfigError, RenderError, StateError};
+
+// Everything that ends the service or one of its commands
+#[derive(Debug)]
+pub enum ServiceError {
+    Io(io::Error),
+    // The image directory cannot be listed, or it holds no images
+    ImageDirectory(PathBuf, Option<io::Error>),
+    State(StateError),
+    Config(ConfigError),
+    Render(RenderError),
+    ExitHandler(ctrlc::Error),
+    // Wrong arguments or unusable data, the message says which
+    Invalid(String),
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceError::Io(err) => write!(f, "I/O error: {err}"),
+            ServiceError::ImageDirectory(path, Some(err)) => {
+                write!(f, "Cannot list the images in {}: {err}", path.display())
+            }
+            ServiceError::ImageDirectory(path, None) => {
+                write!(f, "No images found in {}", path.display())
+            }
+            ServiceError::State(err) => write!(f, "{err}"),
+            ServiceError::Config(err) => write!(f, "{err}"),
+            ServiceError::Render(err) => write!(f, "{err}"),
+            ServiceError::ExitHandler(err) => write!(f, "Cannot install the exit handler: {err}"),
+            ServiceError::Invalid(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl Error for ServiceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ServiceError::Io(err) => Some(err),
+            ServiceError::ImageDirectory(_, err) => err.as_ref().map(|err| err as _),
+            ServiceError::State(err) => Some(err),
+            ServiceError::Config(err) => Some(err),
+            ServiceError::Render(err) => Some(err),
+            ServiceError::ExitHandler(err) => Some(err),
+            ServiceError::Invalid(_) => None,
+        }
+    }
+}
+
+impl ServiceError {
+    // The message with every cause that it does not already include, for the log
+    pub fn report(&self) -> String {
+        let mut report = self.to_string();
+        let mut source = self.source();
+        while let Some(cause) = source {
+            let message = cause.to_string();
+            if !report.contains(&message) {
+                report.push_str("\n  caused by: ");
+                report.push_str(&message);
+            }
+            source = cause.source();
+        }
+        report
+    }
+}
+
+impl From<io::Error> for ServiceError {
+    fn from(err: io::Error) -> Self {
+        ServiceError::Io(err)
+    }
+}
+
+impl From<StateError> for ServiceError {
+    fn from(err: StateError) -> Self {
+        ServiceError::State(err)
+    }
+}
+
+impl From<ConfigError> for ServiceError {
+    fn from(err: ConfigError) -> Self {
+        ServiceError::Config(err)
+    }
+}
+
+impl From<RenderError> for ServiceError {
+    fn from(err: RenderError) -> Self {
+        ServiceError::Render(err)
+    }
+}
+
+impl From<ctrlc::Error> for ServiceError {
+    fn from(err: ctrlc::Error) -> Self {
+        ServiceError::ExitHandler(err)
+    }
+}
+
+impl From<String> for ServiceError {
+    fn from(message: String) -> Self {
+        ServiceError::Invalid(message)
+    }
+}
+
+impl From<&str> for ServiceError {
+    fn from(message: &str) -> Self {
+        ServiceError::Invalid(message.to_owned())
+    }
+}
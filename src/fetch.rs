@@ -0,0 +1,257 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+use std::{fmt, fs, io};
+
+use chrono::{DateTime, Local, TimeDelta, TimeZone as _};
+use serde::Deserialize;
+
+use crate::library::is_supported_image;
+use crate::state::write_atomic;
+use crate::{Command, list_images, sleep_until, to_path};
+
+const API_URL: &str = "https://commons.wikimedia.org/w/api.php";
+// Wikimedia asks clients to identify themselves
+const USER_AGENT: &str = concat!(
+    "flower-service/",
+    env!("CARGO_PKG_VERSION"),
+    " (https://github.com/Fundevoge/flower-service)"
+);
+const FETCH_INTERVAL: TimeDelta = TimeDelta::weeks(1);
+// Bounds the walk through a huge category that has few new files
+const MAX_API_PAGES: usize = 20;
+// Commons scales the downloads to this width, originals can be hundreds of megapixels
+const DOWNLOAD_WIDTH: &str = "2560";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FetchConfig {
+    pub enabled: bool,
+    // Commons category without the "Category:" prefix
+    pub category: String,
+    // New images downloaded per fetch at most
+    pub max_count: usize,
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            category: "Flowers".to_owned(),
+            max_count: 20,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FetchError {
+    Http(reqwest::Error),
+    Io(io::Error),
+    Image(image::ImageError),
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::Http(err) => write!(f, "Request to Wikimedia Commons failed: {err}"),
+            FetchError::Io(err) => write!(f, "Cannot store downloaded image: {err}"),
+            FetchError::Image(err) => write!(f, "Downloaded file is not a valid image: {err}"),
+        }
+    }
+}
+
+impl Error for FetchError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FetchError::Http(err) => Some(err),
+            FetchError::Io(err) => Some(err),
+            FetchError::Image(err) => Some(err),
+        }
+    }
+}
+
+impl From<reqwest::Error> for FetchError {
+    fn from(err: reqwest::Error) -> Self {
+        FetchError::Http(err)
+    }
+}
+
+impl From<io::Error> for FetchError {
+    fn from(err: io::Error) -> Self {
+        FetchError::Io(err)
+    }
+}
+
+impl From<image::ImageError> for FetchError {
+    fn from(err: image::ImageError) -> Self {
+        FetchError::Image(err)
+    }
+}
+
+#[derive(Deserialize)]
+struct ApiResponse {
+    #[serde(rename = "continue")]
+    continuation: Option<Continuation>,
+    query: Option<Query>,
+}
+
+#[derive(Deserialize)]
+struct Continuation {
+    gcmcontinue: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Query {
+    pages: HashMap<String, Page>,
+}
+
+#[derive(Deserialize)]
+struct Page {
+    title: String,
+    #[serde(default)]
+    imageinfo: Vec<ImageInfo>,
+}
+
+#[derive(Deserialize)]
+struct ImageInfo {
+    url: Option<String>,
+    thumburl: Option<String>,
+}
+
+// "File:Rosa canina.jpg" becomes "Rosa_canina.jpg", the way Commons names its files
+fn local_file_name(title: &str) -> String {
+    title
+        .strip_prefix("File:")
+        .unwrap_or(title)
+        .chars()
+        .map(|c| match c {
+            ' ' | '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+// Downloads images of the configured category that are not in `dir` yet, by file name.
+// Returns the names of the new files.
+pub fn fetch_new_images(config: &FetchConfig, dir: &Path) -> Result<Vec<String>, FetchError> {
+    let client = reqwest::blocking::Client::builder()
+        .user_agent(USER_AGENT)
+        .timeout(Duration::from_secs(60))
+        .build()?;
+    let existing: HashSet<String> = list_images(dir)?
+        .iter()
+        .map(|path| path.rsplit('/').next().unwrap_or(path).to_owned())
+        .collect();
+
+    let category = format!("Category:{}", config.category);
+    let mut downloaded = Vec::new();
+    let mut continuation: Option<String> = None;
+    for _ in 0..MAX_API_PAGES {
+        let mut query = vec![
+            ("action", "query"),
+            ("format", "json"),
+            ("generator", "categorymembers"),
+            ("gcmtitle", category.as_str()),
+            ("gcmtype", "file"),
+            ("gcmlimit", "50"),
+            ("prop", "imageinfo"),
+            ("iiprop", "url"),
+            ("iiurlwidth", DOWNLOAD_WIDTH),
+        ];
+        if let Some(continuation) = &continuation {
+            query.push(("gcmcontinue", continuation.as_str()));
+        }
+        let response: ApiResponse = client
+            .get(API_URL)
+            .query(&query)
+            .send()?
+            .error_for_status()?
+            .json()?;
+
+        let mut pages: Vec<Page> = response
+            .query
+            .map(|query| query.pages.into_values().collect())
+            .unwrap_or_default();
+        pages.sort_by(|a, b| a.title.cmp(&b.title));
+        for page in pages {
+            if downloaded.len() >= config.max_count {
+                return Ok(downloaded);
+            }
+            let file_name = local_file_name(&page.title);
+            if existing.contains(&file_name) || !is_supported_image(&file_name) {
+                continue;
+            }
+            let Some(url) = page
+                .imageinfo
+                .into_iter()
+                .next()
+                .and_then(|info| info.thumburl.or(info.url))
+            else {
+                continue;
+            };
+            match download(&client, &url, &dir.join(&file_name)) {
+                Ok(()) => {
+                    log::info!("Downloaded {file_name} from Wikimedia Commons");
+                    downloaded.push(file_name);
+                }
+                Err(err) => log::warn!("Skipping {file_name}: {err}"),
+            }
+        }
+
+        continuation = response
+            .continuation
+            .and_then(|continuation| continuation.gcmcontinue);
+        if continuation.is_none() {
+            break;
+        }
+    }
+    Ok(downloaded)
+}
+
+// Only files that decode end up in the image directory
+fn download(client: &reqwest::blocking::Client, url: &str, path: &Path) -> Result<(), FetchError> {
+    let bytes = client.get(url).send()?.error_for_status()?.bytes()?;
+    image::load_from_memory(&bytes)?;
+    write_atomic(path, &bytes)?;
+    Ok(())
+}
+
+fn last_fetch_path() -> PathBuf {
+    to_path("last_fetch.txt")
+}
+
+fn last_fetch() -> Option<DateTime<Local>> {
+    let seconds = fs::read_to_string(last_fetch_path())
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Local.timestamp_opt(seconds, 0).earliest()
+}
+
+// Fetches once a week and tells the scheduler about new images, until the scheduler is gone
+pub fn run_fetcher(config: FetchConfig, commands: Sender<Command>) {
+    let dir = to_path("wiki_flowers");
+    loop {
+        if let Some(last_fetch) = last_fetch() {
+            sleep_until(last_fetch + FETCH_INTERVAL);
+        }
+        match fetch_new_images(&config, &dir) {
+            Ok(downloaded) if downloaded.is_empty() => log::info!("No new images to fetch"),
+            Ok(downloaded) => {
+                log::info!("Fetched {} new images", downloaded.len());
+                if commands.send(Command::LibraryChanged).is_err() {
+                    return;
+                }
+            }
+            Err(err) => log::warn!("Fetching new images failed: {err}"),
+        }
+        if let Err(err) = write_atomic(&last_fetch_path(), Local::now().timestamp().to_string()) {
+            log::error!("Failed to store the time of the last fetch: {err}");
+            // Without the stored time the next iteration would fetch right away
+            sleep_until(Local::now() + FETCH_INTERVAL);
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use image::RgbaImage;
+use image::imageops::colorops;
+use serde::Deserialize;
+
+// Applied to the photo only, the caption and the rest of the wallpaper keep their colors
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PhotoFilter {
+    #[default]
+    None,
+    Grayscale,
+    Sepia,
+}
+
+// Brightness is added to every channel, contrast is in percent; 0 leaves them as they are
+pub(crate) fn apply_filter(
+    image: &mut RgbaImage,
+    filter: PhotoFilter,
+    brightness: i32,
+    contrast: f32,
+) {
+    match filter {
+        PhotoFilter::None => {}
+        PhotoFilter::Grayscale => {
+            for pixel in image.pixels_mut() {
+                let [r, g, b, _] = pixel.0.map(f32::from);
+                let luma = (0.2126 * r + 0.7152 * g + 0.0722 * b).round() as u8;
+                pixel.0[..3].fill(luma);
+            }
+        }
+        PhotoFilter::Sepia => {
+            for pixel in image.pixels_mut() {
+                let [r, g, b, _] = pixel.0.map(f32::from);
+                pixel.0[0] = (0.393 * r + 0.769 * g + 0.189 * b).min(255.) as u8;
+                pixel.0[1] = (0.349 * r + 0.686 * g + 0.168 * b).min(255.) as u8;
+                pixel.0[2] = (0.272 * r + 0.534 * g + 0.131 * b).min(255.) as u8;
+            }
+        }
+    }
+    if brightness != 0 {
+        colorops::brighten_in_place(image, brightness);
+    }
+    if contrast != 0. {
+        colorops::contrast_in_place(image, contrast);
+    }
+}
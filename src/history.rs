@@ -0,0 +1,52 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write as _};
+use std::path::PathBuf;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::to_path;
+
+// One shown flower; `index` is the position of the image in the sorted image list
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub timestamp: DateTime<Local>,
+    pub index: usize,
+    pub file_name: String,
+}
+
+fn history_path() -> PathBuf {
+    to_path("history.jsonl")
+}
+
+// Appends one JSON object per line, so a crash can at most cut off the last entry
+pub fn append_history(entry: &HistoryEntry) -> io::Result<()> {
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(history_path())?
+        .write_all(line.as_bytes())
+}
+
+// The last `count` entries, oldest first; lines that do not parse are skipped
+pub fn load_history(count: usize) -> io::Result<Vec<HistoryEntry>> {
+    let contents = match fs::read_to_string(history_path()) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let entries: Vec<HistoryEntry> = contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(entry) => Some(entry),
+            Err(err) => {
+                log::warn!("Skipping malformed history entry {line:?}: {err}");
+                None
+            }
+        })
+        .collect();
+    Ok(entries[entries.len().saturating_sub(count)..].to_vec())
+}
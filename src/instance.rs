@@ -0,0 +1,62 @@
+use std::io;
+
+// Held for the lifetime of the service so a second copy does not race on the wallpaper and state
+pub struct InstanceGuard {
+    #[cfg(windows)]
+    handle: winapi::um::winnt::HANDLE,
+    #[cfg(not(windows))]
+    _lock_file: std::fs::File,
+}
+
+impl InstanceGuard {
+    // Returns `None` if another instance already holds the guard
+    #[cfg(windows)]
+    pub fn acquire() -> io::Result<Option<Self>> {
+        use std::ffi::OsStr;
+        use std::os::windows::ffi::OsStrExt as _;
+        use std::ptr;
+
+        use winapi::shared::winerror::ERROR_ALREADY_EXISTS;
+        use winapi::um::errhandlingapi::GetLastError;
+        use winapi::um::handleapi::CloseHandle;
+        use winapi::um::synchapi::CreateMutexW;
+
+        let name: Vec<u16> = OsStr::new("Local\\flower-service-single-instance")
+            .encode_wide()
+            .chain(Some(0))
+            .collect();
+        let handle = unsafe { CreateMutexW(ptr::null_mut(), 0, name.as_ptr()) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { GetLastError() } == ERROR_ALREADY_EXISTS {
+            unsafe { CloseHandle(handle) };
+            return Ok(None);
+        }
+        Ok(Some(Self { handle }))
+    }
+
+    // The lock is advisory and released by the OS when the process exits, even after a crash
+    #[cfg(not(windows))]
+    pub fn acquire() -> io::Result<Option<Self>> {
+        let lock_file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(crate::to_path("flower-service.lock"))?;
+        match lock_file.try_lock() {
+            Ok(()) => Ok(Some(Self {
+                _lock_file: lock_file,
+            })),
+            Err(std::fs::TryLockError::WouldBlock) => Ok(None),
+            Err(std::fs::TryLockError::Error(err)) => Err(err),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for InstanceGuard {
+    fn drop(&mut self) {
+        unsafe { winapi::um::handleapi::CloseHandle(self.handle) };
+    }
+}
@@ -0,0 +1,146 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HorizontalAlign {
+    Left,
+    #[default]
+    Center,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VerticalAlign {
+    Top,
+    #[default]
+    Center,
+    Bottom,
+}
+
+// Where the caption goes; `Auto` puts it beside photos that are much narrower than the box
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptionLayout {
+    #[default]
+    Bottom,
+    Auto,
+}
+
+// Space kept clear along each edge of the canvas, e.g. for desktop icons, in canvas pixels
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct Insets {
+    pub left: u32,
+    pub top: u32,
+    pub right: u32,
+    pub bottom: u32,
+}
+
+impl Insets {
+    pub(crate) fn scaled(&self, factor: f32) -> Self {
+        let scale = |value: u32| (value as f32 * factor).round() as u32;
+        Self {
+            left: scale(self.left),
+            top: scale(self.top),
+            right: scale(self.right),
+            bottom: scale(self.bottom),
+        }
+    }
+}
+
+// Where a photo sits in its image box, read from a sidecar next to the photo
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct Layout {
+    pub horizontal: HorizontalAlign,
+    pub vertical: VerticalAlign,
+}
+
+impl Layout {
+    // "Rosa_canina.jpg" takes its layout from "Rosa_canina.layout.toml".
+    // A missing or malformed sidecar only means the photo is centered.
+    pub fn load_for(image_path: &Path) -> Self {
+        let Some(path) = sidecar_path(image_path) else {
+            return Self::default();
+        };
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Self::default(),
+            Err(err) => {
+                log::warn!("Cannot read {}, ignoring it: {err}", path.display());
+                return Self::default();
+            }
+        };
+        toml::from_str(&contents).unwrap_or_else(|err| {
+            log::warn!("Invalid {}, ignoring it: {err}", path.display());
+            Self::default()
+        })
+    }
+
+    // Offset of a photo of `size` inside a box of `box_size` starting at `box_origin`
+    pub(crate) fn offset(
+        &self,
+        box_origin: (u32, u32),
+        box_size: (u32, u32),
+        size: (u32, u32),
+    ) -> (u32, u32) {
+        let free_x = box_size.0.saturating_sub(size.0);
+        let free_y = box_size.1.saturating_sub(size.1);
+        let x = match self.horizontal {
+            HorizontalAlign::Left => 0,
+            HorizontalAlign::Center => free_x / 2,
+            HorizontalAlign::Right => free_x,
+        };
+        let y = match self.vertical {
+            VerticalAlign::Top => 0,
+            VerticalAlign::Center => free_y / 2,
+            VerticalAlign::Bottom => free_y,
+        };
+        (box_origin.0 + x, box_origin.1 + y)
+    }
+}
+
+fn sidecar_path(image_path: &Path) -> Option<PathBuf> {
+    let stem = image_path.file_stem()?.to_str()?;
+    Some(image_path.with_file_name(format!("{stem}.layout.toml")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn photo_larger_than_its_box_stays_at_the_origin() {
+        for horizontal in [
+            HorizontalAlign::Left,
+            HorizontalAlign::Center,
+            HorizontalAlign::Right,
+        ] {
+            for vertical in [
+                VerticalAlign::Top,
+                VerticalAlign::Center,
+                VerticalAlign::Bottom,
+            ] {
+                let layout = Layout {
+                    horizontal,
+                    vertical,
+                };
+                assert_eq!(layout.offset((50, 50), (100, 60), (300, 200)), (50, 50));
+            }
+        }
+    }
+
+    #[test]
+    fn photo_is_aligned_in_its_box() {
+        let layout = Layout {
+            horizontal: HorizontalAlign::Right,
+            vertical: VerticalAlign::Center,
+        };
+        assert_eq!(layout.offset((50, 50), (100, 60), (40, 20)), (110, 70));
+    }
+}
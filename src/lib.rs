@@ -0,0 +1,114 @@
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+#[cfg(windows)]
+mod autostart;
+mod background;
+mod blocklist;
+mod border;
+mod caption;
+mod color;
+mod config;
+mod control;
+mod corners;
+mod dedup;
+mod error;
+mod fetch;
+mod filter;
+mod history;
+mod instance;
+mod layout;
+mod library;
+mod logging;
+mod metadata;
+mod montage;
+mod names;
+mod notification;
+mod render;
+mod rotation;
+mod schedule;
+mod selection;
+mod shadow;
+mod stamp;
+mod state;
+mod status;
+mod system_theme;
+#[cfg(windows)]
+mod tray;
+mod wallpaper;
+mod weights;
+
+#[cfg(windows)]
+pub use autostart::{AUTOSTART_KEY, install_autostart, uninstall_autostart};
+pub use background::{Background, DEFAULT_BACKGROUND, Gradient};
+pub use blocklist::{is_blocked, load_blocklist};
+pub use border::BorderConfig;
+pub use caption::{CaptionOutline, TextColor};
+pub use color::parse_hex_color;
+pub use config::{Config, ConfigError, changed_settings, read_config_file};
+pub use control::{Command, run_control_server};
+pub use corners::{Corner, CornerRadii, build_corner_mask, round_corner, round_corner_over};
+pub use dedup::{DUPLICATE_DISTANCE, DuplicateGroup, difference_hash, find_duplicates};
+pub use error::ServiceError;
+pub use fetch::{FetchConfig, FetchError, fetch_new_images, run_fetcher};
+pub use filter::PhotoFilter;
+pub use history::{HistoryEntry, append_history, load_history};
+pub use instance::InstanceGuard;
+pub use layout::{CaptionLayout, HorizontalAlign, Insets, Layout, VerticalAlign};
+pub use library::list_images;
+pub use logging::{attach_parent_console, init_logging};
+pub use metadata::{PhotoMetadata, TAKEN_FORMAT, read_metadata};
+pub use montage::{Montage, MontageConfig};
+pub use names::{caption_for, load_names};
+pub use notification::notify_flower_changed;
+pub use render::{
+    BlendMode, FitMode, OutputFormat, RenderConfig, RenderError, Renderer, ResizeFilter,
+    apply_mask, blend_linear, check_font, modify_image,
+};
+pub use rotation::{
+    Rotation, change_wallpaper, output_path, reapply_wallpaper, update_lock_screen,
+};
+pub use schedule::{
+    ChangeInterval, ScheduleZone, duration_until, jitter_offset, parse_duration, pause_until,
+    sleep_step, sleep_until, wait_until,
+};
+pub use selection::{SelectionStrategy, theme_of_week, weekly_attempt, weekly_position};
+pub use shadow::ShadowConfig;
+pub use stamp::StampConfig;
+pub use state::{
+    State, StateError, first_permutation, load_image_list, load_original_wallpaper,
+    load_permutation, set_aside_state, store_image_list, store_original_wallpaper,
+    store_permutation,
+};
+pub use status::{SharedStatus, Status, StatusConfig, run_status_server};
+pub use system_theme::system_uses_dark_theme;
+#[cfg(windows)]
+pub use tray::{Tray, TrayNotifier};
+#[cfg(target_os = "linux")]
+pub use wallpaper::GnomeWallpaper;
+#[cfg(target_os = "macos")]
+pub use wallpaper::MacWallpaper;
+#[cfg(windows)]
+pub use wallpaper::WindowsWallpaper;
+pub use wallpaper::{
+    Monitor, RecordingSetter, WallpaperSetter, WallpaperStyle, detect_screen_size, set_lock_screen,
+};
+pub use weights::load_weights;
+
+pub const BASE_PATH: &str = include_str!("../base_path.txt");
+
+static BASE_PATH_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+
+// Reads and writes everything in `path` instead of BASE_PATH, e.g. a temporary directory in tests.
+// Only the first call counts, later ones return their path back.
+pub fn set_base_path(path: PathBuf) -> Result<(), PathBuf> {
+    BASE_PATH_OVERRIDE.set(path)
+}
+
+// Absolute paths, e.g. a font configured elsewhere, are kept as they are
+pub fn to_path(p: impl AsRef<Path>) -> PathBuf {
+    match BASE_PATH_OVERRIDE.get() {
+        Some(base_path) => base_path.join(p),
+        None => Path::new(BASE_PATH).join(p),
+    }
+}
@@ -0,0 +1,64 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Lists every decodable image below `root` as a `/`-separated path relative to it. The list is
+// sorted so the indices of the stored permutation keep pointing at the same flowers.
+pub fn list_images(root: &Path) -> io::Result<Vec<String>> {
+    let mut images = Vec::new();
+    let mut pending = vec![String::new()];
+    while let Some(relative_dir) = pending.pop() {
+        for entry in fs::read_dir(root.join(&relative_dir))?.flatten() {
+            let Ok(file_name) = entry.file_name().into_string() else {
+                continue;
+            };
+            let relative_path = if relative_dir.is_empty() {
+                file_name
+            } else {
+                format!("{relative_dir}/{file_name}")
+            };
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => pending.push(relative_path),
+                Ok(_) if is_supported_image(&relative_path) => images.push(relative_path),
+                _ => {}
+            }
+        }
+    }
+    images.sort();
+    Ok(images)
+}
+
+pub(crate) fn is_supported_image(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(image::ImageFormat::from_extension)
+        .is_some_and(|format| match format {
+            // The default avif feature of image only encodes, decoding needs dav1d
+            image::ImageFormat::Avif => cfg!(feature = "avif"),
+            format => format.reading_enabled(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn supported_images_go_by_extension() {
+        for path in [
+            "Rosa_canina.jpg",
+            "Rosa_canina.JPEG",
+            "Tulip.webp",
+            "Lily.2.png",
+            "Iris.bmp",
+            "Bellis.gif",
+            "spring/Crocus.tiff",
+        ] {
+            assert!(is_supported_image(path), "{path}");
+        }
+        for path in ["names.json", "Rosa_canina", "Rosa_canina.jpg.txt"] {
+            assert!(!is_supported_image(path), "{path}");
+        }
+        assert_eq!(is_supported_image("Tulip.avif"), cfg!(feature = "avif"));
+    }
+}
@@ -0,0 +1,115 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write as _};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use chrono::Local;
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::to_path;
+
+// The current log is moved to `service.log.1` once it grows past this
+const MAX_LOG_SIZE: u64 = 1024 * 1024;
+
+// As a windows subsystem binary there is no console, so everything goes to `service.log`.
+// Without a path the lines only go to stderr.
+struct FileLogger {
+    path: Option<PathBuf>,
+    file: Mutex<Option<File>>,
+}
+
+impl FileLogger {
+    fn open(&self) -> io::Result<Option<File>> {
+        let Some(path) = &self.path else {
+            return Ok(None);
+        };
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map(Some)
+    }
+
+    fn rotate_if_full(&self, file: &mut Option<File>) -> io::Result<()> {
+        let (Some(path), Some(open_file)) = (&self.path, file.as_ref()) else {
+            return Ok(());
+        };
+        let size = open_file.metadata()?.len();
+        if size < MAX_LOG_SIZE {
+            return Ok(());
+        }
+        // Windows cannot rename a file that is still open
+        *file = None;
+        let mut rotated = path.as_os_str().to_owned();
+        rotated.push(".1");
+        fs::rename(path, rotated)?;
+        *file = self.open()?;
+        Ok(())
+    }
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!(
+            "{} {:<5} {}\n",
+            Local::now().format("%Y-%m-%d %H:%M:%S"),
+            record.level(),
+            record.args()
+        );
+        eprint!("{line}");
+
+        let mut file = self
+            .file
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if file.is_none() {
+            *file = self.open().ok().flatten();
+        }
+        // Logging must never take the service down, so write failures are dropped
+        let _ = self.rotate_if_full(&mut file);
+        if let Some(file) = file.as_mut() {
+            let _ = file.write_all(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = self
+            .file
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .as_mut()
+        {
+            let _ = file.flush();
+        }
+    }
+}
+
+// Lets the report of a command run from a terminal show up there, a windows subsystem binary has
+// no console of its own. Started any other way there is no parent console and nothing changes.
+#[cfg(windows)]
+pub fn attach_parent_console() {
+    use winapi::um::wincon::{ATTACH_PARENT_PROCESS, AttachConsole};
+
+    unsafe { AttachConsole(ATTACH_PARENT_PROCESS) };
+}
+
+#[cfg(not(windows))]
+pub fn attach_parent_console() {}
+
+// `to_file` false logs to stderr only, for runs that must not write anything
+pub fn init_logging(to_file: bool) -> Result<(), log::SetLoggerError> {
+    let logger = FileLogger {
+        path: to_file.then(|| to_path("service.log")),
+        file: Mutex::new(None),
+    };
+    log::set_boxed_logger(Box::new(logger))?;
+    log::set_max_level(LevelFilter::Info);
+    Ok(())
+}
@@ -1,304 +1,999 @@
 #![windows_subsystem = "windows"]
 
-use std::error::Error;
-use std::ffi::OsStr;
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::{self, ExitCode};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Instant;
+use std::{env, fs};
+
+use chrono::{DateTime, Local};
+use flower_service::{
+    BASE_PATH, ChangeInterval, Command, Config, DUPLICATE_DISTANCE, InstanceGuard, RecordingSetter,
+    RenderConfig, Rotation, SelectionStrategy, ServiceError, SharedStatus, State, Status,
+    WallpaperSetter, WallpaperStyle, attach_parent_console, caption_for, change_wallpaper,
+    changed_settings, check_font, detect_screen_size, find_duplicates, first_permutation,
+    init_logging, is_blocked, jitter_offset, list_images, load_blocklist, load_history,
+    load_image_list, load_names, load_original_wallpaper, load_permutation, modify_image,
+    notify_flower_changed, output_path, pause_until, read_config_file, reapply_wallpaper,
+    run_control_server, run_fetcher, run_status_server, set_aside_state, store_image_list,
+    store_original_wallpaper, store_permutation, to_path, update_lock_screen, wait_until,
+};
+use image::ImageReader;
+use rand::SeedableRng as _;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom as _;
+use rayon::prelude::*;
+
+#[cfg(target_os = "linux")]
+use flower_service::GnomeWallpaper;
+#[cfg(target_os = "macos")]
+use flower_service::MacWallpaper;
+#[cfg(windows)]
+use flower_service::{Tray, WindowsWallpaper};
+
+const USAGE: &str = "Usage: flower-service [--dry-run] [--interval <interval>] | --render-once <input> <output> | --preview-all <output directory> | --shuffle [--seed <number>] | --history [count] | --dedup [--remove] | --pin <file name> | --unpin | --install | --uninstall | --validate";
+const DEFAULT_HISTORY_COUNT: usize = 10;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    // The service itself stays detached, closing the terminal would end it
+    let is_service = args.is_empty() || args[0] == "--interval";
+    if !is_service || args.iter().any(|arg| arg == "--dry-run") {
+        attach_parent_console();
+    }
+    if let Err(err) = init_logging(!args.iter().any(|arg| arg == "--dry-run")) {
+        eprintln!("Cannot set up logging: {err}");
+    }
 
-use std::os::windows::ffi::OsStrExt;
-use std::path::{Path, PathBuf};
-use std::time::Duration;
-use std::{fs, path, thread};
+    let result = match args.as_slice() {
+        [flag, input, output] if flag == "--render-once" => {
+            render_once(Path::new(input), Path::new(output))
+        }
+        [flag, output_dir] if flag == "--preview-all" => preview_all(Path::new(output_dir)),
+        [flag] if flag == "--shuffle" => shuffle(None),
+        [flag] if flag == "--history" => print_history(DEFAULT_HISTORY_COUNT),
+        [flag, file_name] if flag == "--pin" => pin(Some(file_name)),
+        [flag] if flag == "--unpin" => pin(None),
+        [flag] if flag == "--install" => install(true),
+        [flag] if flag == "--uninstall" => install(false),
+        [flag] if flag == "--dedup" => dedup(false),
+        [flag] if flag == "--validate" => validate(),
+        [flag, remove] if flag == "--dedup" && remove == "--remove" => dedup(true),
+        [flag, count] if flag == "--history" => match count.parse() {
+            Ok(count) => print_history(count),
+            Err(_) => Err(format!("Invalid count {count:?}, expected a number").into()),
+        },
+        [flag, seed_flag, seed] if flag == "--shuffle" && seed_flag == "--seed" => {
+            match seed.parse() {
+                Ok(seed) => shuffle(Some(seed)),
+                Err(_) => Err(format!("Invalid seed {seed:?}, expected a number").into()),
+            }
+        }
+        _ => parse_service_options(&args).and_then(run_service),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            log::error!("{}", err.report());
+            ExitCode::FAILURE
+        }
+    }
+}
 
-use chrono::TimeZone;
-use chrono::{DateTime, Local, NaiveTime};
-use image::{GenericImageView as _, RgbaImage};
-use winapi::um::winuser::SPI_SETDESKWALLPAPER;
-use winapi::um::winuser::SPIF_UPDATEINIFILE;
-use winapi::um::winuser::SystemParametersInfoW;
+// The images in wiki_flowers, an error if there are none
+fn list_wiki_flowers() -> Result<Vec<String>, ServiceError> {
+    let dir = to_path("wiki_flowers");
+    match list_images(&dir) {
+        Ok(image_file_names) if image_file_names.is_empty() => {
+            Err(ServiceError::ImageDirectory(dir, None))
+        }
+        Ok(image_file_names) => Ok(image_file_names),
+        Err(err) => Err(ServiceError::ImageDirectory(dir, Some(err))),
+    }
+}
 
-const BASE_PATH: &str = include_str!("../base_path.txt");
+#[derive(Clone, Default)]
+struct ServiceOptions {
+    // Log every decision but render, set and store nothing
+    dry_run: bool,
+    // Replaces the interval from config.toml
+    interval: Option<ChangeInterval>,
+}
 
-fn to_path(p: &str) -> PathBuf {
-    Path::new(BASE_PATH).join(p)
+fn parse_service_options(args: &[String]) -> Result<ServiceOptions, ServiceError> {
+    let mut options = ServiceOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => options.dry_run = true,
+            "--interval" => {
+                let interval = args.next().ok_or(USAGE)?;
+                // "5s" is short for "every 5s" here
+                let parsed = interval
+                    .parse()
+                    .or_else(|err| format!("every {interval}").parse().map_err(|_| err))?;
+                options.interval = Some(parsed);
+            }
+            _ => return Err(USAGE.into()),
+        }
+    }
+    Ok(options)
 }
 
-fn store_last_wallpaper_change_and_idx(idx: usize) -> Option<()> {
-    fs::write(
-        to_path("last_wallpaper_and_idx.txt"),
-        format!("{}\n{idx}", Local::now().timestamp()),
-    )
-    .ok()
+// These are read once when the service starts
+const RESTART_SETTINGS: [&str; 6] = [
+    "fetch",
+    "status",
+    "control_pipe",
+    "wallpaper_style",
+    "restore_on_exit",
+    "catch_up",
+];
+
+// The settings from config.toml with the command line applied, read again before every change
+struct ServiceConfig {
+    options: ServiceOptions,
+    contents: String,
+    // An invalid file is only reported once
+    rejected: Option<String>,
+    config: Config,
+    render_config: RenderConfig,
 }
 
-fn get_last_wallpaper_change_and_idx() -> Option<(DateTime<Local>, usize)> {
-    let file_string = fs::read_to_string(to_path("last_wallpaper_and_idx.txt")).unwrap();
-    let (time_stamp, index) = file_string.trim().split_once("\n").unwrap();
-    Some((
-        Local
-            .timestamp_opt(time_stamp.parse::<i64>().unwrap(), 0)
-            .earliest()
-            .unwrap(),
-        index.parse::<usize>().unwrap(),
-    ))
+impl ServiceConfig {
+    fn load(options: &ServiceOptions) -> Result<Self, ServiceError> {
+        let contents = read_config_file()?;
+        let mut config = Config::parse(&contents)?;
+        if let Some(interval) = &options.interval {
+            config.interval = interval.clone();
+        }
+        if options.dry_run {
+            config.fetch.enabled = false;
+            config.restore_on_exit = false;
+            // The pipe belongs to the service the dry run may run next to
+            config.control_pipe = false;
+        }
+        let render_config = screen_render_config(&config)?;
+        Ok(Self {
+            options: options.clone(),
+            contents,
+            rejected: None,
+            config,
+            render_config,
+        })
+    }
+
+    // The jitter moves the whole schedule, so a change just before midnight is not followed by another
+    fn next_change(&self, last_change: DateTime<Local>) -> DateTime<Local> {
+        let offset = jitter_offset(self.config.jitter);
+        self.config
+            .interval
+            .next_change_after(last_change - offset, self.config.timezone)
+            + offset
+    }
+
+    // Takes over an edited config file; an invalid one is rejected and the last good settings stay
+    fn reload(&mut self) {
+        let contents = match read_config_file() {
+            Ok(contents)
+                if contents == self.contents || self.rejected.as_ref() == Some(&contents) =>
+            {
+                return;
+            }
+            Ok(contents) => contents,
+            Err(err) => {
+                log::error!("{err}, keeping the previous settings");
+                return;
+            }
+        };
+        let reloaded = match Self::load(&self.options) {
+            Ok(reloaded) => reloaded,
+            Err(err) => {
+                log::error!("{}, keeping the previous settings", err.report().trim_end());
+                self.rejected = Some(contents);
+                return;
+            }
+        };
+        let changed = changed_settings(&self.contents, &reloaded.contents);
+        if changed.is_empty() {
+            log::info!("Reloaded config.toml");
+        } else {
+            log::info!("Reloaded config.toml, changed: {}", changed.join(", "));
+        }
+        let mut needs_restart: Vec<&str> = changed
+            .iter()
+            .map(|name| name.split('.').next().unwrap_or(name))
+            .filter(|name| RESTART_SETTINGS.contains(name))
+            .collect();
+        needs_restart.dedup();
+        if !needs_restart.is_empty() {
+            log::warn!(
+                "Changes to {} take effect after a restart",
+                needs_restart.join(", ")
+            );
+        }
+        *self = reloaded;
+    }
 }
 
-fn set_wallpaper<P: AsRef<Path>>(image_path: P) {
-    let image_path: Vec<u16> = OsStr::new(image_path.as_ref())
-        .encode_wide()
-        .chain(std::iter::once(0))
-        .collect();
+fn load_captions() -> HashMap<String, String> {
+    load_names(&to_path("names.json"))
+}
+
+fn screen_render_config(config: &Config) -> Result<RenderConfig, ServiceError> {
+    let (screen_width, screen_height) = detect_screen_size();
+    let render_config = RenderConfig {
+        background: config.background,
+        // Relative font paths are looked up next to the flowers
+        font_path: config.font.as_deref().map(to_path),
+        fallback_font_path: config.fallback_font.as_deref().map(to_path),
+        shadow: config.shadow,
+        text_color: config.text_color,
+        text_outline: config.text_outline,
+        caption_layout: config.caption_layout,
+        bottom_extra_margin: config
+            .caption_band
+            .unwrap_or(RenderConfig::default().bottom_extra_margin),
+        caption_template: config
+            .caption_template
+            .clone()
+            .unwrap_or(RenderConfig::default().caption_template),
+        fit_mode: config.fit_mode,
+        resize_filter: config.resize_filter,
+        min_photo_size: config.min_photo_size,
+        max_photo_megapixels: config
+            .max_photo_megapixels
+            .unwrap_or(RenderConfig::default().max_photo_megapixels),
+        animation_frame: config.animation_frame,
+        photo_filter: config.photo_filter,
+        brightness: config.brightness,
+        contrast: config.contrast,
+        corner_blending: config.corner_blending,
+        caption_gradient: config.caption_gradient,
+        info_stamp: config.info_stamp.clone(),
+        letter_spacing: config.letter_spacing,
+        line_spacing: config
+            .line_spacing
+            .unwrap_or(RenderConfig::default().line_spacing),
+        scale: config.scale.unwrap_or(1.),
+        corner_aa: config
+            .corner_aa
+            .unwrap_or(RenderConfig::default().corner_aa),
+        border: config.border,
+        safe_area: config.safe_area,
+        corner_radius: config
+            .corner_radius
+            .unwrap_or(RenderConfig::default().corner_radius),
+        output_format: config.output_format,
+        jpeg_quality: config
+            .jpeg_quality
+            .unwrap_or(RenderConfig::default().jpeg_quality),
+        ..RenderConfig::default()
+    }
+    .scaled_to(screen_width, screen_height);
+    render_config.validate()?;
+    Ok(render_config)
+}
+
+// Renders a single image with the service layout, without touching the wallpaper or the state
+fn render_once(input: &Path, output: &Path) -> Result<(), ServiceError> {
+    let file_name = input
+        .file_name()
+        .and_then(|file_name| file_name.to_str())
+        .ok_or_else(|| format!("Cannot derive a caption from {}", input.display()))?;
+    let render_config = screen_render_config(&Config::load()?)?;
+    let started = Instant::now();
+    modify_image(
+        &caption_for(file_name, &load_captions()),
+        input,
+        output,
+        &render_config,
+    )?;
+    log::info!(
+        "Rendered {} in {} ms",
+        output.display(),
+        started.elapsed().as_millis()
+    );
+    Ok(())
+}
 
-    unsafe {
-        SystemParametersInfoW(
-            SPI_SETDESKWALLPAPER,
-            0,
-            image_path.as_ptr() as *mut _,
-            SPIF_UPDATEINIFILE,
+fn print_history(count: usize) -> Result<(), ServiceError> {
+    for entry in load_history(count)? {
+        println!(
+            "{}  {} (#{})",
+            entry.timestamp.format("%a %Y-%m-%d %H:%M"),
+            entry.file_name,
+            entry.index
         );
     }
+    Ok(())
 }
 
-fn apply_mask(input: image::Rgba<u8>, mask: image::Rgba<u8>) -> image::Rgba<u8> {
-    let [r_mask, g_mask, b_mask, a_mask] = mask.0;
-    let [r_canvas, g_canvas, b_canvas, _a_canvas] = input.0;
+// Keeps showing `file_name` from the next change on, or continues the rotation for `None`
+fn pin(file_name: Option<&str>) -> Result<(), ServiceError> {
+    let mut state = State::load().unwrap_or_default();
+    match file_name {
+        Some(file_name) => {
+            let image_file_names = list_wiki_flowers()?;
+            if !image_file_names.iter().any(|name| name == file_name) {
+                return Err(format!("{file_name} is not in wiki_flowers").into());
+            }
+            // The stored index refers to the list the service works with
+            let known = load_image_list().unwrap_or(image_file_names);
+            let image_idx = known
+                .iter()
+                .position(|name| name == file_name)
+                .ok_or_else(|| {
+                    format!("{file_name} is new, start the service once so it knows the image")
+                })?;
+            state.pinned = Some(image_idx);
+            println!("Pinned {file_name}, it stays from the next change on");
+        }
+        None => {
+            state.pinned = None;
+            println!("Unpinned, the rotation continues with the next change");
+        }
+    }
+    state.store()?;
+    Ok(())
+}
 
-    // Perform alpha blending (compositing) between the mask and the canvas
-    let alpha = a_mask as f32 / 255.0;
-    let new_r = ((1.0 - alpha) * r_canvas as f32 + alpha * r_mask as f32) as u8;
-    let new_g = ((1.0 - alpha) * g_canvas as f32 + alpha * g_mask as f32) as u8;
-    let new_b = ((1.0 - alpha) * b_canvas as f32 + alpha * b_mask as f32) as u8;
-    let new_a = 255; // Keep alpha at 255 for the resulting image
+// Registers the service to start at login, or removes the registration
+#[cfg(windows)]
+fn install(register: bool) -> Result<(), ServiceError> {
+    use flower_service::{AUTOSTART_KEY, install_autostart, uninstall_autostart};
+
+    if register {
+        let command = install_autostart()?;
+        log::info!("Registered {command} to start at login under {AUTOSTART_KEY}\\flower-service");
+    } else if uninstall_autostart()? {
+        log::info!(
+            "Removed {AUTOSTART_KEY}\\flower-service, the service no longer starts at login"
+        );
+    } else {
+        log::info!("flower-service was not registered to start at login");
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn install(_register: bool) -> Result<(), ServiceError> {
+    Err("--install and --uninstall are only available on Windows, use the autostart of the desktop instead".into())
+}
 
-    image::Rgba([new_r, new_g, new_b, new_a])
+// Reports groups of near-identical photos and, with `remove`, deletes all but the largest of
+// every group. The service matches the remaining flowers by name on its next start.
+fn dedup(remove: bool) -> Result<(), ServiceError> {
+    let dir = to_path("wiki_flowers");
+    let image_file_names = list_wiki_flowers()?;
+    let groups = find_duplicates(&dir, &image_file_names, DUPLICATE_DISTANCE);
+    if groups.is_empty() {
+        println!("No duplicates among {} images", image_file_names.len());
+        return Ok(());
+    }
+    let verb = if remove { "Removing" } else { "Would remove" };
+    for group in &groups {
+        println!("Keeping {}", group.keep);
+        for file_name in &group.remove {
+            println!("  {verb} {file_name}");
+        }
+    }
+    let count: usize = groups.iter().map(|group| group.remove.len()).sum();
+    if !remove {
+        println!("{count} duplicates, run with --remove to delete them");
+        return Ok(());
+    }
+    for file_name in groups.iter().flat_map(|group| &group.remove) {
+        fs::remove_file(dir.join(file_name))?;
+    }
+    log::info!("Removed {count} duplicate images");
+    Ok(())
 }
 
-fn modify_image<P: AsRef<path::Path>, Q: AsRef<path::Path>>(
-    name: &str,
-    input_image_path: P,
-    output_image_path: Q,
-) {
-    const MARGIN: u32 = 50;
-    const BOTTOM_EXTRA_MARGIN: u32 = 150;
-    const CANVAS_WIDTH: u32 = 2560;
-    const CANVAS_HEIGHT: u32 = 1530;
-    const IMAGE_BOX_WIDTH: u32 = CANVAS_WIDTH - 2 * MARGIN;
-    const IMAGE_BOX_HEIGHT: u32 = CANVAS_HEIGHT - 2 * MARGIN - BOTTOM_EXTRA_MARGIN;
-    const TEXT_SIZE: u32 = 60;
-    const OFF_WHITE_RGB: (u8, u8, u8) = (233, 223, 199);
-
-    const CORNER_RADIUS: u32 = 50;
-
-    let off_white = image::Rgba([OFF_WHITE_RGB.0, OFF_WHITE_RGB.1, OFF_WHITE_RGB.2, 255]);
-
-    // Load the image
-    let img = image::open(input_image_path).expect("Failed to open image");
-
-    // Calculate the scaled image dimensions while maintaining aspect ratio
-    let (orig_width, orig_height) = img.dimensions();
-    let scale_factor = f32::min(
-        IMAGE_BOX_WIDTH as f32 / orig_width as f32,
-        IMAGE_BOX_HEIGHT as f32 / orig_height as f32,
+// Checks what the service needs without changing anything and prints a line per check
+fn validate() -> Result<(), ServiceError> {
+    let (mut checks, mut failures) = (0, 0);
+    let mut check = |name: &str, result: Result<String, ServiceError>| {
+        checks += 1;
+        match result {
+            Ok(detail) => println!("PASS  {name}: {detail}"),
+            Err(err) => {
+                failures += 1;
+                println!("FAIL  {name}: {}", err.report());
+            }
+        }
+    };
+
+    check(
+        "base path",
+        if Path::new(BASE_PATH).is_dir() {
+            Ok(BASE_PATH.to_owned())
+        } else {
+            Err(format!("{BASE_PATH} is not a directory, see base_path.txt").into())
+        },
     );
 
-    let scaled_width = (orig_width as f32 * scale_factor) as u32;
-    let scaled_height = (orig_height as f32 * scale_factor) as u32;
+    let render_config = Config::load()
+        .map_err(ServiceError::from)
+        .and_then(|config| screen_render_config(&config));
+    check(
+        "config.toml",
+        render_config
+            .as_ref()
+            .map(|render_config| {
+                format!(
+                    "valid, rendering at {}x{}",
+                    render_config.canvas_width, render_config.canvas_height
+                )
+            })
+            .map_err(|err| err.report().into()),
+    );
 
-    // Resize the image
-    let resized_img = image::imageops::resize(
-        &img.to_rgba8(),
-        scaled_width,
-        scaled_height,
-        image::imageops::FilterType::Lanczos3,
+    let image_file_names = list_wiki_flowers();
+    check(
+        "images",
+        image_file_names
+            .as_ref()
+            .map_err(|err| err.report().into())
+            .and_then(|image_file_names| check_images(image_file_names)),
     );
 
-    let mut corner_mask = RgbaImage::new(CORNER_RADIUS, CORNER_RADIUS);
-    for y in 0..CORNER_RADIUS {
-        for x in 0..CORNER_RADIUS {
-            let alpha = (((x as f32) * (x as f32) + (y as f32) * (y as f32)).sqrt()
-                - CORNER_RADIUS as f32
-                + 0.5)
-                .clamp(0., 1.)
-                * 255.;
-            corner_mask.put_pixel(
-                x,
-                y,
-                image::Rgba([
-                    OFF_WHITE_RGB.0,
-                    OFF_WHITE_RGB.1,
-                    OFF_WHITE_RGB.2,
-                    alpha as u8,
-                ]),
+    check(
+        "permutation",
+        image_file_names
+            .as_ref()
+            .map_err(|_| "Needs the images".into())
+            .and_then(|image_file_names| check_permutation(image_file_names)),
+    );
+
+    if let Ok(render_config) = &render_config {
+        let fonts = [
+            ("font", &render_config.font_path),
+            ("fallback font", &render_config.fallback_font_path),
+        ];
+        for (name, font_path) in fonts {
+            check(
+                name,
+                match font_path {
+                    Some(font_path) => check_font(font_path)
+                        .map(|()| font_path.display().to_string())
+                        .map_err(|err| format!("{}: {err}", font_path.display()).into()),
+                    None if name == "font" => Ok("the embedded Playfair Display".to_owned()),
+                    None => Ok("a font of the system".to_owned()),
+                },
             );
         }
+        check(
+            "output directory",
+            check_writable(&output_path(None, render_config)),
+        );
     }
 
-    // Create an off-white canvas
-    let mut canvas = RgbaImage::new(CANVAS_WIDTH, CANVAS_HEIGHT);
+    if failures > 0 {
+        return Err(format!("{failures} of {checks} checks failed").into());
+    }
+    println!("All checks passed");
+    Ok(())
+}
 
-    for y in 0..CANVAS_HEIGHT {
-        for x in 0..CANVAS_WIDTH {
-            canvas.put_pixel(x, y, off_white);
-        }
+// Only the headers are read, which is enough to tell broken files from photos
+fn check_images(image_file_names: &[String]) -> Result<String, ServiceError> {
+    let dir = to_path("wiki_flowers");
+    let unreadable: Vec<&str> = image_file_names
+        .iter()
+        .filter(|file_name| {
+            ImageReader::open(dir.join(file_name.as_str()))
+                .and_then(|reader| reader.with_guessed_format())
+                .map_err(image::ImageError::from)
+                .and_then(|reader| reader.into_dimensions())
+                .is_err()
+        })
+        .map(String::as_str)
+        .collect();
+    if unreadable.is_empty() {
+        return Ok(format!("{} readable images", image_file_names.len()));
     }
+    Err(format!(
+        "{} of {} images cannot be read: {}",
+        unreadable.len(),
+        image_file_names.len(),
+        unreadable.join(", ")
+    )
+    .into())
+}
 
-    // Calculate the position to center the image on the canvas
-    let image_x_offset = (CANVAS_WIDTH - scaled_width) / 2;
-    let image_y_offset = (CANVAS_HEIGHT - scaled_height - BOTTOM_EXTRA_MARGIN) / 2;
+// The permutation refers to the stored image list; images added since are appended on start
+fn check_permutation(image_file_names: &[String]) -> Result<String, ServiceError> {
+    let known = load_image_list().unwrap_or_else(|| image_file_names.to_vec());
+    let mut permutation = load_permutation()?;
+    permutation.sort_unstable();
+    if !permutation.into_iter().eq(0..known.len()) {
+        return Err(format!(
+            "The stored permutation does not cover the {} images exactly once, run flower-service --shuffle",
+            known.len()
+        )
+        .into());
+    }
+    if known != image_file_names {
+        return Ok(format!(
+            "covers the {} known images, the service takes in the changed ones on its next start",
+            known.len()
+        ));
+    }
+    Ok(format!("covers all {} images", known.len()))
+}
 
-    // Place the resized image on the canvas
-    for y in 0..scaled_height {
-        for x in 0..scaled_width {
-            let pixel = resized_img.get_pixel(x, y);
-            canvas.put_pixel(image_x_offset + x, image_y_offset + y, *pixel);
-        }
+// Creates and removes a file next to the wallpaper, which is the only way to be sure
+fn check_writable(output_file_path: &Path) -> Result<String, ServiceError> {
+    let dir = output_file_path.parent().unwrap_or(Path::new("."));
+    let probe = dir.join(".flower-service-validate");
+    fs::write(&probe, b"")?;
+    fs::remove_file(&probe)?;
+    Ok(dir.display().to_string())
+}
+
+// Writes a new permutation of all images and starts over at its first flower. The same seed
+// always gives the same permutation for the same images.
+fn shuffle(seed: Option<u64>) -> Result<(), ServiceError> {
+    let image_file_names = list_wiki_flowers()?;
+    let image_count = image_file_names.len();
+    let mut permutation: Vec<usize> = (0..image_count).collect();
+    match seed {
+        Some(seed) => permutation.shuffle(&mut StdRng::seed_from_u64(seed)),
+        None => permutation.shuffle(&mut rand::thread_rng()),
+    }
+    store_permutation(&permutation)?;
+    store_image_list(&image_file_names)?;
+
+    // What the service reads back has to cover every image exactly once
+    let mut stored = load_permutation()?;
+    stored.sort_unstable();
+    if !stored.into_iter().eq(0..image_count) {
+        return Err("The stored permutation does not cover every image exactly once".into());
     }
 
-    // Apply Corner radius
-    for y in 0..CORNER_RADIUS {
-        for x in 0..CORNER_RADIUS {
-            let pixel = apply_mask(
-                *resized_img.get_pixel(x, y),
-                *corner_mask.get_pixel(CORNER_RADIUS - (x + 1), CORNER_RADIUS - (y + 1)),
+    let mut state = State::load().unwrap_or_default();
+    state.index = 0;
+    state.seen.clear();
+    state.store()?;
+    log::info!("Shuffled {image_count} images into a new permutation");
+    Ok(())
+}
+
+// Renders every flower the service would show into `output_dir`, in parallel
+fn preview_all(output_dir: &Path) -> Result<(), ServiceError> {
+    fs::create_dir_all(output_dir)?;
+    let blocklist = load_blocklist(&to_path("blocklist.txt"));
+    let image_file_names: Vec<String> = list_wiki_flowers()?
+        .into_iter()
+        .filter(|file_name| !is_blocked(file_name, &blocklist))
+        .collect();
+    let render_config = screen_render_config(&Config::load()?)?;
+    let names = load_captions();
+
+    let done = AtomicUsize::new(0);
+    let failed = image_file_names
+        .par_iter()
+        .enumerate()
+        .filter(|(image_idx, file_name)| {
+            // Flowers from subdirectories get the directory in their name so nothing collides
+            let output_name = Path::new(file_name.as_str())
+                .with_extension(render_config.output_format.extension())
+                .to_string_lossy()
+                .replace('/', "_");
+            let result = modify_image(
+                &caption_for(file_name, &names),
+                to_path("wiki_flowers").join(file_name.as_str()),
+                output_dir.join(output_name),
+                &RenderConfig {
+                    stamp_position: Some((image_idx + 1, image_file_names.len())),
+                    ..render_config.clone()
+                },
             );
-            canvas.put_pixel(image_x_offset + x, image_y_offset + y, pixel);
-        }
+            let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+            match result {
+                Ok(()) => {
+                    log::info!("[{done}/{}] Rendered {file_name}", image_file_names.len());
+                    false
+                }
+                Err(err) => {
+                    log::warn!(
+                        "[{done}/{}] Skipping {file_name}: {err}",
+                        image_file_names.len()
+                    );
+                    true
+                }
+            }
+        })
+        .count();
+
+    if failed > 0 {
+        return Err(format!(
+            "{failed} of {} images could not be rendered",
+            image_file_names.len()
+        )
+        .into());
     }
-    for y in 0..CORNER_RADIUS {
-        for x in scaled_width - CORNER_RADIUS..scaled_width {
-            let pixel = apply_mask(
-                *resized_img.get_pixel(x, y),
-                *corner_mask.get_pixel(x - (scaled_width - CORNER_RADIUS), CORNER_RADIUS - (y + 1)),
-            );
-            canvas.put_pixel(image_x_offset + x, image_y_offset + y, pixel);
-        }
+    Ok(())
+}
+
+// Changes the wallpaper on schedule and on commands, e.g. from the tray icon, until told to quit.
+// `on_change` receives the captions of the flowers now shown.
+fn run_scheduler(
+    rotation: Rotation,
+    settings: ServiceConfig,
+    commands: &Receiver<Command>,
+    status: &SharedStatus,
+    on_change: impl Fn(&[String]),
+) -> Result<(), ServiceError> {
+    if rotation.dry_run {
+        let wallpaper_setter = RecordingSetter::new();
+        return schedule(
+            rotation,
+            &wallpaper_setter,
+            settings,
+            commands,
+            status,
+            on_change,
+        );
     }
-    for y in scaled_height - CORNER_RADIUS..scaled_height {
-        for x in scaled_width - CORNER_RADIUS..scaled_width {
-            let pixel = apply_mask(
-                *resized_img.get_pixel(x, y),
-                *corner_mask.get_pixel(
-                    x - (scaled_width - CORNER_RADIUS),
-                    y - (scaled_height - CORNER_RADIUS),
-                ),
-            );
-            canvas.put_pixel(image_x_offset + x, image_y_offset + y, pixel);
+    let wallpaper_setter = platform_wallpaper_setter(settings.config.wallpaper_style);
+    schedule(
+        rotation,
+        &wallpaper_setter,
+        settings,
+        commands,
+        status,
+        on_change,
+    )
+}
+
+fn schedule(
+    mut rotation: Rotation,
+    wallpaper_setter: &impl WallpaperSetter,
+    mut settings: ServiceConfig,
+    commands: &Receiver<Command>,
+    status: &SharedStatus,
+    on_change: impl Fn(&[String]),
+) -> Result<(), ServiceError> {
+    // A restart within the period shows the same flowers, only overdue changes render new ones
+    if settings.next_change(rotation.state.last_change) > Local::now() {
+        reapply_wallpaper(&rotation, wallpaper_setter, &settings.render_config);
+    }
+    if let Some(caption) = rotation.current_caption() {
+        on_change(&[caption]);
+    }
+
+    let mut paused = false;
+    loop {
+        // A new interval or selection counts from this change on
+        settings.reload();
+        rotation.apply_settings(&settings.config);
+        *status
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Status::new(
+            rotation.shown_file_name(0).map(str::to_owned),
+            rotation.state.index,
+            rotation.state.last_change,
+            settings.next_change(rotation.state.last_change),
+        );
+        let command = if paused {
+            match commands.recv() {
+                Ok(command) => Some(command),
+                Err(_) => return Ok(()),
+            }
+        } else if let Some(end) = pause_until(!rotation.dry_run) {
+            // Commands still work while frozen, the timed changes wait for the end of the pause
+            log::info!("Wallpaper frozen until {end}");
+            match wait_until(end, commands) {
+                None => continue,
+                command => command,
+            }
+        } else {
+            if rotation.dry_run {
+                log::info!(
+                    "Next change at {}",
+                    settings
+                        .next_change(rotation.state.last_change)
+                        .format("%Y-%m-%d %H:%M:%S")
+                );
+            }
+            // An overdue change, e.g. after the machine slept through midnight, happens right away
+            wait_until(settings.next_change(rotation.state.last_change), commands)
+        };
+        // A pause may have started while waiting for the change
+        if command.is_none() && pause_until(!rotation.dry_run).is_some() {
+            continue;
+        }
+        // The look may have changed while waiting
+        settings.reload();
+        rotation.apply_settings(&settings.config);
+
+        let strategy = match command {
+            None | Some(Command::Next) => rotation.strategy,
+            Some(Command::Previous) => {
+                // Back over the flowers shown now to the ones shown before them
+                let shown =
+                    wallpaper_setter.monitors().len().max(1) * rotation.cells_per_wallpaper();
+                rotation.rewind(2 * shown);
+                // Going back must not skip flowers that were already seen
+                SelectionStrategy::Sequential
+            }
+            Some(Command::Pause(pause)) => {
+                log::info!("Rotation {}", if pause { "paused" } else { "resumed" });
+                paused = pause;
+                continue;
+            }
+            Some(Command::Pin(file_name)) => {
+                rotation.pin(file_name.as_deref());
+                continue;
+            }
+            Some(Command::Quit) => return Ok(()),
+            Some(Command::LibraryChanged) => {
+                match list_images(&to_path("wiki_flowers")) {
+                    Ok(image_file_names) if !image_file_names.is_empty() => {
+                        rotation.update_library(image_file_names);
+                        rotation.store_library();
+                    }
+                    Ok(_) => log::warn!("The image directory is empty now, keeping the old list"),
+                    Err(err) => log::error!("Cannot list the image directory: {err}"),
+                }
+                continue;
+            }
+        };
+        let archive_dir = settings.config.archive_dir.as_deref().map(to_path);
+        let captions = change_wallpaper(
+            &mut rotation,
+            wallpaper_setter,
+            &settings.render_config,
+            strategy,
+            archive_dir.as_deref(),
+        )?;
+        if settings.config.lock_screen {
+            update_lock_screen(&rotation, wallpaper_setter, &settings.render_config);
+        }
+        if settings.config.notify && !rotation.dry_run {
+            notify_flower_changed(&captions);
         }
+        on_change(&captions);
     }
-    for y in scaled_height - CORNER_RADIUS..scaled_height {
-        for x in 0..CORNER_RADIUS {
-            let pixel = apply_mask(
-                *resized_img.get_pixel(x, y),
-                *corner_mask
-                    .get_pixel(CORNER_RADIUS - (x + 1), y - (scaled_height - CORNER_RADIUS)),
-            );
-            canvas.put_pixel(image_x_offset + x, image_y_offset + y, pixel);
+}
+
+fn platform_wallpaper_setter(style: WallpaperStyle) -> impl WallpaperSetter {
+    #[cfg(windows)]
+    let wallpaper_setter = WindowsWallpaper::new(style);
+    #[cfg(target_os = "linux")]
+    let wallpaper_setter = GnomeWallpaper::new(style);
+    #[cfg(target_os = "macos")]
+    let wallpaper_setter = {
+        // The placement is up to the desktop settings on macOS
+        let _ = style;
+        MacWallpaper
+    };
+    wallpaper_setter
+}
+
+// Only the first run sees the wallpaper the user had before, later ones see our own
+fn capture_original_wallpaper(wallpaper_setter: &impl WallpaperSetter) {
+    if load_original_wallpaper().is_some() {
+        return;
+    }
+    match wallpaper_setter.current() {
+        Ok(Some(path))
+            if !path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with("flower_of_today")) =>
+        {
+            match store_original_wallpaper(&path) {
+                Ok(()) => log::info!("Remembered the original wallpaper {}", path.display()),
+                Err(err) => log::error!("Failed to store the original wallpaper: {err}"),
+            }
         }
+        Ok(_) => {}
+        Err(err) => log::warn!("Cannot read the current wallpaper: {err}"),
     }
+}
 
-    // Load the font
-    let font_data = include_bytes!(r"../PlayfairDisplay-Regular.ttf"); // Adjust to the correct path of a TTF file
-    let font = rusttype::Font::try_from_bytes(font_data).expect("Error loading font");
+fn restore_original_wallpaper(style: WallpaperStyle) {
+    let Some(path) = load_original_wallpaper() else {
+        return;
+    };
+    match platform_wallpaper_setter(style).set(&path) {
+        Ok(()) => log::info!("Restored the original wallpaper {}", path.display()),
+        Err(err) => log::error!("Failed to restore the original wallpaper: {err}"),
+    }
+}
 
-    // Write the filename on the canvas below the image
-    let filename = std::path::Path::new(name)
-        .file_name()
-        .unwrap()
-        .to_str()
-        .unwrap();
-    let scale = rusttype::Scale {
-        x: TEXT_SIZE as f32,
-        y: TEXT_SIZE as f32,
+fn run_service(options: ServiceOptions) -> Result<(), ServiceError> {
+    // A dry run leaves even the lock file alone and may run next to the service
+    let _instance_guard = if options.dry_run {
+        log::info!("Dry run, nothing is rendered, set or stored");
+        None
+    } else {
+        let Some(instance_guard) = InstanceGuard::acquire()? else {
+            log::warn!("Another instance of flower-service is already running, exiting");
+            return Ok(());
+        };
+        Some(instance_guard)
     };
-    let mut text_canvas = RgbaImage::new(CANVAS_WIDTH, TEXT_SIZE + 8);
-    for y in 0..TEXT_SIZE + 8 {
-        for x in 0..CANVAS_WIDTH {
-            text_canvas.put_pixel(x, y, off_white);
+
+    let image_file_names = list_wiki_flowers()?;
+
+    // Without a stored list, e.g. from before it was kept, the permutation refers to today's
+    let stored_image_file_names = load_image_list();
+    let known_image_file_names = stored_image_file_names
+        .clone()
+        .unwrap_or_else(|| image_file_names.clone());
+
+    let mut permutation = match load_permutation() {
+        Ok(permutation) if !permutation.is_empty() => permutation,
+        Ok(_) => first_permutation(known_image_file_names.len(), options.dry_run),
+        Err(err) if err.is_first_run() => {
+            first_permutation(known_image_file_names.len(), options.dry_run)
         }
-    }
-    let mut max_x = 0;
-
-    for glyph in font.layout(
-        filename,
-        scale,
-        rusttype::point(0., (TEXT_SIZE + 8) as f32 / 2.),
-    ) {
-        if let Some(bb) = glyph.pixel_bounding_box() {
-            let color = image::Rgba([0, 0, 0, 255]);
-            glyph.draw(|x, y, v| {
-                let x = (x as i32 + bb.min.x + 2) as u32;
-                let y = (y as i32 + bb.min.y + 2) as u32;
-                if v > 0.5 {
-                    max_x = max_x.max(x);
-                    text_canvas.put_pixel(x, y, color);
+        // Like a first run, the index and the time of the last change are lost with the file
+        Err(err) if err.is_corrupt() => {
+            log::warn!("{err}, starting over with a new rotation");
+            if !options.dry_run {
+                match set_aside_state() {
+                    Ok(bad_path) => {
+                        log::warn!("Moved the unreadable state to {}", bad_path.display())
+                    }
+                    Err(err) => log::error!("Cannot move state.json aside: {err}"),
                 }
-            });
+            }
+            first_permutation(known_image_file_names.len(), options.dry_run)
         }
+        Err(err) => return Err(err.into()),
+    };
+    permutation.retain(|&image_idx| {
+        let in_range = image_idx < known_image_file_names.len();
+        if !in_range {
+            log::warn!(
+                "Ignoring index {image_idx} in the stored permutation, there are only {} images",
+                known_image_file_names.len()
+            );
+        }
+        in_range
+    });
+    if permutation.is_empty() {
+        return Err(
+            "The stored permutation contains no usable indices, run flower-service --shuffle"
+                .into(),
+        );
     }
 
-    // Place the text on the canvas
-    let (text_offset_x, text_offset_y) = (
-        (CANVAS_WIDTH - max_x) / 2,
-        image_y_offset + scaled_height + MARGIN / 2,
-    );
+    let settings = ServiceConfig::load(&options)?;
+    let config = &settings.config;
+    if let Some(jitter) = config.jitter {
+        log::info!(
+            "Changes on this machine are shifted by {:+} minutes, at most {} either way",
+            jitter_offset(Some(jitter)).num_minutes(),
+            jitter.as_secs() / 60
+        );
+    }
 
-    for y in 0..TEXT_SIZE {
-        for x in 0..max_x {
-            let pixel = text_canvas.get_pixel(x, y);
-            canvas.put_pixel(text_offset_x + x, text_offset_y + y, *pixel);
+    let (state, state_loaded) = match State::load() {
+        Ok(state) => (state, true),
+        // The epoch as the last change makes the first flower show right away
+        Err(err) if err.is_first_run() => {
+            log::info!("No wallpaper change stored yet, starting from the first flower");
+            (State::default(), false)
+        }
+        Err(err) => {
+            log::warn!("{err}, starting from the first flower");
+            (State::default(), false)
+        }
+    };
+    let mut rotation = Rotation::new(
+        known_image_file_names,
+        permutation,
+        state,
+        config,
+        options.dry_run,
+    );
+    match &stored_image_file_names {
+        Some(stored) if *stored == image_file_names => {}
+        Some(_) => {
+            log::info!("The images changed since the last run, updating the permutation");
+            rotation.update_library(image_file_names);
+            rotation.store_library();
+        }
+        None if options.dry_run => {}
+        None => {
+            if let Err(err) = store_image_list(&image_file_names) {
+                log::error!("Failed to store the image list: {err}");
+            }
         }
     }
+    // The permutation may have shrunk since the index was stored
+    rotation.state.index %= rotation.permutation.len();
+    // Without a stored state there is no last change to catch up from
+    if config.catch_up && state_loaded {
+        let offset = jitter_offset(config.jitter);
+        let missed = config.interval.missed_changes(
+            rotation.state.last_change - offset,
+            Local::now() - offset,
+            config.timezone,
+        );
+        rotation.skip(missed);
+    }
 
-    // Save the result to the file
-    canvas.save(output_image_path).unwrap();
-}
+    if !options.dry_run {
+        capture_original_wallpaper(&platform_wallpaper_setter(config.wallpaper_style));
+    }
+    let restore_on_exit = config.restore_on_exit.then_some(config.wallpaper_style);
+    if let Some(style) = restore_on_exit {
+        // Also covers closing the console, Windows ends the process once the handler returns
+        ctrlc::set_handler(move || {
+            restore_original_wallpaper(style);
+            process::exit(0);
+        })?;
+    }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let permutated_indices: Vec<usize> =
-        fs::read_to_string(to_path("wiki_flower_permutation.txt"))?
-            .split(", ")
-            .flat_map(|num| num.parse())
-            .collect();
+    let status = SharedStatus::default();
+    if config.status.enabled {
+        let status_config = config.status.clone();
+        let status = status.clone();
+        thread::spawn(move || {
+            if let Err(err) = run_status_server(&status_config, status) {
+                log::error!("Cannot serve the status: {err}");
+            }
+        });
+    }
 
-    let mut image_file_names: Vec<String> = fs::read_dir(to_path("wiki_flowers"))?
-        .flatten()
-        .flat_map(|entry| entry.file_name().into_string())
-        .collect();
-    image_file_names.sort();
-
-    let (last_timestamp, mut file_idx) =
-        get_last_wallpaper_change_and_idx().ok_or("Error reading persistent storage")?;
-
-    if last_timestamp.date_naive() == Local::now().date_naive() {
-        thread::sleep(Duration::from_secs(
-            NaiveTime::from_hms_opt(23, 59, 59)
-                .unwrap()
-                .signed_duration_since(Local::now().time())
-                .num_seconds()
-                .unsigned_abs(),
-        ));
+    let (command_sender, commands) = mpsc::channel();
+
+    if config.control_pipe {
+        let status = status.clone();
+        let command_sender = command_sender.clone();
+        thread::spawn(move || {
+            if let Err(err) = run_control_server(status, command_sender) {
+                log::error!("Cannot accept commands from other programs: {err}");
+            }
+        });
     }
-    let output_file_path = to_path("flower_of_today.png");
-    loop {
-        let current_file_name = &image_file_names[permutated_indices[file_idx]];
-
-        modify_image(
-            current_file_name
-                .trim_end_matches(".jpg")
-                .trim_end_matches(".JPG")
-                .trim_end_matches(".png"),
-            to_path("wiki_flowers").join(current_file_name),
-            output_file_path.clone(),
-        );
 
-        set_wallpaper(output_file_path.clone());
-        file_idx = (file_idx + 1) % image_file_names.len();
-        store_last_wallpaper_change_and_idx(file_idx);
+    if config.fetch.enabled {
+        let fetch_config = config.fetch.clone();
+        let command_sender = command_sender.clone();
+        thread::spawn(move || run_fetcher(fetch_config, command_sender));
+    }
 
-        thread::sleep(Duration::from_secs(
-            NaiveTime::from_hms_opt(23, 59, 59)
-                .unwrap()
-                .signed_duration_since(Local::now().time())
-                .num_seconds()
-                .unsigned_abs(),
-        ));
+    // The tray needs the message loop of the main thread, so the scheduler moves to its own
+    #[cfg(windows)]
+    match Tray::new(command_sender) {
+        Ok(tray) => {
+            let notifier = tray.notifier();
+            let scheduler = thread::spawn(move || {
+                let result = run_scheduler(rotation, settings, &commands, &status, |captions| {
+                    notifier.flower_changed(&captions.join(", "))
+                });
+                // Otherwise the tray would keep running without a scheduler behind it
+                notifier.quit();
+                result
+            });
+            tray.run();
+            let result = match scheduler.join() {
+                Ok(result) => result,
+                Err(_) => Err("The scheduler thread panicked".into()),
+            };
+            if let Some(style) = restore_on_exit {
+                restore_original_wallpaper(style);
+            }
+            return result;
+        }
+        Err(err) => log::warn!("Cannot create the tray icon, running without it: {err}"),
+    }
+    #[cfg(not(windows))]
+    drop(command_sender);
+
+    let result = run_scheduler(rotation, settings, &commands, &status, |_| {});
+    if let Some(style) = restore_on_exit {
+        restore_original_wallpaper(style);
     }
+    result
 }
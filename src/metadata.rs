@@ -0,0 +1,208 @@
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use chrono::NaiveDateTime;
+use exif::{In, Tag, Value};
+
+// strftime format of {taken} in the caption, the info stamp uses its own date_format
+pub const TAKEN_FORMAT: &str = "%-d %B %Y";
+
+// What the EXIF data of a photo tells about it; tags the photo lacks stay `None`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PhotoMetadata {
+    pub taken: Option<NaiveDateTime>,
+    // Latitude and longitude in degrees, negative to the south and west
+    pub gps: Option<(f64, f64)>,
+}
+
+impl PhotoMetadata {
+    pub fn is_used_by(template: &str) -> bool {
+        template.contains("{taken}") || template.contains("{gps}")
+    }
+
+    // Fills in {taken} and {gps}, with empty text for what the photo has no tag for
+    pub fn fill(&self, template: &str, date_format: &str) -> String {
+        let mut taken = String::new();
+        if let Some(date_time) = self.taken
+            && write!(taken, "{}", date_time.format(date_format)).is_err()
+        {
+            taken = date_time.date().to_string();
+        }
+        let gps = self
+            .gps
+            .map(|(latitude, longitude)| {
+                let north_south = if latitude < 0. { 'S' } else { 'N' };
+                let east_west = if longitude < 0. { 'W' } else { 'E' };
+                format!(
+                    "{:.4}° {north_south}, {:.4}° {east_west}",
+                    latitude.abs(),
+                    longitude.abs()
+                )
+            })
+            .unwrap_or_default();
+        template.replace("{taken}", &taken).replace("{gps}", &gps)
+    }
+}
+
+// Photos without EXIF data, or formats that cannot carry it, just have no metadata
+pub fn read_metadata(path: &Path) -> PhotoMetadata {
+    let exif = match File::open(path)
+        .map_err(exif::Error::Io)
+        .and_then(|file| exif::Reader::new().read_from_container(&mut BufReader::new(file)))
+    {
+        Ok(exif) => exif,
+        Err(err) => {
+            log::debug!("No EXIF data in {}: {err}", path.display());
+            return PhotoMetadata::default();
+        }
+    };
+
+    let taken = exif
+        .get_field(Tag::DateTimeOriginal, In::PRIMARY)
+        .or_else(|| exif.get_field(Tag::DateTime, In::PRIMARY))
+        .and_then(|field| match &field.value {
+            Value::Ascii(values) => values.first(),
+            _ => None,
+        })
+        .and_then(|ascii| exif::DateTime::from_ascii(ascii).ok())
+        .and_then(|date_time| {
+            chrono::NaiveDate::from_ymd_opt(
+                date_time.year.into(),
+                date_time.month.into(),
+                date_time.day.into(),
+            )?
+            .and_hms_opt(
+                date_time.hour.into(),
+                date_time.minute.into(),
+                date_time.second.into(),
+            )
+        });
+
+    let coordinate = |tag: Tag, ref_tag: Tag, negative: u8| {
+        let degrees = match &exif.get_field(tag, In::PRIMARY)?.value {
+            Value::Rational(parts) if !parts.is_empty() => parts
+                .iter()
+                .zip([1., 60., 3600.])
+                .map(|(part, divisor)| part.to_f64() / divisor)
+                .sum::<f64>(),
+            _ => return None,
+        };
+        if !degrees.is_finite() {
+            return None;
+        }
+        let negative = match &exif.get_field(ref_tag, In::PRIMARY)?.value {
+            Value::Ascii(values) => values.first()?.first() == Some(&negative),
+            _ => return None,
+        };
+        Some(if negative { -degrees } else { degrees })
+    };
+    let latitude = coordinate(Tag::GPSLatitude, Tag::GPSLatitudeRef, b'S');
+    let longitude = coordinate(Tag::GPSLongitude, Tag::GPSLongitudeRef, b'W');
+    let gps = latitude.zip(longitude);
+
+    PhotoMetadata { taken, gps }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use chrono::NaiveDate;
+    use image::codecs::jpeg::JpegEncoder;
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    // One big-endian IFD entry, the value inline or as an offset into the TIFF data
+    fn entry(tag: u16, kind: u16, count: u32, value: [u8; 4]) -> Vec<u8> {
+        [
+            &tag.to_be_bytes()[..],
+            &kind.to_be_bytes(),
+            &count.to_be_bytes(),
+            &value,
+        ]
+        .concat()
+    }
+
+    fn rationals(parts: [u32; 3]) -> Vec<u8> {
+        parts
+            .into_iter()
+            .flat_map(|part| [part.to_be_bytes(), 1u32.to_be_bytes()].concat())
+            .collect()
+    }
+
+    // A JPEG taken on 1 June 2024 at 52°31'12" N, 13°24'18" W
+    fn jpeg_with_exif() -> Vec<u8> {
+        const ASCII: u16 = 2;
+        const LONG: u16 = 4;
+        const RATIONAL: u16 = 5;
+        // Offsets into the TIFF data of the Exif and GPS IFDs and the values they point to
+        let (exif_ifd, taken, gps_ifd, latitude, longitude) = (38u32, 56u32, 76u32, 130u32, 154u32);
+        let mut tiff = b"MM\0\x2a\0\0\0\x08\0\x02".to_vec();
+        tiff.extend(entry(0x8769, LONG, 1, exif_ifd.to_be_bytes()));
+        tiff.extend(entry(0x8825, LONG, 1, gps_ifd.to_be_bytes()));
+        tiff.extend([0; 4]);
+        tiff.extend([0, 1]);
+        tiff.extend(entry(0x9003, ASCII, 20, taken.to_be_bytes()));
+        tiff.extend([0; 4]);
+        tiff.extend(b"2024:06:01 12:34:56\0");
+        tiff.extend([0, 4]);
+        tiff.extend(entry(0x0001, ASCII, 2, *b"N\0\0\0"));
+        tiff.extend(entry(0x0002, RATIONAL, 3, latitude.to_be_bytes()));
+        tiff.extend(entry(0x0003, ASCII, 2, *b"W\0\0\0"));
+        tiff.extend(entry(0x0004, RATIONAL, 3, longitude.to_be_bytes()));
+        tiff.extend([0; 4]);
+        tiff.extend(rationals([52, 31, 12]));
+        tiff.extend(rationals([13, 24, 18]));
+        assert_eq!(tiff.len(), 178);
+
+        let mut jpeg = Vec::new();
+        JpegEncoder::new(&mut jpeg)
+            .encode_image(&RgbImage::from_pixel(8, 8, Rgb([200, 40, 60])))
+            .unwrap();
+        let mut segment = vec![0xff, 0xe1];
+        segment.extend((tiff.len() as u16 + 8).to_be_bytes());
+        segment.extend(b"Exif\0\0");
+        segment.extend(tiff);
+        jpeg.splice(2..2, segment);
+        jpeg
+    }
+
+    #[test]
+    fn date_taken_and_location_are_read() {
+        let path =
+            std::env::temp_dir().join(format!("flower-service-exif-{}.jpg", std::process::id()));
+        fs::write(&path, jpeg_with_exif()).unwrap();
+        let metadata = read_metadata(&path);
+        let _ = fs::remove_file(&path);
+
+        let taken =
+            NaiveDate::from_ymd_opt(2024, 6, 1).and_then(|date| date.and_hms_opt(12, 34, 56));
+        assert_eq!(metadata.taken, taken);
+        let (latitude, longitude) = metadata.gps.unwrap();
+        assert!((latitude - 52.52).abs() < 1e-9, "{latitude}");
+        assert!((longitude + 13.405).abs() < 1e-9, "{longitude}");
+        assert_eq!(
+            metadata.fill("{taken}, {gps}", TAKEN_FORMAT),
+            "1 June 2024, 52.5200° N, 13.4050° W"
+        );
+    }
+
+    #[test]
+    fn missing_tags_leave_the_placeholders_empty() {
+        let path =
+            std::env::temp_dir().join(format!("flower-service-no-exif-{}.png", std::process::id()));
+        RgbImage::from_pixel(8, 8, Rgb([200, 40, 60]))
+            .save(&path)
+            .unwrap();
+        let metadata = read_metadata(&path);
+        let _ = fs::remove_file(&path);
+        assert_eq!(metadata, PhotoMetadata::default());
+        assert_eq!(
+            metadata.fill("Rosa canina{taken}{gps}", TAKEN_FORMAT),
+            "Rosa canina"
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use std::path::Path;
+
+use image::{RgbaImage, imageops};
+use serde::Deserialize;
+
+use crate::background::{Background, DEFAULT_BACKGROUND};
+use crate::render::{self, RenderConfig, RenderError, Renderer};
+
+// Enough for a contact sheet, more cells leave no room for the photos
+const MAX_CELLS: u32 = 64;
+
+// Several flowers in a grid on one wallpaper, each laid out like a wallpaper of the cell size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "MontageGrid")]
+pub struct MontageConfig {
+    pub columns: u32,
+    pub rows: u32,
+}
+
+impl Default for MontageConfig {
+    fn default() -> Self {
+        Self {
+            columns: 3,
+            rows: 2,
+        }
+    }
+}
+
+impl MontageConfig {
+    // `None` for a grid too large to count, which `Montage::new` rejects
+    pub fn cells(&self) -> Option<usize> {
+        let cells = self.columns.checked_mul(self.rows)?;
+        usize::try_from(cells).ok()
+    }
+
+    fn check(&self) -> Result<u32, String> {
+        let MontageConfig { columns, rows } = *self;
+        match columns.checked_mul(rows) {
+            Some(cells) if columns > 0 && rows > 0 && cells <= MAX_CELLS => Ok(cells),
+            _ => Err(format!(
+                "A montage of {columns}x{rows} flowers is not between 1 and {MAX_CELLS} cells"
+            )),
+        }
+    }
+}
+
+// The grid as written in config.toml, checked before it becomes a `MontageConfig`
+#[derive(Deserialize)]
+#[serde(default)]
+struct MontageGrid {
+    columns: u32,
+    rows: u32,
+}
+
+impl Default for MontageGrid {
+    fn default() -> Self {
+        let MontageConfig { columns, rows } = MontageConfig::default();
+        Self { columns, rows }
+    }
+}
+
+impl TryFrom<MontageGrid> for MontageConfig {
+    type Error = String;
+
+    fn try_from(MontageGrid { columns, rows }: MontageGrid) -> Result<Self, Self::Error> {
+        let montage = MontageConfig { columns, rows };
+        montage.check()?;
+        Ok(montage)
+    }
+}
+
+// A montage being filled cell by cell, left to right and top to bottom
+pub struct Montage {
+    canvas: RgbaImage,
+    // Where each cell starts and the config its flower is rendered with
+    cells: Vec<((u32, u32), RenderConfig)>,
+    filled: usize,
+    jpeg_quality: u8,
+    renderer: Renderer,
+}
+
+impl Montage {
+    pub fn new(config: &RenderConfig, montage: MontageConfig) -> Result<Self, RenderError> {
+        let cell_count = montage.check().map_err(RenderError::Config)?;
+        let MontageConfig { columns, rows } = montage;
+        let (width, height) = (config.canvas_width, config.canvas_height);
+        // The grid covers the canvas outside the safe area, the cells have none of their own
+        let ((area_x, area_y), (area_width, area_height)) =
+            config.with_scale_applied().usable_area()?;
+        // The cell edges are rounded apart, so the cells cover the area without gaps
+        let edge = |index: u32, count: u32, length: u32| {
+            (index as u64 * length as u64 / count as u64) as u32
+        };
+        let mut cells = Vec::with_capacity(cell_count as usize);
+        for row in 0..rows {
+            for column in 0..columns {
+                let (x, y) = (
+                    edge(column, columns, area_width),
+                    edge(row, rows, area_height),
+                );
+                let cell_config = RenderConfig {
+                    // The stamp belongs to the screen, not to every cell
+                    info_stamp: None,
+                    safe_area: None,
+                    ..config.scaled_to(
+                        edge(column + 1, columns, area_width) - x,
+                        edge(row + 1, rows, area_height) - y,
+                    )
+                };
+                cell_config.validate()?;
+                cells.push(((area_x + x, area_y + y), cell_config));
+            }
+        }
+        // Shows in the cells that stay empty when there are fewer flowers than cells
+        let fill = match config.background.resolve_system_theme().0 {
+            Background::Solid(color) => color,
+            _ => DEFAULT_BACKGROUND,
+        };
+        Ok(Self {
+            canvas: RgbaImage::from_pixel(width, height, fill),
+            cells,
+            filled: 0,
+            jpeg_quality: config.jpeg_quality,
+            renderer: Renderer::default(),
+        })
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.filled == self.cells.len()
+    }
+
+    // Renders the photo into the next free cell; a photo that fails leaves the cell free
+    pub fn add(&mut self, name: &str, image_path: &Path) -> Result<(), RenderError> {
+        let Some(((x, y), config)) = self.cells.get(self.filled) else {
+            return Err(RenderError::Config(
+                "The montage is already full".to_owned(),
+            ));
+        };
+        let cell = self.renderer.render_canvas(name, image_path, config)?;
+        imageops::replace(&mut self.canvas, cell, *x as i64, *y as i64);
+        self.filled += 1;
+        Ok(())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), RenderError> {
+        render::save_canvas(&self.canvas, path, self.jpeg_quality)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grids_beyond_the_cell_limit_are_rejected() {
+        let parse = |toml: &str| toml::from_str::<MontageConfig>(toml);
+        assert_eq!(parse("").unwrap(), MontageConfig::default());
+        assert_eq!(parse("columns = 8\nrows = 8").unwrap().cells(), Some(64));
+        for grid in [
+            "columns = 0",
+            "columns = 9\nrows = 8",
+            // Wraps around to 0 cells in u32
+            "columns = 65536\nrows = 65536",
+        ] {
+            assert!(parse(grid).is_err(), "{grid:?}");
+        }
+        let huge = MontageConfig {
+            columns: 65536,
+            rows: 65536,
+        };
+        assert_eq!(huge.cells(), None);
+        let config = RenderConfig::default().scaled_to(640, 360);
+        assert!(matches!(
+            Montage::new(&config, huge),
+            Err(RenderError::Config(_))
+        ));
+    }
+}
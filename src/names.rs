@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Display captions by image file name, e.g. "Rosa_canina.jpg" = "Dog Rose (Rosa canina)".
+// A missing or malformed file only means the captions come from the file names.
+pub fn load_names(path: &Path) -> HashMap<String, String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return HashMap::new(),
+        Err(err) => {
+            log::warn!("Cannot read {}, ignoring it: {err}", path.display());
+            return HashMap::new();
+        }
+    };
+    serde_json::from_str(&contents).unwrap_or_else(|err| {
+        log::warn!("Invalid {}, ignoring it: {err}", path.display());
+        HashMap::new()
+    })
+}
+
+// Prefers the caption from names.json, by path relative to wiki_flowers or by file name.
+// Otherwise drops whatever extension the file has; underscores stand in for spaces in Wikimedia
+// file names.
+pub fn caption_for(file_name: &str, names: &HashMap<String, String>) -> String {
+    let path = Path::new(file_name);
+    let base_name = path.file_name().and_then(|name| name.to_str());
+    if let Some(caption) = names
+        .get(file_name)
+        .or_else(|| base_name.and_then(|name| names.get(name)))
+    {
+        return caption.clone();
+    }
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(file_name)
+        .replace('_', " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caption_drops_any_extension() {
+        let names = HashMap::new();
+        assert_eq!(caption_for("Rosa_canina.JPEG", &names), "Rosa canina");
+        assert_eq!(caption_for("Tulip.webp", &names), "Tulip");
+        assert_eq!(caption_for("Lily.2.png", &names), "Lily.2");
+        assert_eq!(
+            caption_for("spring/Crocus_vernus.jpg", &names),
+            "Crocus vernus"
+        );
+    }
+
+    #[test]
+    fn caption_prefers_names_json() {
+        let names = HashMap::from([
+            ("Rosa_canina.jpg".to_owned(), "Dog Rose".to_owned()),
+            ("spring/Tulip.png".to_owned(), "Garden Tulip".to_owned()),
+        ]);
+        assert_eq!(caption_for("Rosa_canina.jpg", &names), "Dog Rose");
+        assert_eq!(caption_for("summer/Rosa_canina.jpg", &names), "Dog Rose");
+        assert_eq!(caption_for("spring/Tulip.png", &names), "Garden Tulip");
+        assert_eq!(caption_for("Tulip.png", &names), "Tulip");
+    }
+}
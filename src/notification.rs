@@ -0,0 +1,15 @@
+// Shows "Today's flower: ..." as a desktop notification. Failing to show it is only logged,
+// the wallpaper has changed either way.
+pub fn notify_flower_changed(captions: &[String]) {
+    if captions.is_empty() {
+        return;
+    }
+    let result = notify_rust::Notification::new()
+        .appname("flower-service")
+        .summary("flower-service")
+        .body(&format!("Today's flower: {}", captions.join(", ")))
+        .show();
+    if let Err(err) = result {
+        log::warn!("Cannot show the flower notification: {err}");
+    }
+}
@@ -0,0 +1,1704 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Seek};
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use std::{fmt, fs, io};
+
+use chrono::Local;
+use image::buffer::ConvertBuffer as _;
+use image::codecs::gif::GifDecoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::error::DecodingError;
+use image::imageops::FilterType;
+use image::metadata::Orientation;
+use image::{
+    AnimationDecoder as _, DynamicImage, GenericImageView as _, ImageDecoder as _, ImageFormat,
+    ImageReader, Limits, RgbImage, RgbaImage,
+};
+use rayon::prelude::*;
+use serde::Deserialize;
+
+use crate::background::{Background, DEFAULT_BACKGROUND, Gradient};
+use crate::border::{self, BorderConfig};
+use crate::caption::{self, CaptionOutline, TextColor};
+use crate::corners::{self, Corner, CornerRadii};
+use crate::filter::{self, PhotoFilter};
+use crate::layout::{CaptionLayout, Insets, Layout};
+use crate::metadata::{PhotoMetadata, TAKEN_FORMAT, read_metadata};
+use crate::shadow::{self, ShadowConfig};
+use crate::stamp::StampConfig;
+
+// Top left point and size of a rectangle on the canvas
+type CanvasArea = ((u32, u32), (u32, u32));
+
+// With `CaptionLayout::Auto`, photos narrower than this share of the image box aspect ratio get
+// the caption beside them
+const SIDE_CAPTION_ASPECT_RATIO: f32 = 0.6;
+// The side caption takes this part of the width between the margins
+const SIDE_CAPTION_WIDTH_DIVISOR: u32 = 4;
+// More samples are slow to build for large radii and look no different
+const MAX_CORNER_AA: u8 = 16;
+
+pub fn apply_mask(input: image::Rgba<u8>, mask: image::Rgba<u8>) -> image::Rgba<u8> {
+    let [r_mask, g_mask, b_mask, a_mask] = mask.0;
+    let [r_canvas, g_canvas, b_canvas, _a_canvas] = input.0;
+
+    // Perform alpha blending (compositing) between the mask and the canvas
+    let alpha = a_mask as f32 / 255.0;
+    let new_r = ((1.0 - alpha) * r_canvas as f32 + alpha * r_mask as f32) as u8;
+    let new_g = ((1.0 - alpha) * g_canvas as f32 + alpha * g_mask as f32) as u8;
+    let new_b = ((1.0 - alpha) * b_canvas as f32 + alpha * b_mask as f32) as u8;
+    let new_a = 255; // Keep alpha at 255 for the resulting image
+
+    image::Rgba([new_r, new_g, new_b, new_a])
+}
+
+// sRGB channel value to linear light
+pub(crate) static SRGB_TO_LINEAR: LazyLock<[f32; 256]> = LazyLock::new(|| {
+    std::array::from_fn(|value| {
+        let value = value as f32 / 255.;
+        if value <= 0.04045 {
+            value / 12.92
+        } else {
+            ((value + 0.055) / 1.055).powf(2.4)
+        }
+    })
+});
+
+fn linear_to_srgb(value: f32) -> u8 {
+    let value = if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1. / 2.4) - 0.055
+    };
+    (value * 255.).round().clamp(0., 255.) as u8
+}
+
+// Like `apply_mask`, but mixes in linear light, which keeps edges between bright and dark
+// colors from turning muddy
+pub fn blend_linear(input: image::Rgba<u8>, mask: image::Rgba<u8>) -> image::Rgba<u8> {
+    let alpha = mask[3] as f32 / 255.0;
+    let channel = |index: usize| {
+        linear_to_srgb(
+            (1.0 - alpha) * SRGB_TO_LINEAR[input[index] as usize]
+                + alpha * SRGB_TO_LINEAR[mask[index] as usize],
+        )
+    };
+    image::Rgba([channel(0), channel(1), channel(2), 255])
+}
+
+// File format of the rendered wallpaper
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    #[default]
+    Png,
+    Jpeg,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+        }
+    }
+}
+
+// How the photo fills its box: `Contain` shows all of it, `Cover` crops its middle to the box
+// aspect ratio so every flower takes the same space
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FitMode {
+    #[default]
+    Contain,
+    Cover,
+}
+
+// Filter the photo is scaled with; `Lanczos3` is the sharpest, `Nearest` the fastest
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    #[default]
+    Lanczos3,
+}
+
+impl ResizeFilter {
+    fn filter_type(self) -> FilterType {
+        match self {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+// How the rounded corners are mixed into the background
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlendMode {
+    #[default]
+    Srgb,
+    Linear,
+}
+
+impl BlendMode {
+    pub fn blend(&self, input: image::Rgba<u8>, mask: image::Rgba<u8>) -> image::Rgba<u8> {
+        match self {
+            BlendMode::Srgb => apply_mask(input, mask),
+            BlendMode::Linear => blend_linear(input, mask),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RenderError {
+    Config(String),
+    Image(image::ImageError),
+    Font,
+    Caption(String),
+    // The shorter side of the photo is below `min_photo_size`
+    TooSmall {
+        width: u32,
+        height: u32,
+        min_size: u32,
+    },
+    // The photo has more than `max_photo_megapixels`
+    TooLarge {
+        width: u32,
+        height: u32,
+        max_megapixels: u32,
+    },
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::Config(message) => write!(f, "Invalid render config: {message}"),
+            RenderError::Image(err) => write!(f, "Image error: {err}"),
+            RenderError::Font => write!(f, "Error loading font"),
+            RenderError::Caption(name) => write!(f, "Cannot derive a caption from {name:?}"),
+            RenderError::TooSmall {
+                width,
+                height,
+                min_size,
+            } => write!(
+                f,
+                "Photo of {width}x{height} pixels is below the minimum of {min_size} pixels on its shorter side"
+            ),
+            RenderError::TooLarge {
+                width,
+                height,
+                max_megapixels,
+            } => write!(
+                f,
+                "Photo of {width}x{height} pixels is above the limit of {max_megapixels} megapixels, it is not decoded"
+            ),
+        }
+    }
+}
+
+impl Error for RenderError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RenderError::Image(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<image::ImageError> for RenderError {
+    fn from(err: image::ImageError) -> Self {
+        RenderError::Image(err)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RenderConfig {
+    pub canvas_width: u32,
+    pub canvas_height: u32,
+    // Multiplies text, margins, corners and every other size on top of the canvas scaling,
+    // e.g. 1.5 on a high-DPI screen viewed from close by
+    pub scale: f32,
+    pub margin: u32,
+    // Kept clear of the photo and the caption, which are laid out in the rest of the canvas
+    pub safe_area: Option<Insets>,
+    // Height kept below the photo for the caption at least, longer captions take more
+    pub bottom_extra_margin: u32,
+    pub fit_mode: FitMode,
+    pub resize_filter: ResizeFilter,
+    // Photos with a shorter side below this many pixels are not rendered, 0 for no limit
+    pub min_photo_size: u32,
+    // Photos with more pixels are not decoded, which bounds the memory a render takes; 0 for no limit
+    pub max_photo_megapixels: u32,
+    // Frame of an animated GIF that is used as the photo, 0 for the first
+    pub animation_frame: usize,
+    pub photo_filter: PhotoFilter,
+    // Added to every channel of the photo, -255 to 255
+    pub brightness: i32,
+    // Percent, negative values flatten the photo
+    pub contrast: f32,
+    pub corner_radius: CornerRadii,
+    pub text_size: u32,
+    // Added between the letters of the caption, in canvas pixels, may be negative
+    pub letter_spacing: f32,
+    // Distance between the baselines of wrapped caption lines, as a multiple of the text size
+    pub line_spacing: f32,
+    pub background: Background,
+    // Falls back to the embedded Playfair Display when unset or unreadable
+    pub font_path: Option<PathBuf>,
+    // For characters the caption font lacks, defaults to a font of the system
+    pub fallback_font_path: Option<PathBuf>,
+    pub shadow: Option<ShadowConfig>,
+    pub border: Option<BorderConfig>,
+    // Defaults to the color that suits the background
+    pub text_color: Option<TextColor>,
+    pub text_outline: Option<CaptionOutline>,
+    pub caption_layout: CaptionLayout,
+    // Text around the caption, "{name}" stands for the caption itself
+    pub caption_template: String,
+    pub corner_blending: BlendMode,
+    // Samples per axis for the anti-aliasing of the corners, 1 is a single distance test
+    pub corner_aa: u8,
+    // Top and bottom color of a gradient behind the caption, instead of the plain band
+    pub caption_gradient: Option<Gradient>,
+    pub info_stamp: Option<StampConfig>,
+    // 1-based place of the flower in the rotation and the length of it, for the info stamp
+    pub stamp_position: Option<(usize, usize)>,
+    pub output_format: OutputFormat,
+    // 1 to 100, for JPEG output
+    pub jpeg_quality: u8,
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        Self {
+            canvas_width: 2560,
+            canvas_height: 1530,
+            scale: 1.,
+            margin: 50,
+            safe_area: None,
+            bottom_extra_margin: 150,
+            fit_mode: FitMode::Contain,
+            resize_filter: ResizeFilter::Lanczos3,
+            min_photo_size: 0,
+            max_photo_megapixels: 16,
+            animation_frame: 0,
+            photo_filter: PhotoFilter::None,
+            brightness: 0,
+            contrast: 0.,
+            corner_radius: CornerRadii::uniform(50),
+            text_size: 60,
+            letter_spacing: 0.,
+            line_spacing: 1.2,
+            background: Background::Solid(DEFAULT_BACKGROUND),
+            font_path: None,
+            fallback_font_path: None,
+            shadow: None,
+            border: None,
+            text_color: None,
+            text_outline: None,
+            caption_layout: CaptionLayout::Bottom,
+            caption_template: "{name}".to_owned(),
+            corner_blending: BlendMode::Srgb,
+            corner_aa: 1,
+            caption_gradient: None,
+            info_stamp: None,
+            stamp_position: None,
+            output_format: OutputFormat::Png,
+            jpeg_quality: 90,
+        }
+    }
+}
+
+impl RenderConfig {
+    // Margins, corners and the text band keep their proportions relative to the canvas height
+    pub fn scaled_to(&self, canvas_width: u32, canvas_height: u32) -> Self {
+        Self {
+            canvas_width,
+            canvas_height,
+            ..self.scaled_by(canvas_height as f32 / self.canvas_height as f32)
+        }
+    }
+
+    // The sizes the photo is actually rendered with
+    pub(crate) fn with_scale_applied(&self) -> Self {
+        Self {
+            scale: 1.,
+            ..self.scaled_by(self.scale)
+        }
+    }
+
+    fn scaled_by(&self, factor: f32) -> Self {
+        let scale = |value: u32| (value as f32 * factor).round() as u32;
+        Self {
+            canvas_width: self.canvas_width,
+            canvas_height: self.canvas_height,
+            scale: self.scale,
+            margin: scale(self.margin),
+            safe_area: self.safe_area.map(|insets| insets.scaled(factor)),
+            bottom_extra_margin: scale(self.bottom_extra_margin),
+            fit_mode: self.fit_mode,
+            resize_filter: self.resize_filter,
+            // Refers to the photo, not the canvas
+            min_photo_size: self.min_photo_size,
+            max_photo_megapixels: self.max_photo_megapixels,
+            animation_frame: self.animation_frame,
+            photo_filter: self.photo_filter,
+            brightness: self.brightness,
+            contrast: self.contrast,
+            corner_radius: self.corner_radius.map(scale),
+            text_size: scale(self.text_size),
+            letter_spacing: self.letter_spacing * factor,
+            line_spacing: self.line_spacing,
+            background: self.background.scaled(factor),
+            font_path: self.font_path.clone(),
+            fallback_font_path: self.fallback_font_path.clone(),
+            shadow: self.shadow.map(|shadow| shadow.scaled(factor)),
+            border: self.border.map(|border| border.scaled(factor)),
+            text_color: self.text_color,
+            text_outline: self.text_outline.map(|outline| outline.scaled(factor)),
+            caption_layout: self.caption_layout,
+            caption_template: self.caption_template.clone(),
+            corner_blending: self.corner_blending,
+            corner_aa: self.corner_aa,
+            caption_gradient: self.caption_gradient,
+            info_stamp: self.info_stamp.as_ref().map(|stamp| stamp.scaled(factor)),
+            stamp_position: self.stamp_position,
+            output_format: self.output_format,
+            jpeg_quality: self.jpeg_quality,
+        }
+    }
+
+    // Top left point and size of the part of the canvas outside the safe area
+    pub(crate) fn usable_area(&self) -> Result<CanvasArea, RenderError> {
+        let Insets {
+            left,
+            top,
+            right,
+            bottom,
+        } = self.safe_area.unwrap_or_default();
+        let width = left
+            .checked_add(right)
+            .and_then(|insets| self.canvas_width.checked_sub(insets))
+            .filter(|&width| width > 0)
+            .ok_or_else(|| {
+                RenderError::Config(format!(
+                    "Safe area of {left} left and {right} right leaves no room on a canvas {} wide",
+                    self.canvas_width
+                ))
+            })?;
+        let height = top
+            .checked_add(bottom)
+            .and_then(|insets| self.canvas_height.checked_sub(insets))
+            .filter(|&height| height > 0)
+            .ok_or_else(|| {
+                RenderError::Config(format!(
+                    "Safe area of {top} top and {bottom} bottom leaves no room on a canvas {} high",
+                    self.canvas_height
+                ))
+            })?;
+        Ok(((left, top), (width, height)))
+    }
+
+    pub fn image_box(&self) -> Result<(u32, u32), RenderError> {
+        let (_, (area_width, area_height)) = self.usable_area()?;
+        let width = self
+            .margin
+            .checked_mul(2)
+            .and_then(|margins| area_width.checked_sub(margins))
+            .filter(|&width| width > 0)
+            .ok_or_else(|| {
+                RenderError::Config(format!(
+                    "Margin of {} leaves no room for the image on a canvas {} wide",
+                    self.margin, self.canvas_width
+                ))
+            })?;
+        let height = self
+            .margin
+            .checked_mul(2)
+            .and_then(|margins| margins.checked_add(self.bottom_extra_margin))
+            .and_then(|margins| area_height.checked_sub(margins))
+            .filter(|&height| height > 0)
+            .ok_or_else(|| {
+                RenderError::Config(format!(
+                    "Margin of {} plus bottom margin of {} leaves no room for the image on a canvas {} high",
+                    self.margin, self.bottom_extra_margin, self.canvas_height
+                ))
+            })?;
+        Ok((width, height))
+    }
+
+    pub fn validate(&self) -> Result<(), RenderError> {
+        if !(self.scale.is_finite() && self.scale > 0.) {
+            return Err(RenderError::Config(format!(
+                "Scale {} is not a positive number",
+                self.scale
+            )));
+        }
+        if !(1..=MAX_CORNER_AA).contains(&self.corner_aa) {
+            return Err(RenderError::Config(format!(
+                "Corner anti-aliasing of {} samples is not between 1 and {MAX_CORNER_AA}",
+                self.corner_aa
+            )));
+        }
+        if !(1..=100).contains(&self.jpeg_quality) {
+            return Err(RenderError::Config(format!(
+                "JPEG quality {} is not between 1 and 100",
+                self.jpeg_quality
+            )));
+        }
+        if !(-255..=255).contains(&self.brightness) {
+            return Err(RenderError::Config(format!(
+                "Brightness {} is not between -255 and 255",
+                self.brightness
+            )));
+        }
+        if !(self.line_spacing.is_finite() && self.line_spacing > 0.) {
+            return Err(RenderError::Config(format!(
+                "Line spacing {} is not a positive number",
+                self.line_spacing
+            )));
+        }
+        if !self.contrast.is_finite() {
+            return Err(RenderError::Config(format!(
+                "Contrast {} is not a number",
+                self.contrast
+            )));
+        }
+        if !self.caption_template.contains("{name}") {
+            return Err(RenderError::Config(format!(
+                "Caption template {:?} has no {{name}} for the caption",
+                self.caption_template
+            )));
+        }
+        if let Some(stamp) = &self.info_stamp
+            && !stamp.has_valid_date_format()
+        {
+            return Err(RenderError::Config(format!(
+                "Invalid date format {:?} for the info stamp",
+                stamp.date_format
+            )));
+        }
+        self.with_scale_applied().image_box().map(|_| ())
+    }
+}
+
+// Applies the EXIF orientation so photos taken in portrait are not placed sideways.
+// The size is read from the header first: a JPEG above the limit is decoded at a fraction of its
+// size that still covers the canvas, other photos above it are never decoded.
+fn open_oriented(
+    path: &Path,
+    animation_frame: usize,
+    max_megapixels: u32,
+    canvas_size: (u32, u32),
+) -> Result<DynamicImage, RenderError> {
+    let mut reader = ImageReader::open(path)
+        .map_err(image::ImageError::from)?
+        .with_guessed_format()
+        .map_err(image::ImageError::from)?;
+    reader.limits(decode_limits(max_megapixels));
+    if reader.format() == Some(ImageFormat::Gif) {
+        let decoder = GifDecoder::new(reader.into_inner())?;
+        check_decode_size(decoder.dimensions(), max_megapixels)?;
+        return Ok(open_gif_frame(decoder, animation_frame, path)?);
+    }
+    let is_jpeg = reader.format() == Some(ImageFormat::Jpeg);
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let (width, height) = decoder.dimensions();
+    let mut img = match check_decode_size((width, height), max_megapixels) {
+        Ok(()) => DynamicImage::from_decoder(decoder)?,
+        Err(err) if is_jpeg => {
+            drop(decoder);
+            let (canvas_width, canvas_height) = canvas_size;
+            let target = match orientation {
+                Orientation::Rotate90
+                | Orientation::Rotate270
+                | Orientation::Rotate90FlipH
+                | Orientation::Rotate270FlipH => (canvas_height, canvas_width),
+                _ => (canvas_width, canvas_height),
+            };
+            let img = open_jpeg_scaled(path, (width, height), target)?.ok_or(err)?;
+            log::info!(
+                "Decoded {} at {}x{} instead of {width}x{height}",
+                path.display(),
+                img.width(),
+                img.height()
+            );
+            img
+        }
+        Err(err) => return Err(err),
+    };
+    img.apply_orientation(orientation);
+    Ok(img)
+}
+
+// 16-bit RGBA, the largest pixels a decoder produces, for every pixel the limit allows
+fn decode_limits(max_megapixels: u32) -> Limits {
+    let mut limits = Limits::default();
+    if max_megapixels > 0 {
+        limits.max_alloc = Some(max_megapixels as u64 * 1_000_000 * 8);
+    }
+    limits
+}
+
+fn check_decode_size((width, height): (u32, u32), max_megapixels: u32) -> Result<(), RenderError> {
+    if max_megapixels > 0 && width as u64 * height as u64 > max_megapixels as u64 * 1_000_000 {
+        return Err(RenderError::TooLarge {
+            width,
+            height,
+            max_megapixels,
+        });
+    }
+    Ok(())
+}
+
+// The smallest of 1/8, 1/4 and 1/2 of `size` that is still at least `target` on both sides
+fn jpeg_scale_divisor(
+    (width, height): (u32, u32),
+    (target_width, target_height): (u32, u32),
+) -> Option<u32> {
+    [8, 4, 2].into_iter().find(|divisor| {
+        width.div_ceil(*divisor) >= target_width && height.div_ceil(*divisor) >= target_height
+    })
+}
+
+// Decodes a JPEG at a fraction of its size, `None` when even half of it is smaller than `target`
+// or its pixels are not gray or RGB
+fn open_jpeg_scaled(
+    path: &Path,
+    size: (u32, u32),
+    target: (u32, u32),
+) -> Result<Option<DynamicImage>, RenderError> {
+    let Some(divisor) = jpeg_scale_divisor(size, target) else {
+        return Ok(None);
+    };
+    let jpeg_error = |err: jpeg_decoder::Error| {
+        RenderError::Image(image::ImageError::Decoding(DecodingError::new(
+            ImageFormat::Jpeg.into(),
+            err,
+        )))
+    };
+    let file = File::open(path).map_err(image::ImageError::from)?;
+    let mut decoder = jpeg_decoder::Decoder::new(io::BufReader::new(file));
+    let requested = |side: u32| u16::try_from(side.div_ceil(divisor)).unwrap_or(u16::MAX);
+    let (width, height) = decoder
+        .scale(requested(size.0), requested(size.1))
+        .map_err(jpeg_error)?;
+    let pixels = decoder.decode().map_err(jpeg_error)?;
+    let (width, height) = (width.into(), height.into());
+    let img = match decoder.info().map(|info| info.pixel_format) {
+        Some(jpeg_decoder::PixelFormat::L8) => {
+            image::GrayImage::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8)
+        }
+        Some(jpeg_decoder::PixelFormat::RGB24) => {
+            RgbImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8)
+        }
+        _ => None,
+    };
+    Ok(img)
+}
+
+// A still of an animated GIF, the last frame when it has fewer than `frame_index + 1`
+fn open_gif_frame(
+    decoder: GifDecoder<impl BufRead + Seek>,
+    frame_index: usize,
+    path: &Path,
+) -> image::ImageResult<DynamicImage> {
+    let mut frame_count = 0;
+    let mut still = None;
+    for frame in decoder.into_frames().take(frame_index + 1) {
+        still = Some(frame?.into_buffer());
+        frame_count += 1;
+    }
+    let Some(still) = still else {
+        return Err(image::ImageError::Decoding(DecodingError::new(
+            ImageFormat::Gif.into(),
+            "the GIF has no frames",
+        )));
+    };
+    if frame_count <= frame_index {
+        log::warn!(
+            "{} has only {frame_count} frames, using the last one instead of frame {frame_index}",
+            path.display()
+        );
+    }
+    Ok(DynamicImage::ImageRgba8(still))
+}
+
+// The largest middle part of `img` with the aspect ratio of `box_size`
+fn center_crop(img: &DynamicImage, (box_width, box_height): (u32, u32)) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    let box_aspect = box_width as f32 / box_height as f32;
+    if width as f32 / height as f32 > box_aspect {
+        let crop_width = ((height as f32 * box_aspect).round() as u32).clamp(1, width);
+        img.crop_imm((width - crop_width) / 2, 0, crop_width, height)
+    } else {
+        let crop_height = ((width as f32 / box_aspect).round() as u32).clamp(1, height);
+        img.crop_imm(0, (height - crop_height) / 2, width, crop_height)
+    }
+}
+
+// Blends the transparent parts of `image` with what lies below them once it is placed at
+// `offset`, so the photo and its rounded corners come out opaque
+fn composite_over(image: &mut RgbaImage, backdrop: &RgbaImage, (x_offset, y_offset): (u32, u32)) {
+    let (width, height) = backdrop.dimensions();
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let (backdrop_x, backdrop_y) = (x_offset + x, y_offset + y);
+        if pixel[3] < 255 && backdrop_x < width && backdrop_y < height {
+            *pixel = apply_mask(*backdrop.get_pixel(backdrop_x, backdrop_y), *pixel);
+        }
+    }
+}
+
+// Copies `image` onto `canvas` at `offset`, one destination row per task
+// Whatever sticks out of the canvas is cut off
+fn place_image(canvas: &mut RgbaImage, image: &RgbaImage, (x_offset, y_offset): (u32, u32)) {
+    let canvas_row_len = canvas.width() as usize * 4;
+    let image_row_len = image.width() as usize * 4;
+    if canvas_row_len == 0 || image_row_len == 0 {
+        return;
+    }
+    let x_start = (x_offset as usize * 4).min(canvas_row_len);
+    let copy_len = image_row_len.min(canvas_row_len - x_start);
+    canvas
+        .par_chunks_mut(canvas_row_len)
+        .skip(y_offset as usize)
+        .zip(image.par_chunks(image_row_len))
+        .for_each(|(canvas_row, image_row)| {
+            canvas_row[x_start..x_start + copy_len].copy_from_slice(&image_row[..copy_len])
+        });
+}
+
+// Fonts that are usually installed and cover far more characters than Playfair Display
+#[cfg(target_os = "linux")]
+const SYSTEM_FALLBACK_FONTS: &[&str] = &[
+    "/usr/share/fonts/truetype/dejavu/DejaVuSerif.ttf",
+    "/usr/share/fonts/TTF/DejaVuSerif.ttf",
+    "/usr/share/fonts/dejavu-serif-fonts/DejaVuSerif.ttf",
+    "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf",
+    "/usr/share/fonts/noto/NotoSerif-Regular.ttf",
+];
+#[cfg(windows)]
+const SYSTEM_FALLBACK_FONTS: &[&str] = &[
+    r"C:\Windows\Fonts\times.ttf",
+    r"C:\Windows\Fonts\segoeui.ttf",
+];
+#[cfg(target_os = "macos")]
+const SYSTEM_FALLBACK_FONTS: &[&str] = &[
+    "/System/Library/Fonts/Supplemental/Times New Roman.ttf",
+    "/Library/Fonts/Arial Unicode.ttf",
+];
+#[cfg(not(any(target_os = "linux", windows, target_os = "macos")))]
+const SYSTEM_FALLBACK_FONTS: &[&str] = &[];
+
+static SYSTEM_FALLBACK_FONT: LazyLock<Option<rusttype::Font<'static>>> = LazyLock::new(|| {
+    SYSTEM_FALLBACK_FONTS
+        .iter()
+        .find_map(|path| fs::read(path).ok().and_then(rusttype::Font::try_from_vec))
+});
+
+// The configured fallback font, or one of the system when there is none or it cannot be read
+fn load_fallback_font(font_path: Option<&Path>) -> Option<rusttype::Font<'static>> {
+    if let Some(font_path) = font_path {
+        match fs::read(font_path).map(rusttype::Font::try_from_vec) {
+            Ok(Some(font)) => return Some(font),
+            Ok(None) => log::warn!("{} is not a valid font", font_path.display()),
+            Err(err) => log::warn!("Cannot read fallback font {}: {err}", font_path.display()),
+        }
+    }
+    SYSTEM_FALLBACK_FONT.clone()
+}
+
+// Whether the caption can use the font, the render falls back to the embedded one otherwise
+pub fn check_font(font_path: &Path) -> io::Result<()> {
+    match rusttype::Font::try_from_vec(fs::read(font_path)?) {
+        Some(_) => Ok(()),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a TrueType or OpenType font",
+        )),
+    }
+}
+
+fn load_font(font_path: Option<&Path>) -> Result<rusttype::Font<'static>, RenderError> {
+    if let Some(font_path) = font_path {
+        match fs::read(font_path) {
+            Ok(font_data) => match rusttype::Font::try_from_vec(font_data) {
+                Some(font) => return Ok(font),
+                None => log::warn!(
+                    "{} is not a valid font, using the embedded one",
+                    font_path.display()
+                ),
+            },
+            Err(err) => log::warn!(
+                "Cannot read font {}: {err}, using the embedded one",
+                font_path.display()
+            ),
+        }
+    }
+    let font_data = include_bytes!(r"../PlayfairDisplay-Regular.ttf");
+    rusttype::Font::try_from_bytes(font_data).ok_or(RenderError::Font)
+}
+
+pub fn modify_image<P: AsRef<Path>, Q: AsRef<Path>>(
+    name: &str,
+    input_image_path: P,
+    output_image_path: Q,
+    config: &RenderConfig,
+) -> Result<(), RenderError> {
+    Renderer::default().render(name, input_image_path, output_image_path, config)
+}
+
+// Keeps the fonts and the canvas buffers between renders; `modify_image` starts from scratch
+#[derive(Default)]
+pub struct Renderer {
+    fonts: Option<LoadedFonts>,
+    backdrop: RgbaImage,
+    canvas: RgbaImage,
+}
+
+struct LoadedFonts {
+    font_path: Option<PathBuf>,
+    fallback_font_path: Option<PathBuf>,
+    fonts: caption::Fonts<'static>,
+}
+
+// The fonts are read again only when the configured paths change
+fn cached_fonts<'a>(
+    cache: &'a mut Option<LoadedFonts>,
+    font_path: Option<&Path>,
+    fallback_font_path: Option<&Path>,
+) -> Result<&'a caption::Fonts<'static>, RenderError> {
+    cache.take_if(|loaded| {
+        loaded.font_path.as_deref() != font_path
+            || loaded.fallback_font_path.as_deref() != fallback_font_path
+    });
+    let loaded = match cache {
+        Some(loaded) => loaded,
+        None => cache.insert(LoadedFonts {
+            font_path: font_path.map(Path::to_owned),
+            fallback_font_path: fallback_font_path.map(Path::to_owned),
+            fonts: caption::Fonts {
+                primary: load_font(font_path)?,
+                fallback: load_fallback_font(fallback_font_path),
+            },
+        }),
+    };
+    Ok(&loaded.fonts)
+}
+
+// Only a buffer of another size is allocated again, its old contents are overwritten anyway
+fn reuse_buffer(buffer: &mut RgbaImage, width: u32, height: u32) {
+    if buffer.dimensions() != (width, height) {
+        *buffer = RgbaImage::new(width, height);
+    }
+}
+
+impl Renderer {
+    pub fn render<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        name: &str,
+        input_image_path: P,
+        output_image_path: Q,
+        config: &RenderConfig,
+    ) -> Result<(), RenderError> {
+        let canvas = self.render_canvas(name, input_image_path.as_ref(), config)?;
+        save_canvas(canvas, output_image_path.as_ref(), config.jpeg_quality)
+    }
+
+    // The wallpaper for one photo, everything `render` does short of saving it
+    pub(crate) fn render_canvas(
+        &mut self,
+        name: &str,
+        input_image_path: &Path,
+        config: &RenderConfig,
+    ) -> Result<&RgbaImage, RenderError> {
+        let config = &config.with_scale_applied();
+        let &RenderConfig {
+            canvas_width,
+            canvas_height,
+            scale: _,
+            margin,
+            safe_area: _,
+            bottom_extra_margin,
+            fit_mode,
+            resize_filter,
+            min_photo_size,
+            max_photo_megapixels,
+            animation_frame,
+            photo_filter,
+            brightness,
+            contrast,
+            corner_radius,
+            text_size,
+            letter_spacing,
+            line_spacing,
+            background,
+            ref font_path,
+            ref fallback_font_path,
+            shadow,
+            border,
+            text_color,
+            text_outline,
+            caption_layout,
+            ref caption_template,
+            corner_blending,
+            corner_aa,
+            caption_gradient,
+            ref info_stamp,
+            stamp_position,
+            output_format: _,
+            jpeg_quality: _,
+        } = config;
+
+        let Renderer {
+            fonts,
+            backdrop,
+            canvas,
+        } = self;
+        let fonts = cached_fonts(fonts, font_path.as_deref(), fallback_font_path.as_deref())?;
+        let (background, default_text_color) = background.resolve_system_theme();
+
+        let filename = Path::new(name)
+            .file_name()
+            .and_then(|file_name| file_name.to_str())
+            .ok_or_else(|| RenderError::Caption(name.to_owned()))?;
+        let metadata = if PhotoMetadata::is_used_by(caption_template)
+            || info_stamp
+                .as_ref()
+                .is_some_and(|stamp| PhotoMetadata::is_used_by(&stamp.format))
+        {
+            read_metadata(input_image_path)
+        } else {
+            PhotoMetadata::default()
+        };
+        let caption = metadata
+            .fill(caption_template, TAKEN_FORMAT)
+            .replace("{name}", filename);
+        let missing_chars = fonts.missing_chars(&caption);
+        if !missing_chars.is_empty() {
+            log::warn!(
+                "No font has a glyph for {:?}, the caption {caption:?} shows boxes instead",
+                String::from_iter(missing_chars)
+            );
+        }
+        let scale = rusttype::Scale {
+            x: text_size as f32,
+            y: text_size as f32,
+        };
+        let line_height = (text_size as f32 * line_spacing).round() as u32;
+        // Relative to the font size, so it shrinks along with lines that are scaled down to fit
+        let spacing = if text_size > 0 {
+            letter_spacing / text_size as f32
+        } else {
+            0.
+        };
+
+        // Load the image
+        let img = open_oriented(
+            input_image_path,
+            animation_frame,
+            max_photo_megapixels,
+            (canvas_width, canvas_height),
+        )?;
+        let (orig_width, orig_height) = img.dimensions();
+        if orig_width.min(orig_height) < min_photo_size {
+            return Err(RenderError::TooSmall {
+                width: orig_width,
+                height: orig_height,
+                min_size: min_photo_size,
+            });
+        }
+
+        // A caption below a portrait photo shrinks it, beside it the photo can take the full height
+        let ((area_x, area_y), (area_width, _)) = config.usable_area()?;
+        let side_column_width = area_width.saturating_sub(2 * margin) / SIDE_CAPTION_WIDTH_DIVISOR;
+        let side_caption = caption_layout == CaptionLayout::Auto && side_column_width > 4 && {
+            let (box_width, box_height) = config.image_box()?;
+            (orig_width as f32 / orig_height as f32)
+                < box_width as f32 / box_height as f32 * SIDE_CAPTION_ASPECT_RATIO
+        };
+        let (caption_x, caption_width) = if side_caption {
+            (
+                area_x + area_width - margin - side_column_width,
+                side_column_width,
+            )
+        } else {
+            (area_x, area_width)
+        };
+
+        // Wrap the caption into lines that fit between the margins, or into the side column
+        let max_text_width = if side_caption {
+            side_column_width - 4
+        } else {
+            area_width.saturating_sub(2 * margin).saturating_sub(4)
+        } as f32;
+        let caption_lines: Vec<_> = caption::wrap_lines(
+            fonts,
+            scale,
+            &caption,
+            spacing,
+            max_text_width,
+            caption::MAX_CAPTION_LINES,
+        )
+        .into_iter()
+        .map(|line| {
+            let line_scale = caption::fitting_scale(fonts, scale, &line, spacing, max_text_width);
+            (line, line_scale)
+        })
+        .collect();
+        // The last line needs its full text size even when the lines overlap
+        let caption_height = match caption_lines.len() as u32 {
+            0 => 0,
+            lines => (lines - 1) * line_height + line_height.max(text_size),
+        };
+
+        let (image_box_width, image_box_height) = if side_caption {
+            // The column and a margin next to it come off the width, nothing off the height
+            RenderConfig {
+                canvas_width: canvas_width - side_column_width - margin,
+                bottom_extra_margin: 0,
+                ..config.clone()
+            }
+            .image_box()?
+        } else {
+            // The gap above the caption, its lines and the margin below it, or the minimum band
+            RenderConfig {
+                bottom_extra_margin: bottom_extra_margin.max(margin / 2 + caption_height),
+                ..config.clone()
+            }
+            .image_box()?
+        };
+
+        let img = match fit_mode {
+            FitMode::Contain => img,
+            FitMode::Cover => center_crop(&img, (image_box_width, image_box_height)),
+        };
+        let (orig_width, orig_height) = img.dimensions();
+
+        // Calculate the scaled image dimensions while maintaining aspect ratio
+        let scale_factor = f32::min(
+            image_box_width as f32 / orig_width as f32,
+            image_box_height as f32 / orig_height as f32,
+        );
+
+        let scaled_width = (orig_width as f32 * scale_factor) as u32;
+        let scaled_height = (orig_height as f32 * scale_factor) as u32;
+
+        // Resize the image, the decoded photo is converted in place where it can be
+        let mut resized_img = image::imageops::resize(
+            &img.into_rgba8(),
+            scaled_width,
+            scaled_height,
+            resize_filter.filter_type(),
+        );
+        // Before the backdrop, so a blurred copy of the photo gets the same look
+        filter::apply_filter(&mut resized_img, photo_filter, brightness, contrast);
+
+        // Opposite corners must not overlap, otherwise the later loop overwrites the earlier one
+        let corner_radius =
+            corner_radius.map(|radius| radius.min(scaled_width / 2).min(scaled_height / 2));
+
+        // Calculate the position of the image in its box, centered unless a sidecar says otherwise
+        let (image_x_offset, image_y_offset) = Layout::load_for(input_image_path).offset(
+            (area_x + margin, area_y + margin),
+            (image_box_width, image_box_height),
+            (scaled_width, scaled_height),
+        );
+
+        // Create the background canvas, the shadow is part of it so the corners blend into it
+        reuse_buffer(backdrop, canvas_width, canvas_height);
+        background.draw_backdrop(&resized_img, backdrop);
+        if let Some(shadow) = &shadow {
+            shadow::draw_shadow(
+                backdrop,
+                shadow,
+                (image_x_offset, image_y_offset),
+                (scaled_width, scaled_height),
+                corner_radius,
+            );
+        }
+        composite_over(&mut resized_img, backdrop, (image_x_offset, image_y_offset));
+        reuse_buffer(canvas, canvas_width, canvas_height);
+        canvas.copy_from_slice(backdrop);
+
+        // Place the resized image on the canvas
+        place_image(canvas, &resized_img, (image_x_offset, image_y_offset));
+
+        // Apply Corner radius
+        for corner in Corner::ALL {
+            let radius = corner_radius.get(corner);
+            if radius == 0 {
+                continue;
+            }
+            let corner_mask = corners::cached_corner_mask(radius, corner_aa);
+            corners::round_corner_over(
+                canvas,
+                backdrop,
+                &resized_img,
+                (image_x_offset, image_y_offset),
+                &corner_mask,
+                corner,
+                corner_blending,
+            );
+        }
+
+        if let Some(border) = &border {
+            border::draw_border(
+                canvas,
+                border,
+                (image_x_offset, image_y_offset),
+                (scaled_width, scaled_height),
+                corner_radius,
+            );
+        }
+
+        // Place each caption line centered below the image, or beside its middle
+        let caption_top = if side_caption {
+            (image_y_offset + scaled_height / 2).saturating_sub(caption_height / 2)
+        } else {
+            image_y_offset + scaled_height + margin / 2
+        };
+        let strip_columns = if side_caption {
+            caption_x.saturating_sub(margin / 4)..caption_x + caption_width + margin / 4
+        } else {
+            0..canvas_width
+        };
+        let strip_top = caption_top.saturating_sub(margin / 4);
+        match caption_gradient {
+            // Below the photo the gradient runs down to the edge of the screen
+            Some(colors) if !side_caption => {
+                Background::draw_gradient(canvas, strip_columns, strip_top..canvas_height, colors)
+            }
+            Some(colors) => Background::draw_gradient(
+                canvas,
+                strip_columns,
+                strip_top..caption_top + caption_height + margin / 4,
+                colors,
+            ),
+            None => background.prepare_caption_strip(
+                canvas,
+                strip_columns,
+                strip_top..caption_top + caption_height + margin / 4,
+            ),
+        }
+        let caption_color = match text_color {
+            Some(TextColor::Fixed(color)) => color,
+            // Measured on the strip the caption is drawn on, before the text covers it
+            Some(TextColor::Auto) => caption::auto_text_color(
+                canvas,
+                caption_x..caption_x + caption_width,
+                caption_top..caption_top + caption_height,
+            ),
+            None => default_text_color,
+        };
+        for (line_idx, (line, line_scale)) in caption_lines.iter().enumerate() {
+            let line_extent =
+                caption::line_extent(fonts, *line_scale, line, spacing, caption_width);
+            let mut target = image::imageops::crop(
+                canvas,
+                caption_x + caption_width.saturating_sub(line_extent) / 2,
+                caption_top + line_idx as u32 * line_height,
+                line_extent,
+                text_size,
+            );
+            let glyphs = caption::layout(
+                fonts,
+                *line_scale,
+                line,
+                spacing,
+                caption::centered_baseline(&fonts.primary, *line_scale, text_size),
+            );
+            caption::draw_line(&glyphs, &mut *target, caption_color, text_outline.as_ref());
+        }
+
+        if let Some(stamp) = info_stamp
+            && stamp.text_size > 0
+        {
+            let text = stamp.text(name, stamp_position, Local::now().date_naive(), &metadata);
+            let inset = margin / 4;
+            let stamp_scale = caption::fitting_scale(
+                fonts,
+                rusttype::Scale::uniform(stamp.text_size as f32),
+                &text,
+                0.,
+                canvas_width.saturating_sub(2 * inset + 4) as f32,
+            );
+            let width = caption::line_extent(fonts, stamp_scale, &text, 0., canvas_width);
+            match stamp.place(
+                (width, stamp.text_size),
+                (canvas_width, canvas_height),
+                (
+                    (image_x_offset, image_y_offset),
+                    (scaled_width, scaled_height),
+                ),
+                inset,
+            ) {
+                Some((x, y)) => {
+                    // The stamp may sit on the photo, far from the caption
+                    let stamp_color = match text_color {
+                        Some(TextColor::Auto) => {
+                            caption::auto_text_color(canvas, x..x + width, y..y + stamp.text_size)
+                        }
+                        _ => caption_color,
+                    };
+                    let mut target = image::imageops::crop(canvas, x, y, width, stamp.text_size);
+                    let glyphs = caption::layout(
+                        fonts,
+                        stamp_scale,
+                        &text,
+                        0.,
+                        caption::centered_baseline(&fonts.primary, stamp_scale, stamp.text_size),
+                    );
+                    caption::draw_line(&glyphs, &mut *target, stamp_color, text_outline.as_ref());
+                }
+                None => log::warn!("No room for the info stamp next to {filename}"),
+            }
+        }
+
+        Ok(canvas)
+    }
+}
+
+// The format follows the extension; JPEG has no alpha channel and gets the configured quality
+pub(crate) fn save_canvas(
+    canvas: &RgbaImage,
+    path: &Path,
+    jpeg_quality: u8,
+) -> Result<(), RenderError> {
+    if let Ok(ImageFormat::Jpeg) = ImageFormat::from_path(path) {
+        let file = BufWriter::new(File::create(path).map_err(image::ImageError::IoError)?);
+        let rgb: RgbImage = canvas.convert();
+        JpegEncoder::new_with_quality(file, jpeg_quality).encode_image(&rgb)?;
+    } else {
+        canvas.save(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use image::Rgba;
+
+    use super::*;
+
+    const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+    const BLACK: Rgba<u8> = Rgba([0, 0, 0, 255]);
+
+    // A tenth of the default wallpaper, with all sizes scaled along
+    fn small_config() -> RenderConfig {
+        RenderConfig::default().scaled_to(256, 153)
+    }
+
+    // Photos are read from disk, so every generated one gets a file of its own
+    fn render(photo: &RgbaImage, config: &RenderConfig) -> RgbaImage {
+        static PHOTO_COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "flower-service-render-{}-{}.png",
+            std::process::id(),
+            PHOTO_COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        photo.save(&path).unwrap();
+        let canvas = Renderer::default()
+            .render_canvas("Rosa canina", &path, config)
+            .cloned();
+        let _ = fs::remove_file(&path);
+        canvas.unwrap()
+    }
+
+    // Left, top, right and bottom of the matching pixels, all inclusive
+    fn bounds(canvas: &RgbaImage, matches: impl Fn(Rgba<u8>) -> bool) -> (u32, u32, u32, u32) {
+        canvas
+            .enumerate_pixels()
+            .filter(|(_, _, pixel)| matches(**pixel))
+            .fold(
+                (u32::MAX, u32::MAX, 0, 0),
+                |(left, top, right, bottom), (x, y, _)| {
+                    (left.min(x), top.min(y), right.max(x), bottom.max(y))
+                },
+            )
+    }
+
+    fn is_red(Rgba([r, g, b, _]): Rgba<u8>) -> bool {
+        r > 200 && g < 60 && b < 60
+    }
+
+    #[test]
+    fn corners_blend_into_a_black_background() {
+        let config = RenderConfig {
+            background: Background::Solid(BLACK),
+            ..small_config()
+        };
+        let canvas = render(&RgbaImage::from_pixel(200, 100, RED), &config);
+        assert_eq!(*canvas.get_pixel(0, 0), BLACK);
+        let (left, top, right, bottom) = bounds(&canvas, is_red);
+        for (x, y) in [(left, top), (right, top), (left, bottom), (right, bottom)] {
+            let Rgba([r, g, b, _]) = *canvas.get_pixel(x, y);
+            assert!(
+                r < 64 && g == 0 && b == 0,
+                "corner ({x}, {y}) is {r} {g} {b}"
+            );
+        }
+    }
+
+    #[test]
+    fn zero_byte_photo_is_an_error() {
+        let path =
+            std::env::temp_dir().join(format!("flower-service-empty-{}.jpg", std::process::id()));
+        fs::write(&path, b"").unwrap();
+        let output = path.with_extension("png");
+        let result = modify_image("Empty", &path, &output, &small_config());
+        let _ = fs::remove_file(&path);
+        assert!(matches!(result, Err(RenderError::Image(_))), "{result:?}");
+        assert!(!output.exists());
+    }
+
+    // A JPEG with an APP1 segment holding nothing but the EXIF orientation
+    fn jpeg_with_orientation(photo: &RgbImage, orientation: u16) -> Vec<u8> {
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, 95)
+            .encode_image(photo)
+            .unwrap();
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01".to_vec();
+        exif.extend(orientation.to_be_bytes());
+        exif.extend([0; 6]);
+        let mut segment = vec![0xff, 0xe1];
+        segment.extend((exif.len() as u16 + 2).to_be_bytes());
+        segment.extend(exif);
+        jpeg.splice(2..2, segment);
+        jpeg
+    }
+
+    #[test]
+    fn orientation_6_turns_the_photo_upright() {
+        // Red on the left and blue on the right, which comes out on top and at the bottom
+        let photo = RgbImage::from_fn(40, 20, |x, _| {
+            if x < 20 {
+                image::Rgb([255, 0, 0])
+            } else {
+                image::Rgb([0, 0, 255])
+            }
+        });
+        let path = std::env::temp_dir().join(format!(
+            "flower-service-orientation-{}.jpg",
+            std::process::id()
+        ));
+        fs::write(&path, jpeg_with_orientation(&photo, 6)).unwrap();
+        let upright = open_oriented(&path, 0, 0, (256, 153));
+        let _ = fs::remove_file(&path);
+        let upright = upright.unwrap().into_rgb8();
+        assert_eq!(upright.dimensions(), (20, 40));
+        let image::Rgb([r, _, b]) = *upright.get_pixel(10, 5);
+        assert!(r > 200 && b < 60, "top is {r} {b}");
+        let image::Rgb([r, _, b]) = *upright.get_pixel(10, 35);
+        assert!(r < 60 && b > 200, "bottom is {r} {b}");
+    }
+
+    // The copy before it went parallel, cutting off what sticks out of the canvas
+    fn placed_per_pixel(
+        canvas: &RgbaImage,
+        image: &RgbaImage,
+        (x_offset, y_offset): (u32, u32),
+    ) -> RgbaImage {
+        let mut canvas = canvas.clone();
+        for (x, y, pixel) in image.enumerate_pixels() {
+            if x_offset + x < canvas.width() && y_offset + y < canvas.height() {
+                canvas.put_pixel(x_offset + x, y_offset + y, *pixel);
+            }
+        }
+        canvas
+    }
+
+    #[test]
+    fn place_image_matches_the_serial_copy() {
+        let canvas = RgbaImage::from_pixel(64, 48, DEFAULT_BACKGROUND);
+        let image = RgbaImage::from_fn(30, 20, |x, y| Rgba([x as u8 * 8, y as u8 * 12, 77, 255]));
+        for offset in [(0, 0), (17, 11), (40, 35), (63, 47), (70, 10)] {
+            let mut placed = canvas.clone();
+            place_image(&mut placed, &image, offset);
+            assert_eq!(
+                placed.as_raw(),
+                placed_per_pixel(&canvas, &image, offset).as_raw(),
+                "at {offset:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn webp_photo_is_decoded() {
+        let photo = RgbaImage::from_fn(12, 8, |x, _| if x < 6 { RED } else { BLACK });
+        let path =
+            std::env::temp_dir().join(format!("flower-service-photo-{}.webp", std::process::id()));
+        photo.save(&path).unwrap();
+        let decoded = open_oriented(&path, 0, 0, (256, 153));
+        let _ = fs::remove_file(&path);
+        assert_eq!(decoded.unwrap().into_rgba8().as_raw(), photo.as_raw());
+    }
+
+    #[test]
+    fn linear_blend_mixes_in_linear_light() {
+        let gray = |level: u8, alpha: u8| Rgba([level, level, level, alpha]);
+        // (mask level, alpha, input level, linear result, sRGB result)
+        let cases = [
+            (255, 128, 0, 188, 128),
+            (233, 128, 0, 171, 116),
+            (0, 64, 255, 224, 191),
+            (200, 191, 40, 177, 159),
+        ];
+        for (mask, alpha, input, linear, srgb) in cases {
+            assert_eq!(
+                blend_linear(gray(input, 255), gray(mask, alpha)),
+                gray(linear, 255),
+                "{mask} at {alpha} over {input}"
+            );
+            assert_eq!(
+                apply_mask(gray(input, 255), gray(mask, alpha)),
+                gray(srgb, 255)
+            );
+        }
+        assert_eq!(BlendMode::default(), BlendMode::Srgb);
+    }
+
+    #[test]
+    fn linear_blend_keeps_the_ends() {
+        let input = Rgba([10, 120, 250, 255]);
+        let mask = Rgba([200, 30, 90, 255]);
+        assert_eq!(blend_linear(input, Rgba([200, 30, 90, 0])), input);
+        assert_eq!(blend_linear(input, mask), mask);
+    }
+
+    #[test]
+    fn margins_larger_than_the_canvas_are_a_config_error() {
+        let photo = RgbaImage::from_pixel(40, 30, RED);
+        let path =
+            std::env::temp_dir().join(format!("flower-service-margins-{}.png", std::process::id()));
+        photo.save(&path).unwrap();
+        let configs = [
+            RenderConfig {
+                margin: 100,
+                ..small_config()
+            },
+            RenderConfig {
+                bottom_extra_margin: 200,
+                ..small_config()
+            },
+            RenderConfig {
+                margin: 200,
+                ..small_config()
+            },
+        ];
+        for config in configs {
+            assert!(matches!(config.validate(), Err(RenderError::Config(_))));
+            let mut renderer = Renderer::default();
+            let result = renderer.render_canvas("Rosa canina", &path, &config);
+            assert!(matches!(result, Err(RenderError::Config(_))));
+        }
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn border_runs_along_the_photo_edge() {
+        const BLUE: Rgba<u8> = Rgba([0, 0, 255, 255]);
+        let config = RenderConfig {
+            background: Background::Solid(BLACK),
+            border: Some(BorderConfig {
+                width: 2,
+                color: BLUE,
+            }),
+            ..small_config()
+        };
+        let canvas = render(&RgbaImage::from_pixel(200, 100, RED), &config);
+        let (left, top, right, bottom) = bounds(&canvas, |pixel| pixel == BLUE);
+        let (middle_x, middle_y) = ((left + right) / 2, (top + bottom) / 2);
+        for (x, y) in [
+            (middle_x, top),
+            (middle_x, top + 1),
+            (middle_x, bottom),
+            (left, middle_y),
+            (right - 1, middle_y),
+        ] {
+            assert_eq!(*canvas.get_pixel(x, y), BLUE, "edge ({x}, {y})");
+        }
+        assert!(is_red(*canvas.get_pixel(middle_x, top + 3)));
+        assert!(is_red(*canvas.get_pixel(middle_x, middle_y)));
+        // The frame is rounded off with the corners instead of squaring them
+        for (x, y) in [(left, top), (right, top), (left, bottom), (right, bottom)] {
+            assert_eq!(*canvas.get_pixel(x, y), BLACK, "corner ({x}, {y})");
+        }
+    }
+
+    #[test]
+    fn scale_doubles_the_text_band() {
+        // Taller than its box, so the photo reaches down to the band
+        let photo = RgbaImage::from_pixel(100, 400, RED);
+        let band = |scale: f32| {
+            let config = RenderConfig {
+                scale,
+                background: Background::Solid(BLACK),
+                text_color: Some(TextColor::Fixed(Rgba([255, 255, 255, 255]))),
+                ..RenderConfig::default().scaled_to(640, 360)
+            };
+            let canvas = render(&photo, &config);
+            let (_, _, _, photo_bottom) = bounds(&canvas, is_red);
+            let (_, text_top, _, text_bottom) =
+                bounds(&canvas, |Rgba([r, g, b, _])| r > 128 && g > 128 && b > 128);
+            assert!(text_top > photo_bottom);
+            (
+                canvas.height() - 1 - photo_bottom,
+                text_bottom - text_top + 1,
+            )
+        };
+        let (band_height, text_height) = band(1.);
+        let (scaled_band_height, scaled_text_height) = band(2.);
+        for (single, double) in [
+            (band_height, scaled_band_height),
+            (text_height, scaled_text_height),
+        ] {
+            let ratio = double as f32 / single as f32;
+            assert!((1.8..=2.2).contains(&ratio), "{single} to {double}");
+        }
+    }
+
+    #[test]
+    fn cover_trims_the_sides_of_a_wide_photo() {
+        let cropped = center_crop(&DynamicImage::new_rgba8(400, 100), (200, 100));
+        assert_eq!(cropped.dimensions(), (200, 100));
+
+        // Red in the middle, green at both ends
+        let photo = RgbaImage::from_fn(400, 100, |x, _| {
+            if (50..350).contains(&x) {
+                RED
+            } else {
+                Rgba([0, 255, 0, 255])
+            }
+        });
+        let is_green = |Rgba([r, g, b, _]): Rgba<u8>| r < 60 && g > 200 && b < 60;
+        let contained = render(&photo, &small_config());
+        assert!(contained.pixels().any(|pixel| is_green(*pixel)));
+
+        let cover = RenderConfig {
+            fit_mode: FitMode::Cover,
+            ..small_config()
+        };
+        let covered = render(&photo, &cover);
+        assert!(!covered.pixels().any(|pixel| is_green(*pixel)));
+        // A tall photo fills the same box, give or take the rounding of the crop
+        let tall = render(&RgbaImage::from_pixel(100, 400, RED), &cover);
+        let (wide_box, tall_box) = (bounds(&covered, is_red), bounds(&tall, is_red));
+        for (wide, tall) in [
+            (wide_box.0, tall_box.0),
+            (wide_box.1, tall_box.1),
+            (wide_box.2, tall_box.2),
+            (wide_box.3, tall_box.3),
+        ] {
+            assert!(wide.abs_diff(tall) <= 1, "{wide_box:?} and {tall_box:?}");
+        }
+    }
+
+    #[test]
+    fn transparent_photo_comes_out_opaque() {
+        let photo = RgbaImage::from_fn(120, 80, |x, y| {
+            let alpha = if (x / 10 + y / 10) % 2 == 0 { 0 } else { 128 };
+            Rgba([255, 0, 0, alpha])
+        });
+        let canvas = render(&photo, &small_config());
+        let partial = canvas.pixels().filter(|pixel| pixel[3] < 255).count();
+        assert_eq!(partial, 0);
+    }
+
+    #[test]
+    fn template_text_widens_the_centered_caption() {
+        let photo = RgbaImage::from_pixel(100, 400, RED);
+        let caption_bounds = |template: &str| {
+            let config = RenderConfig {
+                background: Background::Solid(BLACK),
+                text_color: Some(TextColor::Fixed(Rgba([255, 255, 255, 255]))),
+                caption_template: template.to_owned(),
+                ..RenderConfig::default().scaled_to(1280, 720)
+            };
+            let canvas = render(&photo, &config);
+            let (_, _, _, photo_bottom) = bounds(&canvas, is_red);
+            let (left, top, right, _) =
+                bounds(&canvas, |Rgba([r, g, b, _])| r > 64 && g > 64 && b > 64);
+            assert!(top > photo_bottom);
+            (left, right, canvas.width())
+        };
+        let (left, right, width) = caption_bounds("{name}");
+        let (decorated_left, decorated_right, _) = caption_bounds("— {name} —");
+        assert!(decorated_left < left && decorated_right > right);
+        // Still centered, give or take the side bearings of the glyphs
+        let off_center = |left: u32, right: u32| (left + right).abs_diff(width - 1);
+        assert!(off_center(decorated_left, decorated_right) <= 4);
+    }
+
+    #[test]
+    fn gif_still_is_the_configured_frame() {
+        use image::codecs::gif::GifEncoder;
+        use image::{Delay, Frame};
+
+        let path =
+            std::env::temp_dir().join(format!("flower-service-frames-{}.gif", std::process::id()));
+        let colors = [RED, Rgba([0, 255, 0, 255]), Rgba([0, 0, 255, 255])];
+        let mut encoder = GifEncoder::new(File::create(&path).unwrap());
+        encoder
+            .encode_frames(colors.map(|color| {
+                Frame::from_parts(
+                    RgbaImage::from_pixel(8, 8, color),
+                    0,
+                    0,
+                    Delay::from_numer_denom_ms(100, 1),
+                )
+            }))
+            .unwrap();
+        drop(encoder);
+
+        let still_color = |frame| {
+            let still = open_oriented(&path, frame, 16, (256, 153)).map(|still| still.to_rgba8());
+            still.map(|still| *still.get_pixel(4, 4))
+        };
+        let stills = [0, 1, 5].map(still_color);
+        let _ = fs::remove_file(&path);
+        // Past the end it is the last frame
+        assert_eq!(
+            stills.map(Result::unwrap),
+            [colors[0], colors[1], colors[2]]
+        );
+    }
+
+    #[test]
+    fn grayscale_photo_has_equal_channels() {
+        let photo = RgbaImage::from_fn(200, 100, |x, y| Rgba([255, x as u8, y as u8 * 2, 255]));
+        let config = RenderConfig {
+            background: Background::Solid(BLACK),
+            ..small_config()
+        };
+        // Only the photo has a red channel that stands out
+        let (left, top, right, bottom) = bounds(&render(&photo, &config), |Rgba([r, g, _, _])| {
+            r > 200 && g < 200
+        });
+        let grayscale = render(
+            &photo,
+            &RenderConfig {
+                photo_filter: PhotoFilter::Grayscale,
+                ..config
+            },
+        );
+        for y in top..=bottom {
+            for x in left..=right {
+                let Rgba([r, g, b, _]) = *grayscale.get_pixel(x, y);
+                assert!(r == g && g == b, "({x}, {y}) is {r} {g} {b}");
+            }
+        }
+    }
+
+    #[test]
+    fn two_caption_lines_reserve_more_space() {
+        let photo = RgbaImage::from_pixel(100, 400, RED);
+        let band = |template: &str| {
+            let config = RenderConfig {
+                text_size: 40,
+                caption_template: template.to_owned(),
+                ..RenderConfig::default().scaled_to(640, 360)
+            };
+            let canvas = render(&photo, &config);
+            canvas.height() - 1 - bounds(&canvas, is_red).3
+        };
+        let one_line = band("{name}");
+        let two_lines = band("{name}, also known as the dog rose or the briar rose");
+        assert!(one_line > 40, "{one_line}");
+        assert!(two_lines >= one_line + 40, "{one_line} and {two_lines}");
+    }
+
+    #[test]
+    fn large_jpeg_is_decoded_at_a_fraction() {
+        assert_eq!(jpeg_scale_divisor((6000, 4000), (2560, 1440)), Some(2));
+        assert_eq!(jpeg_scale_divisor((8000, 6000), (1000, 700)), Some(8));
+        assert_eq!(jpeg_scale_divisor((4000, 2600), (2560, 1440)), None);
+        assert_eq!(decode_limits(0).max_alloc, Limits::default().max_alloc);
+
+        // 3.6 megapixels against a limit of one
+        let photo = RgbImage::from_pixel(2400, 1500, image::Rgb([255, 0, 0]));
+        let path =
+            std::env::temp_dir().join(format!("flower-service-large-{}.jpg", std::process::id()));
+        photo.save(&path).unwrap();
+        let png_path = path.with_extension("png");
+        photo.save(&png_path).unwrap();
+        let jpeg = open_oriented(&path, 0, 1, (256, 153));
+        let png = open_oriented(&png_path, 0, 1, (256, 153));
+        let unlimited = open_oriented(&path, 0, 0, (256, 153));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&png_path);
+
+        let jpeg = jpeg.unwrap();
+        assert_eq!(jpeg.dimensions(), (300, 188));
+        assert!(is_red(*jpeg.to_rgba8().get_pixel(150, 94)));
+        assert!(matches!(png, Err(RenderError::TooLarge { .. })), "{png:?}");
+        assert_eq!(unlimited.unwrap().dimensions(), (2400, 1500));
+    }
+
+    #[test]
+    fn nearest_and_lanczos_scale_a_gradient_differently() {
+        let photo = RgbaImage::from_fn(600, 300, |x, y| {
+            Rgba([
+                (x * 255 / 599) as u8,
+                (y * 255 / 299) as u8,
+                ((x + y) % 7 * 36) as u8,
+                255,
+            ])
+        });
+        let resized = |resize_filter| {
+            render(
+                &photo,
+                &RenderConfig {
+                    resize_filter,
+                    ..small_config()
+                },
+            )
+        };
+        let nearest = resized(ResizeFilter::Nearest);
+        let lanczos = resized(ResizeFilter::Lanczos3);
+        assert_eq!(nearest.dimensions(), lanczos.dimensions());
+        let differing = nearest
+            .pixels()
+            .zip(lanczos.pixels())
+            .filter(|(nearest, lanczos)| nearest != lanczos)
+            .count();
+        assert!(differing > 1000, "only {differing} pixels differ");
+    }
+
+    #[test]
+    fn line_spacing_spreads_the_caption_lines() {
+        let photo = RgbaImage::from_pixel(400, 100, RED);
+        let inked_height = |line_spacing| {
+            let config = RenderConfig {
+                background: Background::Solid(BLACK),
+                text_color: Some(TextColor::Fixed(Rgba([255, 255, 255, 255]))),
+                text_size: 40,
+                line_spacing,
+                caption_template: "{name}, the dog rose, is a wild climbing rose that grows in \
+                                   the hedges and woods of Europe, Africa and Asia"
+                    .to_owned(),
+                ..RenderConfig::default().scaled_to(640, 360)
+            };
+            let canvas = render(&photo, &config);
+            let (_, top, _, bottom) =
+                bounds(&canvas, |Rgba([r, g, b, _])| r > 64 && g > 64 && b > 64);
+            bottom - top + 1
+        };
+        let tight = inked_height(1.);
+        let loose = inked_height(1.5);
+        // Two gaps between three lines, each 20 pixels wider
+        assert!(loose.abs_diff(tight + 40) <= 2, "{tight} and {loose}");
+    }
+}
@@ -0,0 +1,931 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{Local, NaiveDate};
+use rand::seq::SliceRandom as _;
+
+use crate::{
+    Config, HistoryEntry, Monitor, Montage, MontageConfig, RenderConfig, RenderError, Renderer,
+    SelectionStrategy, ServiceError, State, WallpaperSetter, append_history, caption_for,
+    is_blocked, load_blocklist, load_names, load_weights, modify_image, set_lock_screen,
+    store_image_list, store_permutation, theme_of_week, to_path, weekly_attempt, weekly_position,
+    weights::weight_for,
+};
+
+// The flowers in the order they are shown and how far the service got, with everything a change
+// needs to render the next ones
+pub struct Rotation {
+    image_file_names: Vec<String>,
+    pub permutation: Vec<usize>,
+    pub strategy: SelectionStrategy,
+    pub state: State,
+    names: HashMap<String, String>,
+    weights: HashMap<String, u32>,
+    // Stay in the permutation, so unblocking puts them back at their old place
+    blocklist: HashSet<String>,
+    // Images that failed to render since the library was last listed
+    unreadable: HashSet<usize>,
+    // Nothing is rendered or written, the decisions are only logged
+    pub dry_run: bool,
+    montage: Option<MontageConfig>,
+    unique_output_names: bool,
+    // The day and the number of its flowers taken so far, for `SelectionStrategy::WeeklySeeded`
+    weekly_taken: (NaiveDate, usize),
+    renderer: Renderer,
+}
+
+impl Rotation {
+    // The captions, weights and blocklist are read from the base path
+    pub fn new(
+        image_file_names: Vec<String>,
+        permutation: Vec<usize>,
+        state: State,
+        config: &Config,
+        dry_run: bool,
+    ) -> Self {
+        let weekly_taken = weekly_taken_today(&state, permutation.len());
+        Self {
+            image_file_names,
+            permutation,
+            strategy: config.selection,
+            state,
+            names: load_names(&to_path("names.json")),
+            weights: load_weights(&to_path("weights.txt")),
+            blocklist: load_blocklist(&to_path("blocklist.txt")),
+            unreadable: HashSet::new(),
+            dry_run,
+            montage: config.montage,
+            unique_output_names: config.unique_output_names,
+            weekly_taken,
+            renderer: Renderer::default(),
+        }
+    }
+
+    pub fn skip(&mut self, count: u64) {
+        let len = self.permutation.len() as u64;
+        if len > 0 {
+            self.state.index = ((self.state.index as u64 + count % len) % len) as usize;
+        }
+    }
+
+    // Back by `count` shown flowers, not positions, so the next change does not land on one that
+    // is passed over and show the current flower again
+    pub fn rewind(&mut self, count: usize) {
+        if let Some(position) = self.shown_position(count) {
+            self.state.index = position;
+        }
+    }
+
+    // Position of the `count`th flower before the index that `render_next` did not pass over,
+    // going around the rotation when it has fewer. `None` when there is nothing to go back to.
+    fn shown_position(&self, count: usize) -> Option<usize> {
+        let len = self.permutation.len();
+        let is_shown = |position: usize| {
+            let image_idx = self.permutation[position];
+            !self.unreadable.contains(&image_idx)
+                && self
+                    .image_file_names
+                    .get(image_idx)
+                    .is_some_and(|file_name| !is_blocked(file_name, &self.blocklist))
+        };
+        let shown_count = (0..len).filter(|&position| is_shown(position)).count();
+        let mut remaining = match count % shown_count.max(1) {
+            0 if count == 0 || shown_count == 0 => return None,
+            0 => shown_count,
+            remaining => remaining,
+        };
+        let mut position = self.state.index % len;
+        loop {
+            position = (position + len - 1) % len;
+            if is_shown(position) {
+                remaining -= 1;
+                if remaining == 0 {
+                    return Some(position);
+                }
+            }
+        }
+    }
+
+    // Keeps the order of known flowers when images are added or removed; new ones go at the end
+    pub fn update_library(&mut self, image_file_names: Vec<String>) {
+        let new_indices: HashMap<&str, usize> = image_file_names
+            .iter()
+            .enumerate()
+            .map(|(image_idx, name)| (name.as_str(), image_idx))
+            .collect();
+        let remap = |image_idx: usize| {
+            self.image_file_names
+                .get(image_idx)
+                .and_then(|name| new_indices.get(name.as_str()).copied())
+        };
+        let mut permutation: Vec<usize> = self
+            .permutation
+            .iter()
+            .filter_map(|&idx| remap(idx))
+            .collect();
+        let seen = self
+            .state
+            .seen
+            .iter()
+            .filter_map(|&idx| remap(idx))
+            .collect();
+        let pinned = self.state.pinned.and_then(remap);
+
+        let known: HashSet<usize> = permutation.iter().copied().collect();
+        let mut added: Vec<usize> = (0..image_file_names.len())
+            .filter(|image_idx| !known.contains(image_idx))
+            .collect();
+        added.shuffle(&mut rand::thread_rng());
+        permutation.extend(added);
+
+        self.image_file_names = image_file_names;
+        self.permutation = permutation;
+        self.state.seen = seen;
+        self.state.pinned = pinned;
+        self.unreadable.clear();
+        if !self.permutation.is_empty() {
+            self.state.index %= self.permutation.len();
+        }
+    }
+
+    // Stores everything `update_library` touches, so the next start picks it up
+    pub fn store_library(&self) {
+        if self.dry_run {
+            return;
+        }
+        if let Err(err) = store_permutation(&self.permutation) {
+            log::error!("Failed to store the extended permutation: {err}");
+        }
+        if let Err(err) = store_image_list(&self.image_file_names) {
+            log::error!("Failed to store the image list: {err}");
+        }
+        if let Err(err) = self.state.store() {
+            log::error!("Failed to store state: {err}");
+        }
+    }
+
+    // The flower right before the index, which is the one shown since the last change
+    pub fn current_caption(&self) -> Option<String> {
+        self.shown_file_name(0)
+            .map(|file_name| caption_for(file_name, &self.names))
+    }
+
+    // The settings that the rotation keeps a copy of
+    pub fn apply_settings(&mut self, config: &Config) {
+        self.strategy = config.selection;
+        self.montage = config.montage;
+        self.unique_output_names = config.unique_output_names;
+    }
+
+    // A pinned flower is shown alone, even in a montage
+    pub fn cells_per_wallpaper(&self) -> usize {
+        match self.montage {
+            // Only a grid built in code can be too large to count, config.toml rejects one
+            Some(montage) if self.state.pinned.is_none() => montage.cells().unwrap_or(1),
+            _ => 1,
+        }
+    }
+
+    // The flower shown `back` changes before the most recent one
+    pub fn shown_file_name(&self, back: usize) -> Option<&str> {
+        if let Some(pinned) = self.state.pinned
+            && let Some(file_name) = self.image_file_names.get(pinned)
+        {
+            return Some(file_name);
+        }
+        let image_idx = self.permutation[self.shown_position(back + 1)?];
+        self.image_file_names.get(image_idx).map(String::as_str)
+    }
+
+    // 1-based place of the image in the permutation and its length, for the info stamp
+    fn stamp_position(&self, image_idx: usize) -> Option<(usize, usize)> {
+        let position = self.permutation.iter().position(|&idx| idx == image_idx)?;
+        Some((position + 1, self.permutation.len()))
+    }
+
+    // Unreadable images are skipped, so the index ends up just past the flower that was rendered.
+    // Returns the caption of the rendered flower.
+    fn render_next(
+        &mut self,
+        output_file_path: &Path,
+        config: &RenderConfig,
+        strategy: SelectionStrategy,
+    ) -> Result<String, ServiceError> {
+        if let Some(pinned) = self.state.pinned {
+            // The index stays where it is, so unpinning continues the rotation from there
+            if pinned < self.image_file_names.len() {
+                return Ok(self.render_image(pinned, output_file_path, config)?);
+            }
+            log::warn!("The pinned flower {pinned} no longer exists, unpinning it");
+            self.state.pinned = None;
+        }
+        let theme = match strategy {
+            SelectionStrategy::Theme => {
+                theme_of_week(&self.image_file_names, Local::now().date_naive()).map(str::to_owned)
+            }
+            _ => None,
+        };
+        if let Some(theme) = &theme {
+            log::info!("Showing the flowers of {theme} this week");
+        }
+        if let Some(montage) = self.montage {
+            return self.render_montage(
+                output_file_path,
+                config,
+                strategy,
+                theme.as_deref(),
+                montage,
+            );
+        }
+        for _ in 0..self.permutation.len() {
+            let image_idx = self.next_image(strategy, theme.as_deref());
+            let current_file_name = &self.image_file_names[image_idx];
+            if is_blocked(current_file_name, &self.blocklist) {
+                log::info!("Skipping {current_file_name}, it is in blocklist.txt");
+                continue;
+            }
+            match self.render_image(image_idx, output_file_path, config) {
+                Ok(caption) => return Ok(caption),
+                Err(
+                    err @ (RenderError::Image(_)
+                    | RenderError::TooSmall { .. }
+                    | RenderError::TooLarge { .. }),
+                ) => {
+                    log::warn!("Skipping {}: {err}", self.image_file_names[image_idx]);
+                    self.unreadable.insert(image_idx);
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Err("None of the images in wiki_flowers could be rendered".into())
+    }
+
+    // Fills a montage with the next flowers that render. Returns their captions.
+    fn render_montage(
+        &mut self,
+        output_file_path: &Path,
+        config: &RenderConfig,
+        strategy: SelectionStrategy,
+        theme: Option<&str>,
+        montage: MontageConfig,
+    ) -> Result<String, ServiceError> {
+        let mut sheet = Montage::new(config, montage)?;
+        let mut placed = Vec::new();
+        // Once around the rotation at most, a small library repeats no flower
+        for _ in 0..self.permutation.len() {
+            if Some(placed.len()) == montage.cells() {
+                break;
+            }
+            let image_idx = self.next_image(strategy, theme);
+            let file_name = &self.image_file_names[image_idx];
+            if is_blocked(file_name, &self.blocklist) {
+                log::info!("Skipping {file_name}, it is in blocklist.txt");
+                continue;
+            }
+            let caption = caption_for(file_name, &self.names);
+            if self.dry_run {
+                log::info!("Would put flower {image_idx} ({file_name}) into the montage");
+                placed.push((image_idx, caption));
+                continue;
+            }
+            match sheet.add(&caption, &to_path("wiki_flowers").join(file_name)) {
+                Ok(()) => placed.push((image_idx, caption)),
+                Err(
+                    err @ (RenderError::Image(_)
+                    | RenderError::TooSmall { .. }
+                    | RenderError::TooLarge { .. }),
+                ) => {
+                    log::warn!("Skipping {file_name}: {err}");
+                    self.unreadable.insert(image_idx);
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        if placed.is_empty() {
+            return Err("None of the images in wiki_flowers could be rendered".into());
+        }
+        if self.dry_run {
+            log::info!("Would render the montage to {}", output_file_path.display());
+        } else {
+            sheet.save(output_file_path)?;
+            log::info!("Rendered a montage of {} flowers", placed.len());
+            for &(image_idx, _) in &placed {
+                self.record(image_idx);
+            }
+        }
+        let captions: Vec<String> = placed.into_iter().map(|(_, caption)| caption).collect();
+        Ok(captions.join(", "))
+    }
+
+    // Moves the rotation on by one flower and returns it
+    fn next_image(&mut self, strategy: SelectionStrategy, theme: Option<&str>) -> usize {
+        let (position, reshuffled) = match strategy {
+            // The date and the flowers taken on it decide, so every monitor, cell and skip gets
+            // the next flower of the day; the stored position is just kept up to date
+            SelectionStrategy::WeeklySeeded => {
+                let today = Local::now().date_naive();
+                if self.weekly_taken.0 != today {
+                    self.weekly_taken = (today, 0);
+                }
+                let attempt = self.weekly_taken.1;
+                self.weekly_taken.1 += 1;
+                (
+                    weekly_position(self.permutation.len(), today, attempt),
+                    false,
+                )
+            }
+            _ => strategy.next_position(
+                &mut self.permutation,
+                self.state.index,
+                &mut self.state.seen,
+                |image_idx| {
+                    let file_name = &self.image_file_names[image_idx];
+                    if is_blocked(file_name, &self.blocklist) {
+                        0
+                    } else {
+                        weight_for(file_name, &self.weights)
+                    }
+                },
+                // Without subdirectories every image belongs to the theme
+                |image_idx| {
+                    theme.is_none_or(|theme| {
+                        self.image_file_names[image_idx]
+                            .strip_prefix(theme)
+                            .is_some_and(|rest| rest.starts_with('/'))
+                    })
+                },
+            ),
+        };
+        if reshuffled
+            && !self.dry_run
+            && let Err(err) = store_permutation(&self.permutation)
+        {
+            log::error!("Failed to store the reshuffled permutation: {err}");
+        }
+        let image_idx = self.permutation[position];
+        self.state.seen.insert(image_idx);
+        self.state.index = (position + 1) % self.permutation.len();
+        image_idx
+    }
+
+    // Pins or unpins from the next change on, for the control pipe
+    pub fn pin(&mut self, file_name: Option<&str>) {
+        let pinned = match file_name {
+            Some(file_name) => {
+                match self
+                    .image_file_names
+                    .iter()
+                    .position(|name| name == file_name)
+                {
+                    Some(image_idx) => Some(image_idx),
+                    None => {
+                        log::warn!("Cannot pin {file_name}, it is not in the image list yet");
+                        return;
+                    }
+                }
+            }
+            None => None,
+        };
+        if self.dry_run {
+            match file_name {
+                Some(file_name) => log::info!("Would pin {file_name}"),
+                None => log::info!("Would unpin"),
+            }
+            return;
+        }
+        self.state.pinned = pinned;
+        match file_name {
+            Some(file_name) => log::info!("Pinned {file_name}, it stays from the next change on"),
+            None => log::info!("Unpinned, the rotation continues with the next change"),
+        }
+        if let Err(err) = self.state.store() {
+            log::error!("Failed to store state: {err}");
+        }
+    }
+
+    // `--pin` and `--unpin` change the state file while the service runs
+    pub fn reload_pin(&mut self) {
+        if let Ok(stored) = State::load() {
+            self.state.pinned = stored.pinned;
+        }
+    }
+
+    // Renders one flower and records it in the history. Returns its caption.
+    fn render_image(
+        &mut self,
+        image_idx: usize,
+        output_file_path: &Path,
+        config: &RenderConfig,
+    ) -> Result<String, RenderError> {
+        let file_name = &self.image_file_names[image_idx];
+        let caption = caption_for(file_name, &self.names);
+        if self.dry_run {
+            log::info!(
+                "Would render flower {image_idx} ({file_name}) to {}",
+                output_file_path.display()
+            );
+            return Ok(caption);
+        }
+        let config = RenderConfig {
+            stamp_position: self.stamp_position(image_idx),
+            ..config.clone()
+        };
+        let file_name = &self.image_file_names[image_idx];
+        self.renderer.render(
+            &caption,
+            to_path("wiki_flowers").join(file_name),
+            output_file_path,
+            &config,
+        )?;
+        log::info!("Rendered flower {image_idx} ({file_name})");
+        self.record(image_idx);
+        Ok(caption)
+    }
+
+    fn record(&self, image_idx: usize) {
+        let entry = HistoryEntry {
+            timestamp: Local::now(),
+            index: image_idx,
+            file_name: self.image_file_names[image_idx].clone(),
+        };
+        if let Err(err) = append_history(&entry) {
+            log::error!("Failed to append to the history: {err}");
+        }
+    }
+}
+
+// "flower_of_today.png", or one file per monitor when the wallpaper is set per monitor
+pub fn output_path(monitor_offset: Option<usize>, render_config: &RenderConfig) -> PathBuf {
+    let extension = render_config.output_format.extension();
+    match monitor_offset {
+        None => to_path(format!("flower_of_today.{extension}")),
+        Some(offset) => to_path(format!("flower_of_today_{offset}.{extension}")),
+    }
+}
+
+// With unique output names the wallpaper alternates between e.g. "flower_of_today_a.png" and
+// "flower_of_today_b.png", as Windows may keep showing a cached image for a path it already knows.
+// Only the one shown is kept, a new one is removed again when it cannot be set; should both exist
+// anyway, e.g. after a crash in between, the newer one counts as shown.
+fn alternate_output_paths(path: &Path) -> [PathBuf; 2] {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    ["a", "b"].map(|suffix| path.with_file_name(format!("{stem}_{suffix}.{extension}")))
+}
+
+// The rendered wallpaper that is shown now, or was before a restart
+fn shown_output_path(
+    monitor_offset: Option<usize>,
+    render_config: &RenderConfig,
+    unique_names: bool,
+) -> PathBuf {
+    let path = output_path(monitor_offset, render_config);
+    if !unique_names {
+        return path;
+    }
+    let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+    alternate_output_paths(&path)
+        .into_iter()
+        .filter_map(|path| Some((modified(&path).ok()?, path)))
+        .max_by_key(|(modified, _)| *modified)
+        .map_or(path, |(_, path)| path)
+}
+
+// Where the next wallpaper is rendered to, a path other than the shown one with unique names
+fn next_output_path(
+    monitor_offset: Option<usize>,
+    render_config: &RenderConfig,
+    unique_names: bool,
+) -> PathBuf {
+    let path = output_path(monitor_offset, render_config);
+    if !unique_names {
+        return path;
+    }
+    let shown = shown_output_path(monitor_offset, render_config, unique_names);
+    let [first, second] = alternate_output_paths(&path);
+    if shown == first { second } else { first }
+}
+
+// The wallpaper that was shown before the one just set is not needed anymore
+fn remove_previous_output(previous: &Path, current: &Path) {
+    if previous == current {
+        return;
+    }
+    match fs::remove_file(previous) {
+        Ok(()) => log::debug!("Removed the previous wallpaper {}", previous.display()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => log::warn!(
+            "Cannot remove the previous wallpaper {}: {err}",
+            previous.display()
+        ),
+    }
+}
+
+// A wallpaper that could not be set goes again, otherwise it would count as the shown one and the
+// next change would render over the file that really is on the desktop
+fn remove_unset_output(unset: &Path, shown: &Path) {
+    if unset == shown {
+        return;
+    }
+    match fs::remove_file(unset) {
+        Ok(()) => log::debug!("Removed {}, it was not set", unset.display()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => log::warn!("Cannot remove {}: {err}", unset.display()),
+    }
+}
+
+// A rendered wallpaper is reused when it is newer than its photo and the config
+fn is_up_to_date(output_path: &Path, image_path: &Path) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+    let Ok(rendered) = modified(output_path) else {
+        return false;
+    };
+    let image_is_older = modified(image_path).is_ok_and(|modified| modified <= rendered);
+    let config_is_older =
+        modified(&to_path("config.toml")).map_or(true, |modified| modified <= rendered);
+    image_is_older && config_is_older
+}
+
+// Flowers that no longer render leave their cell empty
+fn render_montage_again(
+    rotation: &Rotation,
+    file_names: &[&str],
+    output_file_path: &Path,
+    render_config: &RenderConfig,
+) -> Result<(), RenderError> {
+    let Some(montage) = rotation.montage else {
+        return Err(RenderError::Config("No montage is configured".to_owned()));
+    };
+    let mut sheet = Montage::new(render_config, montage)?;
+    for file_name in file_names {
+        let caption = caption_for(file_name, &rotation.names);
+        match sheet.add(&caption, &to_path("wiki_flowers").join(file_name)) {
+            Ok(()) => {}
+            Err(
+                err @ (RenderError::Image(_)
+                | RenderError::TooSmall { .. }
+                | RenderError::TooLarge { .. }),
+            ) => {
+                log::warn!("Leaving out {file_name}: {err}");
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    sheet.save(output_file_path)?;
+    log::info!("Rendered the montage of {} again", file_names.join(", "));
+    Ok(())
+}
+
+// Puts the flowers of the current period back after a restart, rendering only the ones whose
+// output is missing or stale
+pub fn reapply_wallpaper(
+    rotation: &Rotation,
+    wallpaper_setter: &impl WallpaperSetter,
+    render_config: &RenderConfig,
+) {
+    let monitors = wallpaper_setter.monitors();
+    let outputs: Vec<(Option<&Monitor>, Option<usize>)> = if monitors.is_empty() {
+        vec![(None, None)]
+    } else {
+        monitors
+            .iter()
+            .enumerate()
+            .map(|(offset, monitor)| (Some(monitor), Some(offset)))
+            .collect()
+    };
+    let cells = rotation.cells_per_wallpaper();
+    let unique_names = rotation.unique_output_names;
+    for (back, &(monitor, offset)) in outputs.iter().rev().enumerate() {
+        let shown_file_path = shown_output_path(offset, render_config, unique_names);
+        // A stale wallpaper is rendered to a new path with unique names, like at a change
+        let mut output_file_path = shown_file_path.clone();
+        let render_config = match monitor {
+            Some(monitor) => render_config.scaled_to(monitor.width, monitor.height),
+            None => render_config.clone(),
+        };
+        if cells > 1 {
+            // The cells were filled in rotation order, the first one is the furthest back
+            let file_names: Option<Vec<&str>> = (0..cells)
+                .rev()
+                .map(|cell| rotation.shown_file_name(back * cells + cell))
+                .collect();
+            let Some(file_names) = file_names else {
+                return;
+            };
+            if rotation.dry_run {
+                log::info!("Would show the montage of {} again", file_names.join(", "));
+                continue;
+            }
+            let image_paths: Vec<PathBuf> = file_names
+                .iter()
+                .map(|file_name| to_path("wiki_flowers").join(file_name))
+                .collect();
+            if image_paths
+                .iter()
+                .all(|image_path| is_up_to_date(&output_file_path, image_path))
+            {
+                log::info!("Reusing the montage {}", output_file_path.display());
+            } else {
+                output_file_path = next_output_path(offset, &render_config, unique_names);
+                if let Err(err) =
+                    render_montage_again(rotation, &file_names, &output_file_path, &render_config)
+                {
+                    log::warn!("Cannot render the montage again: {err}");
+                    continue;
+                }
+            }
+        } else {
+            let Some(file_name) = rotation.shown_file_name(back) else {
+                return;
+            };
+            let image_path = to_path("wiki_flowers").join(file_name);
+            if rotation.dry_run {
+                log::info!("Would show {file_name} again");
+                continue;
+            }
+            if is_up_to_date(&output_file_path, &image_path) {
+                log::info!("Reusing {} for {file_name}", output_file_path.display());
+            } else {
+                output_file_path = next_output_path(offset, &render_config, unique_names);
+                let mut render_config = render_config.clone();
+                render_config.stamp_position = rotation
+                    .image_file_names
+                    .iter()
+                    .position(|name| name == file_name)
+                    .and_then(|image_idx| rotation.stamp_position(image_idx));
+                let caption = caption_for(file_name, &rotation.names);
+                if let Err(err) =
+                    modify_image(&caption, &image_path, &output_file_path, &render_config)
+                {
+                    log::warn!("Cannot render {file_name} again: {err}");
+                    continue;
+                }
+                log::info!("Rendered {file_name} again");
+            }
+        }
+        let result = match monitor {
+            Some(monitor) => wallpaper_setter.set_for_monitor(monitor, &output_file_path),
+            None => wallpaper_setter.set(&output_file_path),
+        };
+        match result {
+            Ok(()) => remove_previous_output(&shown_file_path, &output_file_path),
+            Err(err) => {
+                log::error!(
+                    "Failed to set wallpaper {}: {err}",
+                    output_file_path.display()
+                );
+                remove_unset_output(&output_file_path, &shown_file_path);
+            }
+        }
+    }
+}
+
+// Longer captions, like the ones of a montage, are cut in the archive file name
+const MAX_ARCHIVE_NAME_LEN: usize = 80;
+
+// Copies a rendered wallpaper to e.g. "archive/2024-06-01_Dog-Rose.png". A copy that already
+// exists, like after a restart on the same day, is kept.
+fn archive_wallpaper(output_file_path: &Path, archive_dir: &Path, caption: &str) {
+    let name: String = caption
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join("-")
+        .chars()
+        .filter(|c| {
+            !matches!(
+                c,
+                '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' | ','
+            )
+        })
+        .take(MAX_ARCHIVE_NAME_LEN)
+        .collect();
+    let extension = output_file_path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("png");
+    let archive_path = archive_dir.join(format!(
+        "{}_{name}.{extension}",
+        Local::now().format("%Y-%m-%d")
+    ));
+    if archive_path.exists() {
+        return;
+    }
+    match fs::create_dir_all(archive_dir).and_then(|()| fs::copy(output_file_path, &archive_path)) {
+        Ok(_) => log::info!("Archived the wallpaper as {}", archive_path.display()),
+        Err(err) => log::error!("Failed to archive the wallpaper: {err}"),
+    }
+}
+
+// The lock screen shows a single image, the wallpaper of the first monitor
+pub fn update_lock_screen(
+    rotation: &Rotation,
+    wallpaper_setter: &impl WallpaperSetter,
+    render_config: &RenderConfig,
+) {
+    let monitor_offset = (!wallpaper_setter.monitors().is_empty()).then_some(0);
+    let output_file_path =
+        shown_output_path(monitor_offset, render_config, rotation.unique_output_names);
+    if rotation.dry_run {
+        log::info!(
+            "Would set the lock screen to {}",
+            output_file_path.display()
+        );
+        return;
+    }
+    match set_lock_screen(&output_file_path) {
+        Ok(()) => log::info!("Set the lock screen to {}", output_file_path.display()),
+        Err(err) => log::warn!("Cannot set the lock screen, skipping it: {err}"),
+    }
+}
+
+// Renders the next flower for every monitor and stores the state. Returns the captions shown.
+pub fn change_wallpaper(
+    rotation: &mut Rotation,
+    wallpaper_setter: &impl WallpaperSetter,
+    render_config: &RenderConfig,
+    strategy: SelectionStrategy,
+    archive_dir: Option<&Path>,
+) -> Result<Vec<String>, ServiceError> {
+    let monitors = wallpaper_setter.monitors();
+    let mut captions = Vec::new();
+    let set = if rotation.dry_run { "Would set" } else { "Set" };
+    let unique_names = rotation.unique_output_names;
+    // Nothing is rendered in a dry run, so there is no previous wallpaper to remove either
+    let dry_run = rotation.dry_run;
+    let remove_previous = |previous: &Path, current: &Path| {
+        if !dry_run {
+            remove_previous_output(previous, current);
+        }
+    };
+    let remove_unset = |unset: &Path, shown: &Path| {
+        if !dry_run {
+            remove_unset_output(unset, shown);
+        }
+    };
+    rotation.reload_pin();
+
+    if monitors.is_empty() {
+        let shown_file_path = shown_output_path(None, render_config, unique_names);
+        let output_file_path = next_output_path(None, render_config, unique_names);
+        let caption = rotation.render_next(&output_file_path, render_config, strategy)?;
+        if let Some(archive_dir) = archive_dir.filter(|_| !rotation.dry_run) {
+            archive_wallpaper(&output_file_path, archive_dir, &caption);
+        }
+        captions.push(caption);
+        match wallpaper_setter.set(&output_file_path) {
+            Ok(()) => {
+                log::info!("{set} wallpaper {}", output_file_path.display());
+                remove_previous(&shown_file_path, &output_file_path);
+            }
+            Err(err) => {
+                log::error!("Failed to set wallpaper: {err}");
+                remove_unset(&output_file_path, &shown_file_path);
+            }
+        }
+    } else {
+        for (offset, monitor) in monitors.iter().enumerate() {
+            let shown_file_path = shown_output_path(Some(offset), render_config, unique_names);
+            let output_file_path = next_output_path(Some(offset), render_config, unique_names);
+            let caption = rotation.render_next(
+                &output_file_path,
+                &render_config.scaled_to(monitor.width, monitor.height),
+                strategy,
+            )?;
+            if let Some(archive_dir) = archive_dir.filter(|_| !rotation.dry_run) {
+                archive_wallpaper(&output_file_path, archive_dir, &caption);
+            }
+            captions.push(caption);
+            match wallpaper_setter.set_for_monitor(monitor, &output_file_path) {
+                Ok(()) => {
+                    log::info!(
+                        "{set} wallpaper {} on monitor {offset}",
+                        output_file_path.display()
+                    );
+                    remove_previous(&shown_file_path, &output_file_path);
+                }
+                Err(err) => {
+                    log::error!("Failed to set wallpaper on monitor {offset}: {err}");
+                    remove_unset(&output_file_path, &shown_file_path);
+                }
+            }
+        }
+    }
+    rotation.state.last_change = Local::now();
+    // Keeps a pin or unpin from during the rendering instead of overwriting it
+    rotation.reload_pin();
+    if !rotation.dry_run
+        && let Err(err) = rotation.state.store()
+    {
+        log::error!("Failed to store state: {err}");
+    }
+    Ok(captions)
+}
+
+// The flowers of today that weekly_seeded took before a restart, up to the one shown last
+fn weekly_taken_today(state: &State, image_count: usize) -> (NaiveDate, usize) {
+    let today = Local::now().date_naive();
+    if image_count == 0 || state.last_change.date_naive() != today {
+        return (today, 0);
+    }
+    let last_position = (state.index + image_count - 1) % image_count;
+    let taken = weekly_attempt(image_count, today, last_position).map_or(0, |attempt| attempt + 1);
+    (today, taken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A dry run renders and stores nothing, so the rotation runs without a base path
+    fn dry_rotation(file_names: &[&str], blocklist: &[&str]) -> Rotation {
+        Rotation {
+            image_file_names: file_names.iter().map(|&name| name.to_owned()).collect(),
+            permutation: (0..file_names.len()).collect(),
+            strategy: SelectionStrategy::Sequential,
+            state: State::default(),
+            names: HashMap::new(),
+            weights: HashMap::new(),
+            blocklist: blocklist.iter().map(|&name| name.to_owned()).collect(),
+            unreadable: HashSet::new(),
+            dry_run: true,
+            montage: None,
+            unique_output_names: false,
+            weekly_taken: (Local::now().date_naive(), 0),
+            renderer: Renderer::default(),
+        }
+    }
+
+    fn show_next(rotation: &mut Rotation) -> String {
+        let strategy = rotation.strategy;
+        rotation
+            .render_next(
+                Path::new("flower_of_today.png"),
+                &RenderConfig::default(),
+                strategy,
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn blocked_flowers_keep_their_place_in_the_rotation() {
+        let file_names = ["Bellis.png", "Rosa_canina.png", "Tulip.png", "Lily.png"];
+        let mut rotation = dry_rotation(&file_names, &["Rosa_canina.png"]);
+        assert_eq!(show_next(&mut rotation), "Bellis");
+        assert_eq!(rotation.state.index, 1);
+        // The blocked flower is passed over, the index still counts it
+        assert_eq!(show_next(&mut rotation), "Tulip");
+        assert_eq!(rotation.state.index, 3);
+        assert_eq!(rotation.permutation, [0, 1, 2, 3]);
+
+        // Unblocked, it shows at its old place again
+        rotation.blocklist.clear();
+        rotation.state.index = 1;
+        assert_eq!(show_next(&mut rotation), "Rosa canina");
+        assert_eq!(rotation.state.index, 2);
+    }
+
+    #[test]
+    fn everything_blocked_is_an_error() {
+        let strategy = SelectionStrategy::Sequential;
+        let mut rotation = dry_rotation(&["Bellis.png", "Tulip.png"], &["Bellis.png", "Tulip.png"]);
+        let result = rotation.render_next(
+            Path::new("flower_of_today.png"),
+            &RenderConfig::default(),
+            strategy,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn previous_goes_back_over_blocked_flowers() {
+        let mut rotation = dry_rotation(
+            &["Bellis.png", "Rosa_canina.png", "Tulip.png"],
+            &["Rosa_canina.png"],
+        );
+        assert_eq!(show_next(&mut rotation), "Bellis");
+        assert_eq!(show_next(&mut rotation), "Tulip");
+        assert_eq!(rotation.current_caption().as_deref(), Some("Tulip"));
+        assert_eq!(rotation.shown_file_name(1), Some("Bellis.png"));
+
+        // Back over Tulip, which is showing, to Bellis before it
+        rotation.rewind(2);
+        assert_eq!(rotation.state.index, 0);
+        assert_eq!(show_next(&mut rotation), "Bellis");
+        assert_eq!(show_next(&mut rotation), "Tulip");
+    }
+
+    #[test]
+    fn previous_goes_back_over_unreadable_flowers() {
+        let mut rotation = dry_rotation(&["Bellis.png", "Broken.png", "Tulip.png"], &[]);
+        rotation.unreadable.insert(1);
+        rotation.state.index = 3;
+        rotation.rewind(2);
+        assert_eq!(rotation.state.index, 0);
+        // Going further back than there are flowers goes around the rotation
+        rotation.state.index = 3;
+        rotation.rewind(3);
+        assert_eq!(rotation.state.index, 2);
+    }
+}
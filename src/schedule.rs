@@ -0,0 +1,519 @@
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+use std::{env, fs, io};
+
+use chrono::{DateTime, Local, NaiveDateTime, NaiveTime, TimeDelta, TimeZone, Timelike as _};
+use chrono_tz::Tz;
+use rand::rngs::StdRng;
+use rand::{Rng as _, SeedableRng as _};
+use serde::{Deserialize, Deserializer, de};
+
+use crate::state::write_atomic;
+use crate::to_path;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ChangeInterval {
+    #[default]
+    Daily,
+    Hourly,
+    EveryN(Duration),
+    AtTimes(Vec<NaiveTime>),
+}
+
+// Zone of the midnights and times of day the interval refers to, the one of the machine by default
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ScheduleZone {
+    #[default]
+    Local,
+    Named(Tz),
+}
+
+impl ScheduleZone {
+    fn naive(&self, instant: DateTime<Local>) -> NaiveDateTime {
+        match self {
+            ScheduleZone::Local => instant.naive_local(),
+            ScheduleZone::Named(zone) => instant.with_timezone(zone).naive_local(),
+        }
+    }
+
+    fn resolve(&self, naive: NaiveDateTime) -> DateTime<Local> {
+        match self {
+            ScheduleZone::Local => resolve_in(&Local, naive),
+            ScheduleZone::Named(zone) => resolve_in(zone, naive),
+        }
+    }
+}
+
+impl FromStr for ScheduleZone {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        if value.eq_ignore_ascii_case("local") {
+            return Ok(ScheduleZone::Local);
+        }
+        value.parse().map(ScheduleZone::Named).map_err(|_| {
+            format!(
+                "Unknown time zone {value:?}, expected \"local\" or a name like \"Europe/Berlin\""
+            )
+        })
+    }
+}
+
+impl TryFrom<String> for ScheduleZone {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl ChangeInterval {
+    pub fn next_change_after(
+        &self,
+        last_change: DateTime<Local>,
+        zone: ScheduleZone,
+    ) -> DateTime<Local> {
+        let last_naive = zone.naive(last_change);
+        match self {
+            ChangeInterval::Daily => zone.resolve(next_midnight(last_naive)),
+            ChangeInterval::Hourly => {
+                let hour_start = last_naive
+                    .with_minute(0)
+                    .and_then(|time| time.with_second(0))
+                    .and_then(|time| time.with_nanosecond(0))
+                    .unwrap_or(last_naive);
+                zone.resolve(hour_start + TimeDelta::hours(1))
+            }
+            ChangeInterval::EveryN(interval) => TimeDelta::from_std(*interval)
+                .ok()
+                .and_then(|interval| last_change.checked_add_signed(interval))
+                .unwrap_or_else(|| ChangeInterval::Daily.next_change_after(last_change, zone)),
+            ChangeInterval::AtTimes(times) => {
+                let today = last_naive.date();
+                let mut times = times.clone();
+                times.sort();
+                times
+                    .iter()
+                    .map(|&time| today.and_time(time))
+                    .find(|&candidate| candidate > last_naive)
+                    .or_else(|| {
+                        let tomorrow = next_midnight(last_naive).date();
+                        times.first().map(|&time| tomorrow.and_time(time))
+                    })
+                    .map(|naive| zone.resolve(naive))
+                    .unwrap_or_else(|| ChangeInterval::Daily.next_change_after(last_change, zone))
+            }
+        }
+    }
+
+    // Scheduled changes between `last_change` and `now` beyond the one that is due next
+    pub fn missed_changes(
+        &self,
+        last_change: DateTime<Local>,
+        now: DateTime<Local>,
+        zone: ScheduleZone,
+    ) -> u64 {
+        if let ChangeInterval::EveryN(interval) = self {
+            let elapsed = (now - last_change).to_std().unwrap_or_default();
+            return (elapsed.as_secs() / interval.as_secs().max(1)).saturating_sub(1);
+        }
+        let mut due = 0u64;
+        let mut change = last_change;
+        loop {
+            let next = self.next_change_after(change, zone);
+            // The fallbacks above may not move forward, which would never end
+            if next > now || next <= change {
+                return due.saturating_sub(1);
+            }
+            due += 1;
+            change = next;
+        }
+    }
+}
+
+impl FromStr for ChangeInterval {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        if value.eq_ignore_ascii_case("daily") {
+            return Ok(ChangeInterval::Daily);
+        }
+        if value.eq_ignore_ascii_case("hourly") {
+            return Ok(ChangeInterval::Hourly);
+        }
+        if let Some(duration) = value.strip_prefix("every ") {
+            return parse_duration(duration).map(ChangeInterval::EveryN);
+        }
+        if let Some(times) = value.strip_prefix("at ") {
+            let times = times
+                .split(',')
+                .map(|time| {
+                    let time = time.trim();
+                    NaiveTime::parse_from_str(time, "%H:%M:%S")
+                        .or_else(|_| NaiveTime::parse_from_str(time, "%H:%M"))
+                        .map_err(|_| format!("Invalid time {time:?} in interval"))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            return Ok(ChangeInterval::AtTimes(times));
+        }
+        Err(format!(
+            "Unknown interval {value:?}, expected \"daily\", \"hourly\", \"every <n><s|m|h|d>\" or \"at <HH:MM>, ...\""
+        ))
+    }
+}
+
+impl TryFrom<String> for ChangeInterval {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+const MAX_INTERVAL_SECONDS: u64 = 366 * 24 * 60 * 60;
+
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let unit_start = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(unit_start);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("Invalid duration {value:?}"))?;
+    let unit_seconds = match unit.trim() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("Invalid duration unit in {value:?}")),
+    };
+    let seconds = amount
+        .checked_mul(unit_seconds)
+        .filter(|&seconds| seconds <= MAX_INTERVAL_SECONDS)
+        .ok_or_else(|| format!("Duration {value:?} is longer than a year"))?;
+    if seconds == 0 {
+        return Err("Interval must be longer than zero".to_owned());
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+pub(crate) fn deserialize_optional_jitter<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_duration(&value)
+        .map(Some)
+        .map_err(|err| de::Error::custom(format!("Invalid jitter {value:?}: {err}")))
+}
+
+// Shift of all scheduled changes within `jitter` either way. It stays the same on every run of a
+// machine, but differs between machines, so they do not all render at midnight.
+pub fn jitter_offset(jitter: Option<Duration>) -> TimeDelta {
+    let Some(jitter) = jitter else {
+        return TimeDelta::zero();
+    };
+    let seed = jitter_seed(machine_name().as_deref(), &to_path("jitter_seed.txt"));
+    offset_within(jitter, seed)
+}
+
+// macOS has neither variable nor /etc/hostname and gets none, like some containers
+fn machine_name() -> Option<String> {
+    env::var("COMPUTERNAME")
+        .ok()
+        .or_else(|| env::var("HOSTNAME").ok())
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|machine| machine.trim().to_owned())
+        .filter(|machine| !machine.is_empty())
+}
+
+// Without a name, a random seed drawn on the first run and kept in `seed_path` stands in for it
+fn jitter_seed(machine: Option<&str>, seed_path: &Path) -> u64 {
+    if let Some(machine) = machine {
+        // FNV-1a, unlike the hasher of std it gives the same seed with every Rust version
+        return machine
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325, |hash: u64, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+            });
+    }
+    if let Some(seed) = fs::read_to_string(seed_path)
+        .ok()
+        .and_then(|contents| contents.trim().parse().ok())
+    {
+        return seed;
+    }
+    let seed: u64 = rand::random();
+    if let Err(err) = write_atomic(seed_path, seed.to_string()) {
+        log::warn!(
+            "Cannot store the jitter seed in {}: {err}",
+            seed_path.display()
+        );
+    }
+    seed
+}
+
+fn offset_within(jitter: Duration, seed: u64) -> TimeDelta {
+    // At most a year, see parse_duration
+    let seconds = jitter.as_secs() as i64;
+    TimeDelta::seconds(StdRng::seed_from_u64(seed).gen_range(-seconds..=seconds))
+}
+
+// End of a pause from "pause_until.txt", which holds an RFC 3339 timestamp.
+// With `remove_expired` the file is deleted once the time has passed; a malformed timestamp is
+// ignored.
+pub fn pause_until(remove_expired: bool) -> Option<DateTime<Local>> {
+    let path = to_path("pause_until.txt");
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return None,
+        Err(err) => {
+            log::warn!("Cannot read {}, ignoring it: {err}", path.display());
+            return None;
+        }
+    };
+    let end = match DateTime::parse_from_rfc3339(contents.trim()) {
+        Ok(end) => end.with_timezone(&Local),
+        Err(err) => {
+            log::warn!(
+                "Invalid timestamp in {}, ignoring it: {err}",
+                path.display()
+            );
+            return None;
+        }
+    };
+    if end > Local::now() {
+        return Some(end);
+    }
+    log::info!("Pause ended at {end}, resuming the rotation");
+    if remove_expired && let Err(err) = fs::remove_file(&path) {
+        log::warn!("Cannot remove {}: {err}", path.display());
+    }
+    None
+}
+
+// Short enough that a resume from standby past the target triggers the change almost immediately
+const WAKE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+pub fn duration_until(target: DateTime<Local>) -> Duration {
+    (target - Local::now()).to_std().unwrap_or_default()
+}
+
+// How long to sleep at `now` before looking at the clock again, `None` once `target` is due.
+// A clock set back only makes the steps continue, one set forward or a resume makes it due.
+pub fn sleep_step(target: DateTime<Local>, now: DateTime<Local>) -> Option<Duration> {
+    let remaining = (target - now).to_std().unwrap_or_default();
+    (!remaining.is_zero()).then(|| remaining.min(WAKE_CHECK_INTERVAL))
+}
+
+// Sleeps in short steps and re-checks the wall clock, since a monotonic sleep does not advance
+// while the machine is suspended
+pub fn sleep_until(target: DateTime<Local>) {
+    while let Some(step) = sleep_step(target, Local::now()) {
+        thread::sleep(step);
+    }
+}
+
+// Like `sleep_until`, but returns early with the first message received in the meantime
+pub fn wait_until<T>(target: DateTime<Local>, receiver: &Receiver<T>) -> Option<T> {
+    while let Some(step) = sleep_step(target, Local::now()) {
+        match receiver.recv_timeout(step) {
+            Ok(message) => return Some(message),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                sleep_until(target);
+                return None;
+            }
+        }
+    }
+    None
+}
+
+fn next_midnight(after: NaiveDateTime) -> NaiveDateTime {
+    let date = after.date();
+    date.succ_opt().unwrap_or(date).and_time(NaiveTime::MIN)
+}
+
+// Zones that switch to daylight saving time skip an hour, e.g. midnight itself
+fn resolve_in<Z: TimeZone>(zone: &Z, naive: NaiveDateTime) -> DateTime<Local> {
+    (0..24)
+        .find_map(|hour| {
+            zone.from_local_datetime(&(naive + TimeDelta::hours(hour)))
+                .earliest()
+        })
+        .map(|instant| instant.with_timezone(&Local))
+        .unwrap_or_else(Local::now)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn utc(year: i32, month: u32, day: u32, hour: u32, min: u32, sec: u32) -> DateTime<Local> {
+        NaiveDate::from_ymd_opt(year, month, day)
+            .and_then(|date| date.and_hms_opt(hour, min, sec))
+            .unwrap()
+            .and_utc()
+            .with_timezone(&Local)
+    }
+
+    #[test]
+    fn daily_change_is_at_the_next_midnight_around_midnight() {
+        let zone = ScheduleZone::Named(Tz::UTC);
+        let cases = [
+            (utc(2024, 6, 1, 12, 0, 0), utc(2024, 6, 2, 0, 0, 0)),
+            (utc(2024, 6, 1, 23, 59, 59), utc(2024, 6, 2, 0, 0, 0)),
+            (utc(2024, 6, 2, 0, 0, 0), utc(2024, 6, 3, 0, 0, 0)),
+            (utc(2024, 6, 2, 0, 0, 1), utc(2024, 6, 3, 0, 0, 0)),
+            (utc(2024, 12, 31, 23, 30, 0), utc(2025, 1, 1, 0, 0, 0)),
+        ];
+        for (last_change, expected) in cases {
+            assert_eq!(
+                ChangeInterval::Daily.next_change_after(last_change, zone),
+                expected,
+                "last change at {last_change}"
+            );
+        }
+    }
+
+    #[test]
+    fn sleep_past_midnight_wakes_right_away() {
+        let midnight = utc(2024, 6, 2, 0, 0, 0);
+        assert_eq!(
+            sleep_step(midnight, utc(2024, 6, 1, 23, 59, 59)),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(sleep_step(midnight, midnight), None);
+        assert_eq!(sleep_step(midnight, utc(2024, 6, 2, 0, 0, 1)), None);
+        assert_eq!(sleep_step(midnight, utc(2024, 6, 2, 23, 59, 59)), None);
+    }
+
+    #[test]
+    fn changes_follow_the_configured_zone() {
+        let berlin = ScheduleZone::Named(Tz::Europe__Berlin);
+        let cases = [
+            // Summer time, Berlin is two hours ahead
+            (utc(2024, 6, 1, 12, 0, 0), utc(2024, 6, 1, 22, 0, 0)),
+            (utc(2024, 6, 1, 22, 0, 0), utc(2024, 6, 2, 22, 0, 0)),
+            // Winter time, one hour
+            (utc(2024, 1, 15, 23, 30, 0), utc(2024, 1, 16, 23, 0, 0)),
+        ];
+        for (last_change, expected) in cases {
+            assert_eq!(
+                ChangeInterval::Daily.next_change_after(last_change, berlin),
+                expected,
+                "last change at {last_change}"
+            );
+        }
+
+        let at_times: ChangeInterval = "at 07:00, 19:00".parse().unwrap();
+        assert_eq!(
+            at_times.next_change_after(utc(2024, 6, 1, 6, 0, 0), berlin),
+            utc(2024, 6, 1, 17, 0, 0)
+        );
+        assert_eq!(
+            at_times.next_change_after(utc(2024, 6, 1, 18, 0, 0), berlin),
+            utc(2024, 6, 2, 5, 0, 0)
+        );
+    }
+
+    #[test]
+    fn sleep_is_cut_into_steps_up_to_the_target() {
+        let target = utc(2024, 6, 2, 0, 0, 0);
+        assert_eq!(
+            sleep_step(target, utc(2024, 6, 1, 12, 0, 0)),
+            Some(WAKE_CHECK_INTERVAL)
+        );
+        assert_eq!(
+            sleep_step(target, utc(2024, 6, 1, 23, 58, 30)),
+            Some(WAKE_CHECK_INTERVAL)
+        );
+        assert_eq!(
+            sleep_step(target, utc(2024, 6, 1, 23, 59, 30)),
+            Some(Duration::from_secs(30))
+        );
+
+        // Stepping through simulated wakes reaches the target exactly
+        let mut now = utc(2024, 6, 1, 23, 55, 20);
+        let mut steps = 0;
+        while let Some(step) = sleep_step(target, now) {
+            now += TimeDelta::from_std(step).unwrap();
+            steps += 1;
+        }
+        assert_eq!((now, steps), (target, 5));
+
+        // A resume past the target is overdue right away
+        assert_eq!(sleep_step(target, utc(2024, 6, 2, 8, 0, 0)), None);
+    }
+
+    #[test]
+    fn jittered_change_stays_within_the_window() {
+        let jitter = Duration::from_secs(30 * 60);
+        let midnight = utc(2024, 6, 2, 0, 0, 0);
+        let window = (midnight - TimeDelta::minutes(30))..=(midnight + TimeDelta::minutes(30));
+        let zone = ScheduleZone::Named(Tz::UTC);
+        let mut offsets = HashSet::new();
+        for seed in 0..1000 {
+            let offset = offset_within(jitter, seed);
+            assert_eq!(offset, offset_within(jitter, seed), "seed {seed}");
+            let target =
+                ChangeInterval::Daily.next_change_after(utc(2024, 6, 1, 9, 0, 0), zone) + offset;
+            assert!(window.contains(&target), "seed {seed} changes at {target}");
+            offsets.insert(offset);
+        }
+        // Machines spread out over the whole window
+        assert!(offsets.len() > 500);
+        assert!(
+            offsets
+                .iter()
+                .any(|offset| *offset < TimeDelta::minutes(-25))
+        );
+        assert!(
+            offsets
+                .iter()
+                .any(|offset| *offset > TimeDelta::minutes(25))
+        );
+        assert_eq!(jitter_offset(None), TimeDelta::zero());
+    }
+
+    #[test]
+    fn jitter_seed_without_a_machine_name_is_kept() {
+        assert_eq!(
+            jitter_seed(Some("FLOWER-PC"), Path::new("unused")),
+            jitter_seed(Some("FLOWER-PC"), Path::new("unused"))
+        );
+        assert_ne!(
+            jitter_seed(Some("FLOWER-PC"), Path::new("unused")),
+            jitter_seed(Some("FLOWER-MAC"), Path::new("unused"))
+        );
+
+        // A nameless machine draws its own seed once and keeps it
+        let seed_path = |machine: &str| {
+            std::env::temp_dir().join(format!(
+                "flower-service-jitter-seed-{machine}-{}.txt",
+                std::process::id()
+            ))
+        };
+        let (first_mac, second_mac) = (seed_path("first"), seed_path("second"));
+        let seed = jitter_seed(None, &first_mac);
+        let stored = fs::read_to_string(&first_mac);
+        let again = jitter_seed(None, &first_mac);
+        let other = jitter_seed(None, &second_mac);
+        let _ = fs::remove_file(&first_mac);
+        let _ = fs::remove_file(&second_mac);
+        assert_eq!(stored.unwrap(), seed.to_string());
+        assert_eq!(again, seed);
+        assert_ne!(other, seed);
+    }
+}
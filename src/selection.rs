@@ -0,0 +1,147 @@
+use std::collections::{BTreeSet, HashSet};
+
+use chrono::{Datelike as _, NaiveDate};
+use rand::SeedableRng as _;
+use rand::distributions::{Distribution as _, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom as _;
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionStrategy {
+    // Walk through the permutation in order
+    #[default]
+    Sequential,
+    // Skip images that were already shown and reshuffle once every image has been seen
+    NoRepeatUntilExhausted,
+    // Draw every image at random in proportion to its weight, see `load_weights`
+    Weighted,
+    // Walk through the images of one subdirectory of wiki_flowers, another one every week
+    Theme,
+    // Seven flowers a week, one per day in an order seeded by the ISO week, see `weekly_position`
+    WeeklySeeded,
+}
+
+impl SelectionStrategy {
+    // Returns the permutation position to show next, searching forward from `position`.
+    // May reshuffle `permutation` and clear `seen` when a round is complete.
+    pub fn next_position(
+        &self,
+        permutation: &mut [usize],
+        position: usize,
+        seen: &mut HashSet<usize>,
+        weight: impl Fn(usize) -> u32,
+        in_theme: impl Fn(usize) -> bool,
+    ) -> (usize, bool) {
+        match self {
+            // The weekly order depends on the date, on its own it walks on like `Sequential`
+            SelectionStrategy::Sequential | SelectionStrategy::WeeklySeeded => (position, false),
+            SelectionStrategy::Theme => {
+                let themed = (0..permutation.len())
+                    .map(|offset| (position + offset) % permutation.len())
+                    .find(|&candidate| in_theme(permutation[candidate]));
+                (themed.unwrap_or(position), false)
+            }
+            SelectionStrategy::Weighted => {
+                match WeightedIndex::new(permutation.iter().map(|&image_idx| weight(image_idx))) {
+                    Ok(distribution) => (distribution.sample(&mut rand::thread_rng()), false),
+                    // With every image weighted 0 there is nothing to draw from
+                    Err(_) => (position, false),
+                }
+            }
+            SelectionStrategy::NoRepeatUntilExhausted => {
+                let unseen = (0..permutation.len())
+                    .map(|offset| (position + offset) % permutation.len())
+                    .find(|&candidate| !seen.contains(&permutation[candidate]));
+                match unseen {
+                    Some(candidate) => (candidate, false),
+                    None => {
+                        permutation.shuffle(&mut rand::thread_rng());
+                        seen.clear();
+                        (0, true)
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Position of the flower of `date` in a permutation of `image_count` images. Every week takes the
+// next seven positions and shuffles them with a seed from its ISO week, so a week always shows the
+// same flowers in the same order. `attempt` moves on to the flower of the next day, for images
+// that cannot be rendered, for further monitors and cells of a montage and for skipping ahead.
+pub fn weekly_position(image_count: usize, date: NaiveDate, attempt: usize) -> usize {
+    const DAYS: usize = 7;
+    if image_count == 0 {
+        return 0;
+    }
+    let week = (date.num_days_from_ce() - 1).div_euclid(DAYS as i32) as usize;
+    let start = week % image_count.div_ceil(DAYS) * DAYS;
+    let len = DAYS.min(image_count - start);
+    if attempt >= len {
+        return (start + attempt) % image_count;
+    }
+    let mut order: Vec<usize> = (start..start + len).collect();
+    let iso_week = date.iso_week();
+    let seed = iso_week.year() as u64 * 100 + u64::from(iso_week.week());
+    order.shuffle(&mut StdRng::seed_from_u64(seed));
+    let day = date.weekday().num_days_from_monday() as usize;
+    order[(day + attempt) % len]
+}
+
+// The `attempt` at which `weekly_position` gives `position` on `date`, i.e. how many flowers of
+// the day were taken before it
+pub fn weekly_attempt(image_count: usize, date: NaiveDate, position: usize) -> Option<usize> {
+    (0..image_count).find(|&attempt| weekly_position(image_count, date, attempt) == position)
+}
+
+// The subdirectory of wiki_flowers whose images are shown in the week of `date`, taken in turn
+// by ISO week number. None when all images are at the top level.
+pub fn theme_of_week(image_file_names: &[String], date: NaiveDate) -> Option<&str> {
+    let themes: BTreeSet<&str> = image_file_names
+        .iter()
+        .filter_map(|file_name| file_name.split_once('/'))
+        .map(|(theme, _)| theme)
+        .collect();
+    if themes.is_empty() {
+        return None;
+    }
+    // ISO weeks counted from 0001-01-01, a Monday, so the turn does not restart every January
+    let week = (date.num_days_from_ce() - 1).div_euclid(7) as usize;
+    let count = themes.len();
+    themes.into_iter().nth(week % count)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Days;
+
+    use super::*;
+
+    fn week_of(monday: NaiveDate, image_count: usize) -> Vec<usize> {
+        (0..7)
+            .map(|day| weekly_position(image_count, monday + Days::new(day), 0))
+            .collect()
+    }
+
+    #[test]
+    fn the_same_week_gives_the_same_order() {
+        let monday = NaiveDate::from_ymd_opt(2024, 6, 3).unwrap();
+        let week = week_of(monday, 30);
+        assert_eq!(week, week_of(monday, 30));
+        // Seven different flowers from one block of seven
+        let mut sorted = week.clone();
+        sorted.sort_unstable();
+        let start = sorted[0];
+        assert_eq!(start % 7, 0);
+        assert_eq!(sorted, Vec::from_iter(start..start + 7));
+        assert_ne!(week, sorted, "the week is shuffled");
+        assert_ne!(week_of(monday + Days::new(7), 30), week);
+
+        // Further attempts on a day move on to the flowers of the next days
+        let wednesday = monday + Days::new(2);
+        assert_eq!(weekly_position(30, wednesday, 1), week[3]);
+        assert_eq!(weekly_attempt(30, wednesday, week[4]), Some(2));
+    }
+}
@@ -0,0 +1,102 @@
+use image::imageops::{self, FilterType};
+use image::{GrayImage, Luma, Rgba, RgbaImage};
+use serde::Deserialize;
+
+use crate::color::deserialize_color;
+use crate::corners::rounded_rect_coverage;
+use crate::{CornerRadii, apply_mask};
+
+// Sigma the mask is blurred with at most; larger blurs run on a downscaled mask
+const MAX_WORK_SIGMA: f32 = 4.;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ShadowConfig {
+    pub offset_x: i32,
+    pub offset_y: i32,
+    // Sigma of the gaussian blur in canvas pixels
+    pub blur: f32,
+    #[serde(deserialize_with = "deserialize_color")]
+    pub color: Rgba<u8>,
+}
+
+impl Default for ShadowConfig {
+    fn default() -> Self {
+        Self {
+            offset_x: 0,
+            offset_y: 12,
+            blur: 20.,
+            color: Rgba([0, 0, 0, 110]),
+        }
+    }
+}
+
+impl ShadowConfig {
+    pub(crate) fn scaled(&self, factor: f32) -> Self {
+        Self {
+            offset_x: (self.offset_x as f32 * factor).round() as i32,
+            offset_y: (self.offset_y as f32 * factor).round() as i32,
+            blur: self.blur * factor,
+            color: self.color,
+        }
+    }
+}
+
+fn rounded_rect_mask(width: u32, height: u32, radii: CornerRadii) -> GrayImage {
+    let radii = radii.map(|radius| radius.min(width / 2).min(height / 2));
+    GrayImage::from_fn(width, height, |x, y| {
+        Luma([(rounded_rect_coverage((x, y), (width, height), radii) * 255.) as u8])
+    })
+}
+
+// Composites the shadow of a photo of the given size at `offset` onto `canvas`
+pub(crate) fn draw_shadow(
+    canvas: &mut RgbaImage,
+    shadow: &ShadowConfig,
+    offset: (u32, u32),
+    (width, height): (u32, u32),
+    corner_radius: CornerRadii,
+) {
+    if width == 0 || height == 0 {
+        return;
+    }
+    // Room for the blur to fade out on every side
+    let padding = (shadow.blur.max(0.) * 3.).ceil() as u32;
+    let mut mask = GrayImage::new(width + 2 * padding, height + 2 * padding);
+    imageops::replace(
+        &mut mask,
+        &rounded_rect_mask(width, height, corner_radius),
+        padding as i64,
+        padding as i64,
+    );
+
+    if shadow.blur > 0. {
+        let work_scale = (MAX_WORK_SIGMA / shadow.blur).min(1.);
+        let (mask_width, mask_height) = mask.dimensions();
+        let small = imageops::resize(
+            &mask,
+            ((mask_width as f32 * work_scale) as u32).max(1),
+            ((mask_height as f32 * work_scale) as u32).max(1),
+            FilterType::Triangle,
+        );
+        let blurred = imageops::blur(&small, shadow.blur * work_scale);
+        mask = imageops::resize(&blurred, mask_width, mask_height, FilterType::Triangle);
+    }
+
+    let [r, g, b, a] = shadow.color.0;
+    let origin_x = offset.0 as i64 + shadow.offset_x as i64 - padding as i64;
+    let origin_y = offset.1 as i64 + shadow.offset_y as i64 - padding as i64;
+    for (x, y, coverage) in mask.enumerate_pixels() {
+        let (canvas_x, canvas_y) = (origin_x + x as i64, origin_y + y as i64);
+        if canvas_x < 0
+            || canvas_y < 0
+            || canvas_x >= canvas.width() as i64
+            || canvas_y >= canvas.height() as i64
+        {
+            continue;
+        }
+        let alpha = (coverage[0] as u32 * a as u32 / 255) as u8;
+        let pixel = canvas.get_pixel_mut(canvas_x as u32, canvas_y as u32);
+        *pixel = apply_mask(*pixel, Rgba([r, g, b, alpha]));
+    }
+}
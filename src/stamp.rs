@@ -0,0 +1,122 @@
+use std::fmt::Write as _;
+
+use chrono::NaiveDate;
+use chrono::format::{Item, StrftimeItems};
+use serde::Deserialize;
+
+use crate::{Corner, PhotoMetadata};
+
+// Small line of text in a corner of the canvas, like the date and the place in the rotation
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct StampConfig {
+    pub corner: Corner,
+    // In canvas pixels
+    pub text_size: u32,
+    // {date}, {index}, {total}, {taken}, {gps} and {name} are filled in
+    pub format: String,
+    // strftime format of {date}
+    pub date_format: String,
+}
+
+impl Default for StampConfig {
+    fn default() -> Self {
+        Self {
+            corner: Corner::TopRight,
+            text_size: 28,
+            format: "{date}".to_owned(),
+            date_format: "%-d %B %Y".to_owned(),
+        }
+    }
+}
+
+impl StampConfig {
+    pub(crate) fn scaled(&self, factor: f32) -> Self {
+        Self {
+            text_size: (self.text_size as f32 * factor).round() as u32,
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn has_valid_date_format(&self) -> bool {
+        !StrftimeItems::new(&self.date_format).any(|item| item == Item::Error)
+    }
+
+    // `position` is the 1-based place of the flower in the rotation and its length; without
+    // one {index} and {total} stay empty
+    pub fn text(
+        &self,
+        name: &str,
+        position: Option<(usize, usize)>,
+        date: NaiveDate,
+        metadata: &PhotoMetadata,
+    ) -> String {
+        let mut formatted_date = String::new();
+        if write!(formatted_date, "{}", date.format(&self.date_format)).is_err() {
+            formatted_date = date.to_string();
+        }
+        let (index, total) = position
+            .map(|(index, total)| (index.to_string(), total.to_string()))
+            .unwrap_or_default();
+        // The name goes in last, a caption with braces in it stays as it is
+        let format = self
+            .format
+            .replace("{date}", &formatted_date)
+            .replace("{index}", &index)
+            .replace("{total}", &total);
+        metadata
+            .fill(&format, &self.date_format)
+            .replace("{name}", name)
+    }
+
+    // Top left point of a stamp of `size` in its corner, `inset` from the canvas edges. A stamp
+    // that would cover the photo moves off it, above or below first, then to its side; `None`
+    // if there is no room anywhere.
+    pub(crate) fn place(
+        &self,
+        size: (u32, u32),
+        canvas: (u32, u32),
+        (photo_origin, photo_size): ((u32, u32), (u32, u32)),
+        inset: u32,
+    ) -> Option<(u32, u32)> {
+        let (width, height) = size;
+        let (canvas_width, canvas_height) = canvas;
+        let left = matches!(self.corner, Corner::TopLeft | Corner::BottomLeft);
+        let top = matches!(self.corner, Corner::TopLeft | Corner::TopRight);
+        let x = if left {
+            inset
+        } else {
+            canvas_width.checked_sub(inset + width)?
+        };
+        let y = if top {
+            inset
+        } else {
+            canvas_height.checked_sub(inset + height)?
+        };
+
+        let photo_end = (photo_origin.0 + photo_size.0, photo_origin.1 + photo_size.1);
+        let covers_photo = |(x, y): (u32, u32)| {
+            x < photo_end.0
+                && photo_origin.0 < x + width
+                && y < photo_end.1
+                && photo_origin.1 < y + height
+        };
+        if !covers_photo((x, y)) {
+            return Some((x, y));
+        }
+        let vertical = if top {
+            photo_origin.1.checked_sub(inset + height)
+        } else {
+            Some(photo_end.1 + inset).filter(|&y| y + height <= canvas_height)
+        };
+        let horizontal = if left {
+            photo_origin.0.checked_sub(inset + width)
+        } else {
+            Some(photo_end.0 + inset).filter(|&x| x + width <= canvas_width)
+        };
+        vertical
+            .map(|y| (x, y))
+            .or(horizontal.map(|x| (x, y)))
+            .filter(|&point| !covers_photo(point))
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::{BTreeSet, HashSet};
+use std::error::Error;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::{fmt, fs, io};
+
+use chrono::{DateTime, Local, TimeZone as _};
+use rand::seq::SliceRandom as _;
+use serde::{Deserialize, Serialize};
+
+use crate::to_path;
+
+// Bumped when a change to state.json cannot be read by older versions
+const STATE_VERSION: u32 = 1;
+
+fn state_path() -> PathBuf {
+    to_path("state.json")
+}
+
+// Versions before state.json kept the state in these two files, they are read until it exists
+fn legacy_state_path() -> PathBuf {
+    to_path("last_wallpaper_and_idx.txt")
+}
+
+fn legacy_permutation_path() -> PathBuf {
+    to_path("wiki_flower_permutation.txt")
+}
+
+#[derive(Debug)]
+pub enum StateError {
+    Missing(io::Error),
+    Write(io::Error),
+    Json(serde_json::Error),
+    UnsupportedVersion(u32),
+    // Only the rotation was stored so far, no wallpaper change
+    NoChange,
+    MalformedLines,
+    InvalidTimestamp(String),
+    InvalidIndex(String),
+}
+
+impl StateError {
+    // Nothing was stored yet, e.g. on a clean install
+    pub fn is_first_run(&self) -> bool {
+        match self {
+            StateError::Missing(err) => err.kind() == io::ErrorKind::NotFound,
+            StateError::NoChange => true,
+            _ => false,
+        }
+    }
+
+    // state.json exists but cannot be parsed, see `set_aside_state`
+    pub fn is_corrupt(&self) -> bool {
+        matches!(self, StateError::Json(_))
+    }
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::Missing(err) => write!(f, "Cannot read state.json: {err}"),
+            StateError::Write(err) => write!(f, "Cannot write state.json: {err}"),
+            StateError::Json(err) => write!(f, "Invalid state.json: {err}"),
+            StateError::UnsupportedVersion(version) => write!(
+                f,
+                "state.json has version {version}, this flower-service only reads up to {STATE_VERSION}"
+            ),
+            StateError::NoChange => write!(f, "state.json records no wallpaper change yet"),
+            StateError::MalformedLines => write!(
+                f,
+                "last_wallpaper_and_idx.txt must contain a timestamp and an index line"
+            ),
+            StateError::InvalidTimestamp(value) => {
+                write!(
+                    f,
+                    "Invalid timestamp {value:?} in last_wallpaper_and_idx.txt"
+                )
+            }
+            StateError::InvalidIndex(value) => {
+                write!(f, "Invalid index {value:?} in last_wallpaper_and_idx.txt")
+            }
+        }
+    }
+}
+
+impl Error for StateError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StateError::Missing(err) | StateError::Write(err) => Some(err),
+            StateError::Json(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+// The contents of state.json; everything but the version may be missing
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StateFile {
+    version: u32,
+    #[serde(default)]
+    permutation: Vec<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_change: Option<DateTime<Local>>,
+    #[serde(default)]
+    index: usize,
+    #[serde(default)]
+    seen: BTreeSet<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pinned: Option<usize>,
+}
+
+impl StateFile {
+    fn load() -> Result<Self, StateError> {
+        let contents = match fs::read_to_string(state_path()) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Self::load_legacy(err),
+            Err(err) => return Err(StateError::Missing(err)),
+        };
+        let file: Self = serde_json::from_str(&contents).map_err(StateError::Json)?;
+        if file.version > STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(file.version));
+        }
+        Ok(file)
+    }
+
+    // A state file that is missing starts out empty, one that is invalid is not overwritten
+    fn load_or_default() -> Result<Self, StateError> {
+        match Self::load() {
+            Err(StateError::Missing(err)) if err.kind() == io::ErrorKind::NotFound => {
+                Ok(Self::default())
+            }
+            result => result,
+        }
+    }
+
+    // Reads the text files of older versions, `not_found` is returned when neither exists.
+    // They are migrated by the next store, which writes state.json.
+    fn load_legacy(not_found: io::Error) -> Result<Self, StateError> {
+        let permutation = match fs::read_to_string(legacy_permutation_path()) {
+            Ok(contents) => Some(parse_indices(&contents)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(StateError::Missing(err)),
+        };
+        let state = match fs::read_to_string(legacy_state_path()) {
+            Ok(contents) => match parse_legacy_state(&contents) {
+                Ok(state) => Some(state),
+                Err(err) => {
+                    log::warn!("Ignoring last_wallpaper_and_idx.txt: {err}");
+                    None
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(StateError::Missing(err)),
+        };
+        if permutation.is_none() && state.is_none() {
+            return Err(StateError::Missing(not_found));
+        }
+        let mut file = Self {
+            permutation: permutation.unwrap_or_default(),
+            ..Self::default()
+        };
+        if let Some(state) = state {
+            file.set_state(&state);
+        }
+        Ok(file)
+    }
+
+    fn set_state(&mut self, state: &State) {
+        self.last_change = Some(state.last_change);
+        self.index = state.index;
+        self.seen = state.seen.iter().copied().collect();
+        self.pinned = state.pinned;
+    }
+
+    fn store(mut self) -> Result<(), StateError> {
+        let path = state_path();
+        if !path.exists() && (legacy_permutation_path().exists() || legacy_state_path().exists()) {
+            log::info!(
+                "Moving wiki_flower_permutation.txt and last_wallpaper_and_idx.txt into state.json"
+            );
+        }
+        self.version = STATE_VERSION;
+        let contents = serde_json::to_string_pretty(&self).map_err(StateError::Json)?;
+        write_atomic(&path, contents).map_err(StateError::Write)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct State {
+    pub last_change: DateTime<Local>,
+    pub index: usize,
+    // Images already shown in the current round, see `SelectionStrategy::NoRepeatUntilExhausted`
+    pub seen: HashSet<usize>,
+    // Image shown at every change instead of the next one, set by `flower-service --pin`
+    pub pinned: Option<usize>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            last_change: DateTime::UNIX_EPOCH.with_timezone(&Local),
+            index: 0,
+            seen: HashSet::new(),
+            pinned: None,
+        }
+    }
+}
+
+impl State {
+    pub fn load() -> Result<Self, StateError> {
+        let file = StateFile::load()?;
+        Ok(Self {
+            last_change: file.last_change.ok_or(StateError::NoChange)?,
+            index: file.index,
+            seen: file.seen.into_iter().collect(),
+            pinned: file.pinned,
+        })
+    }
+
+    // Keeps the permutation stored next to the state
+    pub fn store(&self) -> Result<(), StateError> {
+        let mut file = StateFile::load_or_default()?;
+        file.set_state(self);
+        file.store()
+    }
+}
+
+// The seen set and the pinned image are optional third and fourth lines, so older two-line
+// files still load
+fn parse_legacy_state(contents: &str) -> Result<State, StateError> {
+    let mut lines = contents.trim().lines().map(str::trim);
+    let (Some(time_stamp), Some(index)) = (lines.next(), lines.next()) else {
+        return Err(StateError::MalformedLines);
+    };
+
+    let last_change = time_stamp
+        .parse::<i64>()
+        .ok()
+        .and_then(|seconds| Local.timestamp_opt(seconds, 0).earliest())
+        .ok_or_else(|| StateError::InvalidTimestamp(time_stamp.to_owned()))?;
+    let index = index
+        .parse::<usize>()
+        .map_err(|_| StateError::InvalidIndex(index.to_owned()))?;
+    let seen = lines
+        .next()
+        .unwrap_or_default()
+        .split(", ")
+        .filter(|seen| !seen.is_empty())
+        .map(|seen| {
+            seen.parse::<usize>()
+                .map_err(|_| StateError::InvalidIndex(seen.to_owned()))
+        })
+        .collect::<Result<_, _>>()?;
+    let pinned = match lines.next().unwrap_or_default() {
+        "" => None,
+        pinned => Some(
+            pinned
+                .parse::<usize>()
+                .map_err(|_| StateError::InvalidIndex(pinned.to_owned()))?,
+        ),
+    };
+    Ok(State {
+        last_change,
+        index,
+        seen,
+        pinned,
+    })
+}
+
+// Moves an unreadable state.json to state.json.bad, which keeps it for a look and lets a new
+// rotation be stored in its place. Returns the new path.
+pub fn set_aside_state() -> Result<PathBuf, StateError> {
+    let bad_path = to_path("state.json.bad");
+    fs::rename(state_path(), &bad_path).map_err(StateError::Write)?;
+    // An empty state.json rather than none, so the files of older versions are not read instead
+    let file = StateFile {
+        version: STATE_VERSION,
+        ..StateFile::default()
+    };
+    let contents = serde_json::to_string_pretty(&file).map_err(StateError::Json)?;
+    write_atomic(&state_path(), contents).map_err(StateError::Write)?;
+    Ok(bad_path)
+}
+
+fn original_wallpaper_path() -> PathBuf {
+    to_path("original_wallpaper.txt")
+}
+
+// The wallpaper from before the service first ran, `None` if it was never captured
+pub fn load_original_wallpaper() -> Option<PathBuf> {
+    let contents = fs::read_to_string(original_wallpaper_path()).ok()?;
+    let path = contents.trim_end_matches(['\r', '\n']);
+    (!path.is_empty()).then(|| PathBuf::from(path))
+}
+
+pub fn store_original_wallpaper(path: &Path) -> io::Result<()> {
+    write_atomic(
+        &original_wallpaper_path(),
+        path.to_string_lossy().as_bytes(),
+    )
+}
+
+fn image_list_path() -> PathBuf {
+    to_path("wiki_flower_images.txt")
+}
+
+// The image list the indices of the stored permutation refer to, `None` if it was never stored
+pub fn load_image_list() -> Option<Vec<String>> {
+    let contents = fs::read_to_string(image_list_path()).ok()?;
+    Some(
+        contents
+            .lines()
+            .map(str::trim_end)
+            .filter(|line| !line.is_empty())
+            .map(str::to_owned)
+            .collect(),
+    )
+}
+
+pub fn store_image_list(image_file_names: &[String]) -> io::Result<()> {
+    write_atomic(&image_list_path(), image_file_names.join("\n"))
+}
+
+pub fn load_permutation() -> Result<Vec<usize>, StateError> {
+    Ok(StateFile::load()?.permutation)
+}
+
+// Keeps the state stored next to the permutation
+pub fn store_permutation(permutation: &[usize]) -> Result<(), StateError> {
+    let mut file = StateFile::load_or_default()?;
+    file.permutation = permutation.to_vec();
+    file.store()
+}
+
+// Shuffles all images when no permutation was stored yet, e.g. on the first run
+pub fn first_permutation(image_count: usize, dry_run: bool) -> Vec<usize> {
+    let mut permutation: Vec<usize> = (0..image_count).collect();
+    permutation.shuffle(&mut rand::thread_rng());
+    log::info!("No stored permutation yet, shuffled {image_count} images into a new one");
+    if !dry_run && let Err(err) = store_permutation(&permutation) {
+        log::error!("Failed to store the new permutation: {err}");
+    }
+    permutation
+}
+
+fn parse_indices(contents: &str) -> Vec<usize> {
+    contents
+        .split(", ")
+        .flat_map(|num| num.trim().parse())
+        .collect()
+}
+
+// Writes next to the target and renames over it, so a crash mid-write never leaves a truncated file
+pub(crate) fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+
+    let mut file = fs::File::create(&temp_path)?;
+    file.write_all(contents.as_ref())?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&temp_path, path)
+}
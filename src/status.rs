@@ -0,0 +1,81 @@
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use tiny_http::{Header, Method, Response, Server};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StatusConfig {
+    pub enabled: bool,
+    // Only ever bound on 127.0.0.1
+    pub port: u16,
+}
+
+impl Default for StatusConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 8321,
+        }
+    }
+}
+
+// What GET /status answers with, times in RFC 3339
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Status {
+    pub file_name: Option<String>,
+    pub index: usize,
+    pub last_change: String,
+    pub next_change: String,
+}
+
+impl Status {
+    pub fn new(
+        file_name: Option<String>,
+        index: usize,
+        last_change: DateTime<Local>,
+        next_change: DateTime<Local>,
+    ) -> Self {
+        Self {
+            file_name,
+            index,
+            last_change: last_change.to_rfc3339(),
+            next_change: next_change.to_rfc3339(),
+        }
+    }
+}
+
+pub type SharedStatus = Arc<Mutex<Status>>;
+
+// Answers GET /status with the shared status as JSON, everything else with 404. Only returns
+// when the port cannot be bound.
+pub fn run_status_server(
+    config: &StatusConfig,
+    status: SharedStatus,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let server = Server::http(("127.0.0.1", config.port))?;
+    log::info!(
+        "Serving the status on http://127.0.0.1:{}/status",
+        config.port
+    );
+    for request in server.incoming_requests() {
+        let result = if request.method() == &Method::Get && request.url() == "/status" {
+            let body = serde_json::to_string(
+                &*status
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner()),
+            )?;
+            let content_type = Header::from_bytes("Content-Type", "application/json")
+                .map_err(|()| "Invalid content type header")?;
+            request.respond(Response::from_string(body).with_header(content_type))
+        } else {
+            request.respond(Response::from_string("Not found").with_status_code(404))
+        };
+        if let Err(err) = result {
+            log::warn!("Cannot answer a status request: {err}");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,36 @@
+// Whether the desktop uses a dark theme for apps, false where that cannot be read
+#[cfg(windows)]
+pub fn system_uses_dark_theme() -> bool {
+    use std::ffi::OsStr;
+    use std::os::windows::ffi::OsStrExt as _;
+    use std::ptr;
+
+    use winapi::shared::winerror::ERROR_SUCCESS;
+    use winapi::um::winreg::{HKEY_CURRENT_USER, RRF_RT_REG_DWORD, RegGetValueW};
+
+    const PERSONALIZE_SUB_KEY: &str =
+        r"Software\Microsoft\Windows\CurrentVersion\Themes\Personalize";
+
+    let to_wide =
+        |value: &str| -> Vec<u16> { OsStr::new(value).encode_wide().chain(Some(0)).collect() };
+    let (sub_key, name) = (to_wide(PERSONALIZE_SUB_KEY), to_wide("AppsUseLightTheme"));
+    let mut light: u32 = 1;
+    let mut size = size_of::<u32>() as u32;
+    let result = unsafe {
+        RegGetValueW(
+            HKEY_CURRENT_USER,
+            sub_key.as_ptr(),
+            name.as_ptr(),
+            RRF_RT_REG_DWORD,
+            ptr::null_mut(),
+            &mut light as *mut u32 as *mut _,
+            &mut size,
+        )
+    };
+    result == ERROR_SUCCESS as i32 && light == 0
+}
+
+#[cfg(not(windows))]
+pub fn system_uses_dark_theme() -> bool {
+    false
+}
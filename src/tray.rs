@@ -0,0 +1,166 @@
+use std::error::Error;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::{mem, ptr};
+
+use image::{Rgba, RgbaImage};
+use tray_icon::menu::{CheckMenuItem, Menu, MenuEvent, MenuId, MenuItem, PredefinedMenuItem};
+use tray_icon::{Icon, TrayIcon, TrayIconBuilder};
+use winapi::um::processthreadsapi::GetCurrentThreadId;
+use winapi::um::winuser::{
+    DispatchMessageW, GetMessageW, MSG, PostThreadMessageW, TranslateMessage, WM_APP, WM_QUIT,
+};
+
+use crate::Command;
+
+// Posted to the tray thread when the flower changed, so the tooltip can follow
+const WM_FLOWER_CHANGED: u32 = WM_APP + 1;
+
+const ICON_SIZE: u32 = 32;
+
+pub struct Tray {
+    icon: TrayIcon,
+    next_id: MenuId,
+    previous_id: MenuId,
+    pause: CheckMenuItem,
+    quit_id: MenuId,
+    commands: Sender<Command>,
+    current_flower: Arc<Mutex<String>>,
+    thread_id: u32,
+}
+
+// Lets the scheduler thread update the tray, which lives on the thread that created it
+pub struct TrayNotifier {
+    current_flower: Arc<Mutex<String>>,
+    thread_id: u32,
+}
+
+impl TrayNotifier {
+    pub fn flower_changed(&self, caption: &str) {
+        *self
+            .current_flower
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = caption.to_owned();
+        unsafe { PostThreadMessageW(self.thread_id, WM_FLOWER_CHANGED, 0, 0) };
+    }
+
+    // Ends the message loop, e.g. when the scheduler stopped with an error
+    pub fn quit(&self) {
+        unsafe { PostThreadMessageW(self.thread_id, WM_QUIT, 0, 0) };
+    }
+}
+
+impl Tray {
+    pub fn new(commands: Sender<Command>) -> Result<Self, Box<dyn Error>> {
+        let next = MenuItem::new("Next flower", true, None);
+        let previous = MenuItem::new("Previous flower", true, None);
+        let pause = CheckMenuItem::new("Pause rotation", true, false, None);
+        let quit = MenuItem::new("Quit", true, None);
+        let menu = Menu::new();
+        menu.append_items(&[
+            &next,
+            &previous,
+            &pause,
+            &PredefinedMenuItem::separator(),
+            &quit,
+        ])?;
+
+        let icon = TrayIconBuilder::new()
+            .with_menu(Box::new(menu))
+            .with_tooltip("flower-service")
+            .with_icon(flower_icon()?)
+            .build()?;
+
+        Ok(Self {
+            icon,
+            next_id: next.id().clone(),
+            previous_id: previous.id().clone(),
+            pause,
+            quit_id: quit.id().clone(),
+            commands,
+            current_flower: Arc::new(Mutex::new(String::new())),
+            thread_id: unsafe { GetCurrentThreadId() },
+        })
+    }
+
+    pub fn notifier(&self) -> TrayNotifier {
+        TrayNotifier {
+            current_flower: self.current_flower.clone(),
+            thread_id: self.thread_id,
+        }
+    }
+
+    // Runs the message loop of the tray until "Quit" is chosen or the scheduler is gone
+    pub fn run(self) {
+        let mut msg: MSG = unsafe { mem::zeroed() };
+        while unsafe { GetMessageW(&mut msg, ptr::null_mut(), 0, 0) } > 0 {
+            if msg.message == WM_FLOWER_CHANGED {
+                self.update_tooltip();
+                continue;
+            }
+            unsafe {
+                TranslateMessage(&msg);
+                DispatchMessageW(&msg);
+            }
+
+            // Menu clicks are queued while the message above is dispatched
+            while let Ok(event) = MenuEvent::receiver().try_recv() {
+                let command = match event.id() {
+                    id if *id == self.next_id => Command::Next,
+                    id if *id == self.previous_id => Command::Previous,
+                    id if id == self.pause.id() => Command::Pause(self.pause.is_checked()),
+                    id if *id == self.quit_id => Command::Quit,
+                    _ => continue,
+                };
+                let quit = command == Command::Quit;
+                if self.commands.send(command).is_err() || quit {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn update_tooltip(&self) {
+        let caption = self
+            .current_flower
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone();
+        let tooltip = if caption.is_empty() {
+            "flower-service".to_owned()
+        } else {
+            caption
+        };
+        if let Err(err) = self.icon.set_tooltip(Some(tooltip)) {
+            log::warn!("Cannot update the tray tooltip: {err}");
+        }
+    }
+}
+
+// Five petals around a yellow center, drawn here so no icon file has to ship
+fn flower_icon() -> Result<Icon, tray_icon::BadIcon> {
+    let center = ICON_SIZE as f32 / 2.;
+    let petal_radius = ICON_SIZE as f32 / 5.;
+    let petals: Vec<(f32, f32)> = (0..5)
+        .map(|petal| {
+            let angle = petal as f32 * std::f32::consts::TAU / 5.;
+            (
+                center + angle.cos() * petal_radius * 1.2,
+                center + angle.sin() * petal_radius * 1.2,
+            )
+        })
+        .collect();
+
+    let icon = RgbaImage::from_fn(ICON_SIZE, ICON_SIZE, |x, y| {
+        let (x, y) = (x as f32 + 0.5, y as f32 + 0.5);
+        let distance = |(px, py): (f32, f32)| ((x - px).powi(2) + (y - py).powi(2)).sqrt();
+        if distance((center, center)) < petal_radius * 0.8 {
+            Rgba([246, 196, 48, 255])
+        } else if petals.iter().any(|&petal| distance(petal) < petal_radius) {
+            Rgba([226, 92, 140, 255])
+        } else {
+            Rgba([0, 0, 0, 0])
+        }
+    });
+    Icon::from_rgba(icon.into_raw(), ICON_SIZE, ICON_SIZE)
+}
@@ -0,0 +1,85 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+#[cfg(target_os = "linux")]
+mod gnome;
+#[cfg(target_os = "macos")]
+mod macos;
+mod recording;
+#[cfg(windows)]
+mod windows;
+
+#[cfg(target_os = "linux")]
+pub use gnome::GnomeWallpaper;
+#[cfg(target_os = "macos")]
+pub use macos::MacWallpaper;
+pub use recording::RecordingSetter;
+#[cfg(windows)]
+pub use windows::{WindowsWallpaper, detect_screen_size, set_lock_screen};
+
+// How the desktop places the wallpaper. The image is rendered at the screen size, so the default
+// keeps it untouched whatever style was set before.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WallpaperStyle {
+    #[default]
+    Center,
+    Tile,
+    Stretch,
+    Fit,
+    Fill,
+    Span,
+}
+
+pub trait WallpaperSetter {
+    fn set(&self, image_path: &Path) -> io::Result<()>;
+
+    // Backends that cannot address monitors individually report none
+    fn monitors(&self) -> Vec<Monitor> {
+        Vec::new()
+    }
+
+    fn set_for_monitor(&self, _monitor: &Monitor, image_path: &Path) -> io::Result<()> {
+        self.set(image_path)
+    }
+
+    // The image of the current wallpaper, `None` for a plain color or an unknown backend
+    fn current(&self) -> io::Result<Option<PathBuf>> {
+        Ok(None)
+    }
+}
+
+// Desktops tend to fall back to a black screen for a missing file instead of failing
+pub(crate) fn ensure_image_exists(image_path: &Path) -> io::Result<()> {
+    if image_path.is_file() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Wallpaper image {} does not exist", image_path.display()),
+        ))
+    }
+}
+
+pub struct Monitor {
+    #[cfg_attr(not(windows), allow(dead_code))]
+    id: Vec<u16>,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[cfg(not(windows))]
+pub fn set_lock_screen(_image_path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Only Windows has a lock screen image to set",
+    ))
+}
+
+#[cfg(not(windows))]
+pub fn detect_screen_size() -> (u32, u32) {
+    let fallback = crate::RenderConfig::default();
+    (fallback.canvas_width, fallback.canvas_height)
+}
@@ -0,0 +1,101 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use super::{WallpaperSetter, WallpaperStyle, ensure_image_exists};
+
+pub struct GnomeWallpaper {
+    style: WallpaperStyle,
+}
+
+impl GnomeWallpaper {
+    pub fn new(style: WallpaperStyle) -> Self {
+        Self { style }
+    }
+
+    fn set_key(key: &str, value: &str) -> io::Result<()> {
+        let status = Command::new("gsettings")
+            .args(["set", "org.gnome.desktop.background", key, value])
+            .status()?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "gsettings failed to set {key}: {status}"
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl WallpaperSetter for GnomeWallpaper {
+    fn set(&self, image_path: &Path) -> io::Result<()> {
+        ensure_image_exists(image_path)?;
+        let uri = file_uri(&std::path::absolute(image_path)?);
+        let picture_options = match self.style {
+            WallpaperStyle::Center => "centered",
+            WallpaperStyle::Tile => "wallpaper",
+            WallpaperStyle::Stretch => "stretched",
+            WallpaperStyle::Fit => "scaled",
+            WallpaperStyle::Fill => "zoom",
+            WallpaperStyle::Span => "spanned",
+        };
+        Self::set_key("picture-options", picture_options)?;
+        Self::set_key("picture-uri", &uri)?;
+        // Only newer GNOME releases know the dark variant, so a failure here is not an error
+        let _ = Self::set_key("picture-uri-dark", &uri);
+        Ok(())
+    }
+
+    fn current(&self) -> io::Result<Option<PathBuf>> {
+        let output = Command::new("gsettings")
+            .args(["get", "org.gnome.desktop.background", "picture-uri"])
+            .output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "gsettings failed to get picture-uri: {}",
+                output.status
+            )));
+        }
+        let uri = String::from_utf8_lossy(&output.stdout);
+        Ok(uri
+            .trim()
+            .trim_matches('\'')
+            .strip_prefix("file://")
+            .map(|path| PathBuf::from(percent_decode(path))))
+    }
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| value.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn file_uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                uri.push(byte as char)
+            }
+            _ => uri.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    uri
+}
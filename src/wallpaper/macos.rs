@@ -0,0 +1,46 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use super::{WallpaperSetter, ensure_image_exists};
+
+// Sets the picture of every desktop through System Events. The placement is left to the
+// desktop settings, System Events has no scripting access to it.
+pub struct MacWallpaper;
+
+impl MacWallpaper {
+    fn run_script(script: &str) -> io::Result<String> {
+        let output = Command::new("osascript").args(["-e", script]).output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "osascript failed with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    }
+}
+
+impl WallpaperSetter for MacWallpaper {
+    fn set(&self, image_path: &Path) -> io::Result<()> {
+        ensure_image_exists(image_path)?;
+        let path = std::path::absolute(image_path)?;
+        Self::run_script(&format!(
+            "tell application \"System Events\" to set picture of every desktop to {}",
+            quoted(&path.to_string_lossy())
+        ))?;
+        Ok(())
+    }
+
+    fn current(&self) -> io::Result<Option<PathBuf>> {
+        let picture =
+            Self::run_script("tell application \"System Events\" to get picture of desktop 1")?;
+        Ok((!picture.is_empty()).then(|| PathBuf::from(picture)))
+    }
+}
+
+// AppleScript string literal
+fn quoted(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
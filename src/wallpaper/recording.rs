@@ -0,0 +1,40 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::WallpaperSetter;
+
+// Remembers the images it is asked to set instead of touching the desktop, for dry runs and
+// for exercising the scheduling without a desktop session
+#[derive(Debug, Default)]
+pub struct RecordingSetter {
+    set_paths: Mutex<Vec<PathBuf>>,
+}
+
+impl RecordingSetter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Every image set so far, oldest first
+    pub fn set_paths(&self) -> Vec<PathBuf> {
+        self.set_paths
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+}
+
+impl WallpaperSetter for RecordingSetter {
+    fn set(&self, image_path: &Path) -> io::Result<()> {
+        self.set_paths
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(image_path.to_owned());
+        Ok(())
+    }
+
+    fn current(&self) -> io::Result<Option<PathBuf>> {
+        Ok(self.set_paths().pop())
+    }
+}
@@ -0,0 +1,320 @@
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{mem, ptr, thread};
+
+use winapi::Interface as _;
+use winapi::shared::minwindef::MAX_PATH;
+use winapi::shared::windef::{HMONITOR, POINT, RECT};
+use winapi::shared::winerror::{ERROR_SUCCESS, FAILED};
+use winapi::um::combaseapi::{
+    CLSCTX_ALL, CoCreateInstance, CoInitializeEx, CoTaskMemFree, CoUninitialize,
+};
+use winapi::um::objbase::COINIT_APARTMENTTHREADED;
+use winapi::um::shobjidl_core::{
+    CLSID_DesktopWallpaper, DWPOS_CENTER, DWPOS_FILL, DWPOS_FIT, DWPOS_SPAN, DWPOS_STRETCH,
+    DWPOS_TILE, IDesktopWallpaper,
+};
+use winapi::um::wingdi::DEVMODEW;
+use winapi::um::winnt::REG_SZ;
+use winapi::um::winreg::{HKEY_CURRENT_USER, RegSetKeyValueW};
+use winapi::um::winuser::SystemParametersInfoW;
+use winapi::um::winuser::{
+    ENUM_CURRENT_SETTINGS, EnumDisplaySettingsW, GetMonitorInfoW, GetSystemMetrics,
+    MONITOR_DEFAULTTONULL, MONITOR_DEFAULTTOPRIMARY, MONITORINFO, MONITORINFOEXW, MonitorFromPoint,
+    MonitorFromRect, SM_CXSCREEN, SM_CYSCREEN,
+};
+use winapi::um::winuser::{SPI_GETDESKWALLPAPER, SPI_SETDESKWALLPAPER};
+use winapi::um::winuser::{SPIF_SENDWININICHANGE, SPIF_UPDATEINIFILE};
+
+use ::windows::Storage::StorageFile;
+use ::windows::System::UserProfile::LockScreen;
+use ::windows::core::HSTRING;
+
+use super::{Monitor, WallpaperSetter, WallpaperStyle, ensure_image_exists};
+use crate::RenderConfig;
+
+const SET_ATTEMPTS: u32 = 3;
+// Grows with every attempt
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+pub struct WindowsWallpaper {
+    // Falls back to a single wallpaper across all monitors when COM is unavailable
+    desktop_wallpaper: Option<DesktopWallpaper>,
+    style: WallpaperStyle,
+}
+
+impl WindowsWallpaper {
+    pub fn new(style: WallpaperStyle) -> Self {
+        Self {
+            desktop_wallpaper: DesktopWallpaper::new(),
+            style,
+        }
+    }
+}
+
+impl Default for WindowsWallpaper {
+    fn default() -> Self {
+        Self::new(WallpaperStyle::default())
+    }
+}
+
+// Windows copies the image, so the next render may overwrite the file. Versions before Windows 8
+// lack the API and return an error.
+pub fn set_lock_screen(image_path: &Path) -> io::Result<()> {
+    ensure_image_exists(image_path)?;
+    let image_path = std::path::absolute(image_path)?;
+    let file = StorageFile::GetFileFromPathAsync(&HSTRING::from(image_path.as_path()))?.join()?;
+    LockScreen::SetImageFileAsync(&file)?.join()?;
+    Ok(())
+}
+
+// Windows keeps the style of the single wallpaper next to it in the registry
+fn store_registry_style(style: WallpaperStyle) -> io::Result<()> {
+    let (wallpaper_style, tile_wallpaper) = match style {
+        WallpaperStyle::Center => ("0", "0"),
+        WallpaperStyle::Tile => ("0", "1"),
+        WallpaperStyle::Stretch => ("2", "0"),
+        WallpaperStyle::Fit => ("6", "0"),
+        WallpaperStyle::Fill => ("10", "0"),
+        WallpaperStyle::Span => ("22", "0"),
+    };
+    let sub_key = to_wide(r"Control Panel\Desktop");
+    for (name, value) in [
+        ("WallpaperStyle", wallpaper_style),
+        ("TileWallpaper", tile_wallpaper),
+    ] {
+        let (name, value) = (to_wide(name), to_wide(value));
+        let result = unsafe {
+            RegSetKeyValueW(
+                HKEY_CURRENT_USER,
+                sub_key.as_ptr(),
+                name.as_ptr(),
+                REG_SZ,
+                value.as_ptr() as *const _,
+                (value.len() * 2) as u32,
+            )
+        };
+        if result != ERROR_SUCCESS as i32 {
+            return Err(io::Error::from_raw_os_error(result));
+        }
+    }
+    Ok(())
+}
+
+// Broadcasting the change makes Windows pick up the registry style as well
+fn set_desk_wallpaper(image_path: &[u16]) -> io::Result<()> {
+    let result = unsafe {
+        SystemParametersInfoW(
+            SPI_SETDESKWALLPAPER,
+            0,
+            image_path.as_ptr() as *mut _,
+            SPIF_UPDATEINIFILE | SPIF_SENDWININICHANGE,
+        )
+    };
+    if result == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl WallpaperSetter for WindowsWallpaper {
+    fn set(&self, image_path: &Path) -> io::Result<()> {
+        ensure_image_exists(image_path)?;
+        store_registry_style(self.style)?;
+        let image_path = to_wide(image_path);
+
+        // The call fails now and then right after logon, while the desktop is still starting
+        let mut attempt = 1;
+        loop {
+            match set_desk_wallpaper(&image_path) {
+                Ok(()) => return Ok(()),
+                Err(err) if attempt < SET_ATTEMPTS => {
+                    log::warn!(
+                        "Setting the wallpaper failed (attempt {attempt} of {SET_ATTEMPTS}): {err}, retrying"
+                    );
+                    thread::sleep(RETRY_DELAY * attempt);
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn current(&self) -> io::Result<Option<PathBuf>> {
+        let mut buffer = [0u16; MAX_PATH];
+        let result = unsafe {
+            SystemParametersInfoW(
+                SPI_GETDESKWALLPAPER,
+                buffer.len() as u32,
+                buffer.as_mut_ptr() as *mut _,
+                0,
+            )
+        };
+        if result == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let len = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+        Ok((len > 0).then(|| PathBuf::from(OsString::from_wide(&buffer[..len]))))
+    }
+
+    fn monitors(&self) -> Vec<Monitor> {
+        self.desktop_wallpaper
+            .as_ref()
+            .map(DesktopWallpaper::monitors)
+            .unwrap_or_default()
+    }
+
+    fn set_for_monitor(&self, monitor: &Monitor, image_path: &Path) -> io::Result<()> {
+        match &self.desktop_wallpaper {
+            Some(desktop_wallpaper) => {
+                ensure_image_exists(image_path)?;
+                desktop_wallpaper.set_position(self.style)?;
+                desktop_wallpaper.set(monitor, image_path)
+            }
+            None => self.set(image_path),
+        }
+    }
+}
+
+// Owns the COM apartment and the IDesktopWallpaper instance used for per-monitor wallpapers
+struct DesktopWallpaper {
+    interface: *mut IDesktopWallpaper,
+}
+
+impl DesktopWallpaper {
+    fn new() -> Option<Self> {
+        unsafe {
+            if FAILED(CoInitializeEx(ptr::null_mut(), COINIT_APARTMENTTHREADED)) {
+                return None;
+            }
+            let mut interface: *mut IDesktopWallpaper = ptr::null_mut();
+            let result = CoCreateInstance(
+                &CLSID_DesktopWallpaper,
+                ptr::null_mut(),
+                CLSCTX_ALL,
+                &IDesktopWallpaper::uuidof(),
+                &mut interface as *mut *mut IDesktopWallpaper as *mut _,
+            );
+            if FAILED(result) || interface.is_null() {
+                CoUninitialize();
+                return None;
+            }
+            Some(Self { interface })
+        }
+    }
+
+    fn monitors(&self) -> Vec<Monitor> {
+        let mut count = 0;
+        if FAILED(unsafe { (*self.interface).GetMonitorDevicePathCount(&mut count) }) {
+            return Vec::new();
+        }
+        (0..count)
+            .filter_map(|index| unsafe {
+                let mut raw_id = ptr::null_mut();
+                if FAILED((*self.interface).GetMonitorDevicePathAt(index, &mut raw_id)) {
+                    return None;
+                }
+                let len = (0..).take_while(|&i| *raw_id.add(i) != 0).count();
+                let id = std::slice::from_raw_parts(raw_id, len + 1).to_vec();
+                CoTaskMemFree(raw_id as *mut _);
+
+                // Detached monitors keep their device path but report no rectangle
+                let mut rect: RECT = std::mem::zeroed();
+                if FAILED((*self.interface).GetMonitorRECT(id.as_ptr(), &mut rect)) {
+                    return None;
+                }
+                let (width, height) = (rect.right - rect.left, rect.bottom - rect.top);
+                if width <= 0 || height <= 0 {
+                    return None;
+                }
+                let (width, height) = physical_size(MonitorFromRect(&rect, MONITOR_DEFAULTTONULL))
+                    .unwrap_or((width as u32, height as u32));
+                Some(Monitor { id, width, height })
+            })
+            .collect()
+    }
+
+    // Applies to all monitors
+    fn set_position(&self, style: WallpaperStyle) -> io::Result<()> {
+        let position = match style {
+            WallpaperStyle::Center => DWPOS_CENTER,
+            WallpaperStyle::Tile => DWPOS_TILE,
+            WallpaperStyle::Stretch => DWPOS_STRETCH,
+            WallpaperStyle::Fit => DWPOS_FIT,
+            WallpaperStyle::Fill => DWPOS_FILL,
+            WallpaperStyle::Span => DWPOS_SPAN,
+        };
+        let result = unsafe { (*self.interface).SetPosition(position) };
+        if FAILED(result) {
+            return Err(io::Error::from_raw_os_error(result));
+        }
+        Ok(())
+    }
+
+    fn set(&self, monitor: &Monitor, image_path: &Path) -> io::Result<()> {
+        let image_path = to_wide(image_path);
+        let result =
+            unsafe { (*self.interface).SetWallpaper(monitor.id.as_ptr(), image_path.as_ptr()) };
+        if FAILED(result) {
+            return Err(io::Error::from_raw_os_error(result));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DesktopWallpaper {
+    fn drop(&mut self) {
+        unsafe {
+            (*self.interface).Release();
+            CoUninitialize();
+        }
+    }
+}
+
+// Resolution of the display mode of the monitor in physical pixels. On a scaled display Windows
+// reports smaller rectangles to a process that is not DPI aware, the display mode stays as it is.
+fn physical_size(monitor: HMONITOR) -> Option<(u32, u32)> {
+    if monitor.is_null() {
+        return None;
+    }
+    unsafe {
+        let mut info: MONITORINFOEXW = mem::zeroed();
+        info.cbSize = mem::size_of::<MONITORINFOEXW>() as u32;
+        let info_ptr = &mut info as *mut MONITORINFOEXW as *mut MONITORINFO;
+        if GetMonitorInfoW(monitor, info_ptr) == 0 {
+            return None;
+        }
+        let mut mode: DEVMODEW = mem::zeroed();
+        mode.dmSize = mem::size_of::<DEVMODEW>() as u16;
+        if EnumDisplaySettingsW(info.szDevice.as_ptr(), ENUM_CURRENT_SETTINGS, &mut mode) == 0 {
+            return None;
+        }
+        (mode.dmPelsWidth > 0 && mode.dmPelsHeight > 0)
+            .then_some((mode.dmPelsWidth, mode.dmPelsHeight))
+    }
+}
+
+// Falls back to the logical size of the primary monitor when its display mode is unknown
+pub fn detect_screen_size() -> (u32, u32) {
+    let primary = unsafe { MonitorFromPoint(POINT { x: 0, y: 0 }, MONITOR_DEFAULTTOPRIMARY) };
+    if let Some(size) = physical_size(primary) {
+        return size;
+    }
+    let (width, height) = unsafe { (GetSystemMetrics(SM_CXSCREEN), GetSystemMetrics(SM_CYSCREEN)) };
+    if width <= 0 || height <= 0 {
+        let fallback = RenderConfig::default();
+        return (fallback.canvas_width, fallback.canvas_height);
+    }
+    (width as u32, height as u32)
+}
+
+fn to_wide<S: AsRef<OsStr>>(value: S) -> Vec<u16> {
+    value
+        .as_ref()
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect()
+}
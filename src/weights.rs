@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Selection weights by image file name, one "Rosa_canina.jpg 3" per line; lines starting with
+// '#' are comments. A missing file means every image has weight 1.
+pub fn load_weights(path: &Path) -> HashMap<String, u32> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return HashMap::new(),
+        Err(err) => {
+            log::warn!("Cannot read {}, ignoring it: {err}", path.display());
+            return HashMap::new();
+        }
+    };
+    let mut weights = HashMap::new();
+    for (line_idx, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line
+            .rsplit_once(char::is_whitespace)
+            .and_then(|(name, weight)| Some((name.trim_end(), weight.parse().ok()?)))
+        {
+            Some((name, weight)) => {
+                weights.insert(name.to_owned(), weight);
+            }
+            None => log::warn!(
+                "Ignoring line {} of {}, expected a file name and a weight",
+                line_idx + 1,
+                path.display()
+            ),
+        }
+    }
+    weights
+}
+
+// Looked up like the captions; unlisted images have weight 1
+pub(crate) fn weight_for(file_name: &str, weights: &HashMap<String, u32>) -> u32 {
+    let base_name = Path::new(file_name)
+        .file_name()
+        .and_then(|name| name.to_str());
+    weights
+        .get(file_name)
+        .or_else(|| base_name.and_then(|name| weights.get(name)))
+        .copied()
+        .unwrap_or(1)
+}
@@ -0,0 +1,22 @@
+use std::fs;
+use std::path::PathBuf;
+
+use flower_service::set_base_path;
+use image::{Rgb, RgbImage};
+
+// A fresh base path named after the test with plain photos in wiki_flowers and nothing else,
+// like on a first run
+pub fn temp_base_path(test_name: &str, photos: &[(&str, [u8; 3])]) -> PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("flower-service-{test_name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let image_dir = dir.join("wiki_flowers");
+    fs::create_dir_all(&image_dir).expect("cannot create the image directory");
+    for &(file_name, color) in photos {
+        RgbImage::from_pixel(640, 480, Rgb(color))
+            .save(image_dir.join(file_name))
+            .expect("cannot write a test photo");
+    }
+    set_base_path(dir.clone()).expect("the base path is only set once");
+    dir
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use std::collections::HashMap;
+use std::fs;
+
+use flower_service::{
+    Config, RecordingSetter, RenderConfig, Rotation, SelectionStrategy, State, caption_for,
+    change_wallpaper, first_permutation, list_images, load_permutation,
+};
+
+#[test]
+fn first_run_shows_the_first_flower_and_stores_the_state() {
+    let base_path = common::temp_base_path(
+        "first-run",
+        &[
+            ("Bellis_perennis.png", [250, 250, 240]),
+            ("Rosa_canina.png", [200, 40, 60]),
+            ("Tulip.png", [240, 200, 30]),
+        ],
+    );
+    let image_file_names = list_images(&base_path.join("wiki_flowers")).unwrap();
+    assert!(State::load().is_err_and(|err| err.is_first_run()));
+    assert!(load_permutation().is_err_and(|err| err.is_first_run()));
+
+    let permutation = first_permutation(image_file_names.len(), false);
+    let mut sorted = permutation.clone();
+    sorted.sort_unstable();
+    assert_eq!(sorted, [0, 1, 2]);
+    assert_eq!(load_permutation().unwrap(), permutation);
+    // No flower was shown yet
+    assert!(State::load().is_err_and(|err| err.is_first_run()));
+
+    let first_flower = image_file_names[permutation[0]].clone();
+    let mut rotation = Rotation::new(
+        image_file_names,
+        permutation.clone(),
+        State::default(),
+        &Config::default(),
+        false,
+    );
+    let wallpaper_setter = RecordingSetter::new();
+    let captions = change_wallpaper(
+        &mut rotation,
+        &wallpaper_setter,
+        &RenderConfig::default().scaled_to(320, 200),
+        SelectionStrategy::Sequential,
+        None,
+    )
+    .expect("the first change fails");
+
+    assert_eq!(wallpaper_setter.set_paths().len(), 1);
+    assert_eq!(captions, [caption_for(&first_flower, &HashMap::new())]);
+    let state = State::load().expect("the state is not stored");
+    assert_eq!(state.index, 1);
+    assert_eq!(load_permutation().unwrap(), permutation);
+
+    let _ = fs::remove_dir_all(base_path);
+}
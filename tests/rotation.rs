@@ -0,0 +1,48 @@
+mod common;
+
+use std::fs;
+
+use flower_service::{
+    Config, RecordingSetter, RenderConfig, Rotation, SelectionStrategy, State, change_wallpaper,
+    output_path,
+};
+
+#[test]
+fn change_renders_and_sets_the_next_flower() {
+    let base_path = common::temp_base_path(
+        "rotation",
+        &[
+            ("Rosa_canina.png", [200, 40, 60]),
+            ("Tulip.png", [240, 200, 30]),
+        ],
+    );
+    let mut rotation = Rotation::new(
+        vec!["Rosa_canina.png".to_owned(), "Tulip.png".to_owned()],
+        vec![1, 0],
+        State::default(),
+        &Config::default(),
+        false,
+    );
+    let render_config = RenderConfig::default().scaled_to(320, 200);
+    let wallpaper_setter = RecordingSetter::new();
+
+    let captions = change_wallpaper(
+        &mut rotation,
+        &wallpaper_setter,
+        &render_config,
+        SelectionStrategy::Sequential,
+        None,
+    )
+    .expect("the change fails");
+
+    let rendered = output_path(None, &render_config);
+    assert_eq!(rendered, base_path.join("flower_of_today.png"));
+    assert_eq!(wallpaper_setter.set_paths(), [rendered.as_path()]);
+    assert_eq!(captions, ["Tulip"]);
+    let wallpaper = image::open(&rendered).expect("the wallpaper is not readable");
+    assert_eq!((wallpaper.width(), wallpaper.height()), (320, 200));
+    assert_eq!(rotation.state.index, 1);
+    assert!(base_path.join("state.json").is_file());
+
+    let _ = fs::remove_dir_all(base_path);
+}
@@ -0,0 +1,89 @@
+mod common;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use flower_service::{
+    Config, RecordingSetter, RenderConfig, Rotation, SelectionStrategy, State, WallpaperSetter,
+    change_wallpaper,
+};
+
+// A desktop that refuses every wallpaper
+struct FailingSetter;
+
+impl WallpaperSetter for FailingSetter {
+    fn set(&self, _image_path: &Path) -> io::Result<()> {
+        Err(io::Error::other("the desktop is not reachable"))
+    }
+}
+
+#[test]
+fn failed_set_does_not_take_the_place_of_the_shown_wallpaper() {
+    let base_path = common::temp_base_path(
+        "unique-output-names",
+        &[
+            ("Bellis_perennis.png", [250, 250, 240]),
+            ("Rosa_canina.png", [200, 40, 60]),
+            ("Tulip.png", [240, 200, 30]),
+        ],
+    );
+    let config = Config {
+        unique_output_names: true,
+        ..Config::default()
+    };
+    let mut rotation = Rotation::new(
+        vec![
+            "Bellis_perennis.png".to_owned(),
+            "Rosa_canina.png".to_owned(),
+            "Tulip.png".to_owned(),
+        ],
+        vec![0, 1, 2],
+        State::default(),
+        &config,
+        false,
+    );
+    let render_config = RenderConfig::default().scaled_to(320, 200);
+    let strategy = SelectionStrategy::Sequential;
+    let (first, second) = (
+        base_path.join("flower_of_today_a.png"),
+        base_path.join("flower_of_today_b.png"),
+    );
+
+    let wallpaper_setter = RecordingSetter::new();
+    change_wallpaper(
+        &mut rotation,
+        &wallpaper_setter,
+        &render_config,
+        strategy,
+        None,
+    )
+    .unwrap();
+    assert_eq!(wallpaper_setter.set_paths(), [first.as_path()]);
+    assert!(first.is_file() && !second.exists());
+
+    // The new file is removed again, the shown one stays
+    change_wallpaper(
+        &mut rotation,
+        &FailingSetter,
+        &render_config,
+        strategy,
+        None,
+    )
+    .unwrap();
+    assert!(first.is_file() && !second.exists());
+
+    let wallpaper_setter = RecordingSetter::new();
+    change_wallpaper(
+        &mut rotation,
+        &wallpaper_setter,
+        &render_config,
+        strategy,
+        None,
+    )
+    .unwrap();
+    assert_eq!(wallpaper_setter.set_paths(), [second.as_path()]);
+    assert!(!first.exists() && second.is_file());
+
+    let _ = fs::remove_dir_all(base_path);
+}